@@ -1,84 +1,2529 @@
-use std::sync::OnceLock;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use agent_stream_kit::{
-    ASKit, Agent, AgentContext, AgentData, AgentError, AgentOutput, AgentSpec, AgentValue,
+    ASKit, ASKitEvent, ASKitObserver, Agent, AgentConfigs, AgentContext, AgentData,
+    AgentDefinition, AgentError, AgentOutput, AgentRegistration, AgentSpec, AgentValue,
     AgentValueMap, AsAgent, askit_agent, async_trait,
 };
-use rhai::{AST, Dynamic, Engine, Scope};
+use rhai::{AST, Dynamic, EvalAltResult, INT, Scope};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
 
-static RHAI_ENGINE: OnceLock<Engine> = OnceLock::new();
+use crate::cache::{AstCache, compile_cached};
+use crate::convert::{
+    ConvertOptions, OpaqueFlow, blob_to_value, from_dynamic_to_value, from_dynamic_to_value_with,
+    from_value_to_dynamic, from_values_to_dynamic, hold_opaque_flow, opaque_scope, type_tag,
+};
+use crate::engine::{
+    eval_permit, eval_permits, get_engine, get_engine_generation, global_constants, is_retryable,
+    new_engine, new_sandboxed_engine,
+};
+use crate::error::{
+    LIMIT_ERROR_PREFIX, ScriptErrorKind, compile_error, runtime_error, script_error_kind,
+};
+use crate::formats::{FloatFormat, Format, parse_csv_line};
+use crate::functions::{
+    Caller, HOST_FUNCTIONS, ScriptOutput, agent_value_size, seed_from_value, with_caller,
+};
+use crate::metadata::script_metadata;
+use crate::patch::apply_patch;
+use crate::state::{Eviction, StateLimits, StateStore};
+use crate::template::Template;
+
+/// Compile a script, or return `None` when it's empty.
+pub(crate) fn compile_script(
+    script: &str,
+    normalize: bool,
+) -> Result<Option<Arc<AST>>, AgentError> {
+    if script.is_empty() {
+        return Ok(None);
+    }
+    let (engine, generation) = get_engine_generation();
+    compile_cached(&engine, generation, script, normalize).map(Some)
+}
+
+/// Compile a script with some keywords or operators disabled, or return
+/// `None` when it's empty.
+///
+/// The restrictions only apply while parsing, so the script still runs on the
+/// shared engine. It isn't cached since the cache doesn't know about them.
+fn compile_restricted(
+    script: &str,
+    normalize: bool,
+    disabled_symbols: &[String],
+) -> Result<Option<Arc<AST>>, AgentError> {
+    if disabled_symbols.is_empty() {
+        return compile_script(script, normalize);
+    }
+    if script.is_empty() {
+        return Ok(None);
+    }
+    restricted_engine(disabled_symbols)
+        .compile(script)
+        .map(|ast| Some(Arc::new(ast)))
+        .map_err(compile_error)
+}
+
+fn restricted_engine(disabled_symbols: &[String]) -> rhai::Engine {
+    let mut engine = new_engine();
+    for symbol in disabled_symbols {
+        engine.disable_symbol(symbol);
+    }
+    engine
+}
+
+/// An engine for scripts taken from the input: it has the functions of
+/// [`new_sandboxed_engine`], so the scripts can't read files or secrets or
+/// message other agents, and the symbols and resource limits of the `configs`.
+///
+/// The `unchecked` feature removes the limits from Rhai, so the agent refuses
+/// to run these scripts when built with it.
+fn sandboxed_engine(configs: &AgentConfigs, disabled_symbols: &[String]) -> rhai::Engine {
+    let mut engine = new_sandboxed_engine();
+    for symbol in disabled_symbols {
+        engine.disable_symbol(symbol);
+    }
+    #[cfg(not(feature = "unchecked"))]
+    {
+        let limit = |name, default| configs.get_integer_or(name, default).max(0) as usize;
+        engine.set_max_operations(limit(CONFIG_MAX_OPERATIONS, 1_000_000) as u64);
+        engine.set_max_string_size(limit(CONFIG_MAX_STRING_SIZE, 1 << 20));
+        engine.set_max_array_size(limit(CONFIG_MAX_ARRAY_SIZE, 1 << 16));
+        engine.set_max_map_size(limit(CONFIG_MAX_MAP_SIZE, 1 << 16));
+        // Rhai forbids any call at a limit of zero, so it means its default here
+        let levels = limit(CONFIG_MAX_CALL_LEVELS, 32);
+        if levels > 0 {
+            engine.set_max_call_levels(levels);
+        }
+        let depth = limit(CONFIG_MAX_EXPR_DEPTH, 64);
+        if depth > 0 {
+            engine.set_max_expr_depths(depth, depth);
+        }
+    }
+    #[cfg(feature = "unchecked")]
+    let _ = configs;
+    engine
+}
+
+fn split_symbols(symbols: &str) -> Vec<String> {
+    symbols
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Load a shared function library, given either inline or as a path to a `.rhai` file.
+///
+/// Its functions run as part of the script, so the same symbols are disabled.
+fn compile_shared_lib(
+    lib: &str,
+    disabled_symbols: &[String],
+) -> Result<Option<Arc<AST>>, AgentError> {
+    let lib = lib.trim();
+    if !lib.contains('\n') && lib.ends_with(".rhai") {
+        let source = std::fs::read_to_string(lib)
+            .map_err(|e| AgentError::IoError(format!("Failed to read {}: {}", lib, e)))?;
+        return compile_restricted(&source, false, disabled_symbols);
+    }
+    compile_restricted(lib, false, disabled_symbols)
+}
+
+/// Make the functions of `lib` available to `ast`. Functions defined in the
+/// script take precedence, and the library's top-level statements are not run.
+fn merge_shared_lib(ast: Arc<AST>, lib: &AST) -> Arc<AST> {
+    Arc::new(lib.clone_functions_only().merge(&ast))
+}
+
+pub(crate) fn eval_ast(
+    agent_id: &str,
+    ctx: &AgentContext,
+    ast: &AST,
+    scope: &mut Scope,
+) -> Result<Dynamic, AgentError> {
+    let caller = Caller {
+        agent_id: agent_id.to_string(),
+        ctx: ctx.clone(),
+        ..Default::default()
+    };
+    eval_ast_with(caller, ast, scope)
+}
+
+pub(crate) fn eval_ast_with(
+    mut caller: Caller,
+    ast: &AST,
+    scope: &mut Scope,
+) -> Result<Dynamic, AgentError> {
+    try_eval_ast_with(&mut caller, ast, scope).map_err(runtime_error)
+}
+
+fn try_eval_ast_with(
+    caller: &mut Caller,
+    ast: &AST,
+    scope: &mut Scope,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    try_eval_ast_on(&get_engine(), caller, ast, scope)
+}
+
+/// Evaluate `ast` on `engine` rather than the shared one.
+fn eval_ast_on(
+    engine: &rhai::Engine,
+    agent_id: &str,
+    ctx: &AgentContext,
+    ast: &AST,
+    scope: &mut Scope,
+) -> Result<Dynamic, AgentError> {
+    let mut caller = Caller {
+        agent_id: agent_id.to_string(),
+        ctx: ctx.clone(),
+        ..Default::default()
+    };
+    try_eval_ast_on(engine, &mut caller, ast, scope).map_err(runtime_error)
+}
+
+fn try_eval_ast_on(
+    engine: &rhai::Engine,
+    caller: &mut Caller,
+    ast: &AST,
+    scope: &mut Scope,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    if let Some(constants) = global_constants() {
+        for (name, value) in constants.iter() {
+            if !scope.contains(name) {
+                scope.push_constant(name.to_string(), value.clone());
+            }
+        }
+    }
+    let (result, done) = with_caller(std::mem::take(caller), || {
+        engine.eval_ast_with_scope::<Dynamic>(scope, ast)
+    });
+    *caller = done;
+    result
+}
+
+/// The outcome of a script evaluation.
+struct Evaluated {
+    value: AgentValue,
+    state: rhai::Map,
+    sends: Vec<(String, AgentValue)>,
+    publishes: Vec<(String, AgentValue)>,
+    emits: Vec<(Duration, AgentValue)>,
+    pause: Duration,
+    /// Set when the script called `no_output`.
+    no_output: bool,
+    /// Values for the stage and diagnostics ports, emitted before the result.
+    debug_outputs: Vec<(&'static str, AgentValue)>,
+}
+
+/// A failed evaluation, which may be worth retrying.
+struct EvalError {
+    error: AgentError,
+    retryable: bool,
+}
+
+impl From<AgentError> for EvalError {
+    fn from(error: AgentError) -> Self {
+        Self {
+            error,
+            retryable: false,
+        }
+    }
+}
+
+/// Reject a script that assigns to scope variables. Mutating methods such as
+/// `value.push(1)` can't be told apart from other calls, so they are allowed.
+fn check_pure(ast: &AST) -> Result<(), AgentError> {
+    let assigned = script_metadata(ast).assigned_vars;
+    if assigned.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<_> = assigned.into_iter().collect();
+    Err(compile_error(format!(
+        "pure script assigns to {}",
+        names.join(", ")
+    )))
+}
+
+/// Reject a script calling functions a sandboxed engine leaves out, so it
+/// fails when it's compiled rather than partway through a run.
+fn check_sandboxed(ast: &AST) -> Result<(), AgentError> {
+    let called = script_metadata(ast).called_fns;
+    let names: Vec<_> = HOST_FUNCTIONS
+        .iter()
+        .filter(|name| called.contains(**name))
+        .copied()
+        .collect();
+    if names.is_empty() {
+        return Ok(());
+    }
+    Err(compile_error(format!(
+        "sandboxed script calls {}",
+        names.join(", ")
+    )))
+}
+
+/// A table for `table_lookup`: an object, or CSV text with a key and a value
+/// on each line.
+fn load_table(name: &str, table: AgentValue) -> Result<rhai::Map, AgentError> {
+    if let Some(csv) = table.as_str() {
+        let mut map = rhai::Map::new();
+        for line in csv.lines().filter(|line| !line.trim().is_empty()) {
+            let fields = parse_csv_line(line)?;
+            let [key, value] = <[String; 2]>::try_from(fields).map_err(|_| {
+                AgentError::InvalidConfig(format!(
+                    "table {} must have a key and a value on each line: {}",
+                    name, line
+                ))
+            })?;
+            map.insert(key.into(), value.into());
+        }
+        return Ok(map);
+    }
+    match from_value_to_dynamic(table)?.try_cast::<rhai::Map>() {
+        Some(map) => Ok(map),
+        None => Err(AgentError::InvalidConfig(format!(
+            "table {} must be an object or CSV text",
+            name
+        ))),
+    }
+}
+
+static SCHEMA_TYPES: [&str; 8] = [
+    "unit", "boolean", "integer", "number", "string", "array", "object", "any",
+];
+
+/// Whether `value` has the type `ty`, one of [`SCHEMA_TYPES`]. Integers count
+/// as numbers.
+fn matches_type(ty: &str, value: &AgentValue) -> bool {
+    let actual = type_tag(value);
+    ty == "any" || ty == actual || (ty == "number" && actual == "integer")
+}
 
-fn get_engine() -> &'static Engine {
-    RHAI_ENGINE.get_or_init(|| {
-        let engine = Engine::new();
-        engine
-    })
+/// Check that `value` is an object with the keys of `schema`, each of the type
+/// it gives. Other keys are allowed.
+fn check_schema(schema: &AgentValueMap<String, String>, value: &AgentValue) -> Result<(), String> {
+    if schema.is_empty() {
+        return Ok(());
+    }
+    if !value.is_object() {
+        return Err(format!("output must be an object, not {}", type_tag(value)));
+    }
+    for (key, ty) in schema {
+        let Some(v) = value.get(key) else {
+            return Err(format!("output is missing {}", key));
+        };
+        if !matches_type(ty, v) {
+            return Err(format!(
+                "output {} must be {}, not {}",
+                key,
+                ty,
+                type_tag(v)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check that every element of an array has the type `ty`, or with `"same"`
+/// the type of the first element. Other values pass.
+fn check_elements(ty: &str, value: &AgentValue) -> Result<(), String> {
+    let Some(arr) = value.as_array() else {
+        return Ok(());
+    };
+    let Some(first) = arr.first() else {
+        return Ok(());
+    };
+    let ty = if ty == "same" { type_tag(first) } else { ty };
+    for (i, v) in arr.iter().enumerate() {
+        if !matches_type(ty, v) {
+            return Err(format!(
+                "array element {} must be {}, not {}",
+                i,
+                ty,
+                type_tag(v)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Split off the `__port__` key of an object result, checking that the port is
+/// one of `outputs`. Other values go to the `value` port.
+fn route_by_port_key(
+    outputs: Option<&[String]>,
+    mut value: AgentValue,
+) -> Result<(String, AgentValue), AgentError> {
+    let port = match value.get(PORT_META_KEY) {
+        None => return Ok((PORT_VALUE.to_string(), value)),
+        Some(AgentValue::String(port)) => port.to_string(),
+        Some(_) => {
+            return Err(AgentError::InvalidValue(format!(
+                "{} must be a string",
+                PORT_META_KEY
+            )));
+        }
+    };
+    if !outputs.is_some_and(|outputs| outputs.contains(&port)) {
+        return Err(AgentError::PinNotFound(port));
+    }
+    if let Some(obj) = value.as_object_mut() {
+        obj.remove(PORT_META_KEY);
+    }
+    Ok((port, value))
+}
+
+/// Definitions of the agents this crate provides, with their titles,
+/// categories, ports and configs, for tools listing them.
+///
+/// Agents register themselves with agent-stream-kit as they are defined, so
+/// the list always matches what `ASKit` loads:
+///
+/// ```
+/// let defs = askit_rhai_agents::agents::agent_definitions();
+/// let script = defs
+///     .iter()
+///     .find(|def| def.name.ends_with("::RhaiScriptAgent"))
+///     .unwrap();
+/// assert_eq!(script.inputs.as_deref(), Some(&["value".to_string()][..]));
+/// assert!(script.outputs.as_ref().unwrap().contains(&"value".to_string()));
+/// assert!(script.default_configs.as_ref().unwrap().contains_key("script"));
+/// ```
+pub fn agent_definitions() -> Vec<AgentDefinition> {
+    let prefix = concat!(module_path!(), "::");
+    let mut defs: Vec<_> = agent_stream_kit::inventory::iter::<AgentRegistration>
+        .into_iter()
+        .map(|reg| (reg.build)())
+        .filter(|def| def.name.starts_with(prefix))
+        .collect();
+    defs.sort_by(|a, b| a.name.cmp(&b.name));
+    defs
 }
 
 static CATEGORY: &str = "Rhai";
+
+/// Maximum number of scripts each Rhai Dynamic Script agent keeps compiled.
+const DYNAMIC_SCRIPT_CACHE_CAPACITY: usize = 64;
+/// Longest delay between two retries of a Rhai Script agent.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
 static PORT_VALUE: &str = "value";
+static PORT_DERIVED: &str = "derived";
+// Debug ports of the pre_transform and script results
+static PORT_STAGE_1: &str = "stage_1";
+static PORT_STAGE_2: &str = "stage_2";
+static PORT_DIAGNOSTICS: &str = "diagnostics";
+static PORT_ERROR: &str = "error";
+static PORT_META_KEY: &str = "__port__";
 static CONFIG_SCRIPT: &str = "script";
+static CONFIG_FORMAT: &str = "format";
+static CONFIG_MODE: &str = "mode";
+static CONFIG_MAX_FANOUT: &str = "max_fanout";
+static CONFIG_FANOUT_POLICY: &str = "fanout_policy";
+static CONFIG_NORMALIZE_CACHE_KEY: &str = "normalize_cache_key";
+static CONFIG_AUTO_ITERATE: &str = "auto_iterate";
+static CONFIG_SHARED_LIB: &str = "shared_lib";
+static CONFIG_SEED_FROM: &str = "seed_from";
+static CONFIG_PRE_TRANSFORM: &str = "pre_transform";
+static CONFIG_OUTPUT_ROUND: &str = "output_round";
+static CONFIG_OUTPUT_ROUND_RECURSIVE: &str = "output_round_recursive";
+static CONFIG_CONSTANTS: &str = "constants";
+static CONFIG_BATCH_SIZE: &str = "batch_size";
+static CONFIG_SAMPLE_RATE: &str = "sample_rate";
+static CONFIG_ALLOW_DYNAMIC_SCRIPTS: &str = "allow_dynamic_scripts";
+static CONFIG_SCRIPT_KEY: &str = "script_key";
+static CONFIG_DATA_KEY: &str = "data_key";
+static CONFIG_MAX_OPERATIONS: &str = "max_operations";
+static CONFIG_MAX_STRING_SIZE: &str = "max_string_size";
+static CONFIG_MAX_ARRAY_SIZE: &str = "max_array_size";
+static CONFIG_MAX_MAP_SIZE: &str = "max_map_size";
+static CONFIG_MAX_CALL_LEVELS: &str = "max_call_levels";
+static CONFIG_MAX_EXPR_DEPTH: &str = "max_expr_depth";
+static CONFIG_STATE_MAX_ENTRIES: &str = "state_max_entries";
+static CONFIG_STATE_MAX_BYTES: &str = "state_max_bytes";
+static CONFIG_STATE_EVICTION: &str = "state_eviction";
+static CONFIG_MAX_RETRIES: &str = "max_retries";
+static CONFIG_RETRY_BACKOFF_MS: &str = "retry_backoff_ms";
+static CONFIG_MAX_CONCURRENT_EVALS: &str = "max_concurrent_evals";
+static CONFIG_DISABLED_SYMBOLS: &str = "disabled_symbols";
+static CONFIG_TEE: &str = "tee";
+static CONFIG_ROUTE_ON_TYPE_ONLY: &str = "route_on_type_only";
+static CONFIG_WRAP_OUTPUT: &str = "wrap_output";
+static CONFIG_WRAP_ALWAYS: &str = "wrap_always";
+static CONFIG_SELF_TEST: &str = "self_test";
+static CONFIG_HEARTBEAT_MS: &str = "heartbeat_ms";
+static CONFIG_HEARTBEAT_SCRIPT: &str = "heartbeat_script";
+static CONFIG_PERSIST_SEQ: &str = "persist_seq";
+static CONFIG_SKIP_ON_UNIT: &str = "skip_on_unit";
+static CONFIG_PASS_UNIT: &str = "pass_unit";
+static CONFIG_TEMPLATE: &str = "template";
+static CONFIG_DEBUG_STAGES: &str = "debug_stages";
+static CONFIG_TIMINGS: &str = "timings";
+static CONFIG_KEEP_LAST_GOOD: &str = "keep_last_good";
+static CONFIG_HISTORY_SIZE: &str = "history_size";
+static CONFIG_FLOAT_FORMAT: &str = "float_format";
+static CONFIG_PURE: &str = "pure";
+/// Not a config of any agent: overflow checking is chosen when the crate is
+/// built, but a flow setting it is told so rather than silently ignored.
+static CONFIG_CHECKED_ARITHMETIC: &str = "checked_arithmetic";
+static CONFIG_ROUTE_TABLE: &str = "route_table";
+static CONFIG_RESULT_VAR: &str = "result_var";
+static CONFIG_OUTPUT_SCHEMA: &str = "output_schema";
+static CONFIG_FALLBACK_SCRIPT: &str = "fallback_script";
+static CONFIG_TABLES: &str = "tables";
+static CONFIG_LOG_PREFIX: &str = "log_prefix";
+static CONFIG_COALESCE_MS: &str = "coalesce_ms";
+static CONFIG_ELEMENT_TYPE: &str = "element_type";
+static CONFIG_CHANGE_KEY: &str = "change_key";
+static CONFIG_ERROR_KEY: &str = "error_key";
+static CONFIG_CHANNELS: &str = "channels";
+static CONFIG_SCHEMA_ERRORS_TO_PORT: &str = "schema_errors_to_port";
+static CONFIG_LIMIT_ERRORS_TO_PORT: &str = "limit_errors_to_port";
+
+/// Entry of the route table used for keys it doesn't list.
+static ROUTE_DEFAULT: &str = "default";
+
+/// Context variable carrying the sequence number of a Rhai Script output.
+static SEQ_VAR: &str = "seq";
+
+// Rhai Script
+//
+// Reconfiguring and processing both take the agent mutably, so they never
+// overlap: a message being processed finishes with the script it started
+// with, and the next message sees the new configuration. The compiled script
+// is held as an Arc, so clearing the shared compile cache or swapping the
+// engine doesn't affect it either.
+#[askit_agent(
+    title = "Rhai Script",
+    category = CATEGORY,
+    inputs = [PORT_VALUE],
+    outputs = [PORT_VALUE, PORT_DERIVED],
+    text_config(
+        name = CONFIG_SCRIPT,
+        title = "Script"
+    ),
+    boolean_config(
+        name = CONFIG_NORMALIZE_CACHE_KEY,
+        title = "Normalize Cache Key",
+        description = "Ignore comments and whitespace when looking up the compiled script cache. Error positions may then be those of a differently formatted copy of the script"
+    ),
+    text_config(
+        name = CONFIG_PRE_TRANSFORM,
+        title = "Pre-transform",
+        description = "Script applied to value before the main script, e.g. to normalize the input"
+    ),
+    text_config(
+        name = CONFIG_SHARED_LIB,
+        title = "Shared Library",
+        description = "Rhai functions available to the script, inline or as a path to a .rhai file"
+    ),
+    boolean_config(
+        name = CONFIG_AUTO_ITERATE,
+        title = "Auto Iterate",
+        description = "Run the script for each element of an array input and emit the results individually"
+    ),
+    integer_config(
+        name = CONFIG_OUTPUT_ROUND,
+        default = -1,
+        title = "Output Round",
+        description = "Round numeric results to this many decimal places (negative to disable)"
+    ),
+    boolean_config(
+        name = CONFIG_OUTPUT_ROUND_RECURSIVE,
+        default = true,
+        title = "Output Round Recursive",
+        description = "Also round numbers inside arrays and objects"
+    ),
+    string_config(
+        name = CONFIG_SEED_FROM,
+        title = "Seed From",
+        description = "Input field used to seed random() and uuid(), so that the same input gives the same output"
+    ),
+    object_config(
+        name = CONFIG_CONSTANTS,
+        title = "Constants",
+        description = "Values available to the script as constants of the same name"
+    ),
+    integer_config(
+        name = CONFIG_STATE_MAX_ENTRIES,
+        title = "State Max Entries",
+        description = "Maximum number of entries in the state object (0 for unlimited)"
+    ),
+    integer_config(
+        name = CONFIG_STATE_MAX_BYTES,
+        title = "State Max Bytes",
+        description = "Maximum size of the state object as JSON (0 for unlimited)"
+    ),
+    string_config(
+        name = CONFIG_STATE_EVICTION,
+        default = "reject",
+        title = "State Eviction",
+        description = "reject to fail the script, or lru to drop the least recently updated entries, when the state exceeds its limits"
+    ),
+    integer_config(
+        name = CONFIG_MAX_RETRIES,
+        title = "Max Retries",
+        description = "Times to rerun the script when a function fails with a retryable error"
+    ),
+    integer_config(
+        name = CONFIG_RETRY_BACKOFF_MS,
+        default = 100,
+        title = "Retry Backoff (ms)",
+        description = "Delay before the first retry, doubled for each further retry up to a minute"
+    ),
+    integer_config(
+        name = CONFIG_MAX_CONCURRENT_EVALS,
+        title = "Max Concurrent Evals",
+        description = "Scripts of this agent, heartbeats included, evaluated at the same time (0 for no limit of its own)"
+    ),
+    string_config(
+        name = CONFIG_DISABLED_SYMBOLS,
+        title = "Disabled Symbols",
+        description = "Comma separated keywords or operators the scripts may not use, e.g. print, while"
+    ),
+    boolean_config(
+        name = CONFIG_TEE,
+        title = "Tee",
+        description = "Emit the script result on derived and pass the input through unchanged on value"
+    ),
+    boolean_config(
+        name = CONFIG_ROUTE_ON_TYPE_ONLY,
+        title = "Route on Type Only",
+        description = "Give the script only the type of the input, e.g. \"object\", and forward the input unchanged to the port it returns"
+    ),
+    string_config(
+        name = CONFIG_WRAP_OUTPUT,
+        title = "Wrap Output",
+        description = "Emit a scalar result x as an object with x under this key"
+    ),
+    boolean_config(
+        name = CONFIG_WRAP_ALWAYS,
+        title = "Wrap Always",
+        description = "Also wrap array and object results"
+    ),
+    text_config(
+        name = CONFIG_SELF_TEST,
+        title = "Self Test",
+        description = "JSON array of {\"input\": ..., \"expected\": ...} cases the script must pass to be configured"
+    ),
+    integer_config(
+        name = CONFIG_HEARTBEAT_MS,
+        title = "Heartbeat (ms)",
+        description = "Emit a heartbeat after this long without input (0 to disable)"
+    ),
+    text_config(
+        name = CONFIG_HEARTBEAT_SCRIPT,
+        title = "Heartbeat Script",
+        description = "Produces the heartbeat value, evaluated without value; unit when empty"
+    ),
+    boolean_config(
+        name = CONFIG_PERSIST_SEQ,
+        title = "Persist Sequence",
+        description = "Keep counting output sequence numbers after the agent is restarted"
+    ),
+    boolean_config(
+        name = CONFIG_SKIP_ON_UNIT,
+        title = "Skip on Unit",
+        description = "Don't run the script when the input is unit"
+    ),
+    boolean_config(
+        name = CONFIG_PASS_UNIT,
+        title = "Pass Unit",
+        description = "With skip_on_unit, emit the unit input instead of nothing"
+    ),
+    boolean_config(
+        name = CONFIG_DEBUG_STAGES,
+        title = "Debug Stages",
+        description = "Also emit the pre_transform result on stage_1 and the script result on stage_2"
+    ),
+    boolean_config(
+        name = CONFIG_TIMINGS,
+        title = "Timings",
+        description = "Emit how long converting the input, evaluating and converting the result took on diagnostics"
+    ),
+    boolean_config(
+        name = CONFIG_KEEP_LAST_GOOD,
+        title = "Keep Last Good Script",
+        description = "When an edited script fails to compile or a config is invalid, log the error and keep running the previous configuration"
+    ),
+    integer_config(
+        name = CONFIG_HISTORY_SIZE,
+        title = "History Size",
+        description = "Number of previous inputs the script can read as recent, oldest first (0 to disable)"
+    ),
+    boolean_config(
+        name = CONFIG_PURE,
+        title = "Pure",
+        description = "Reject scripts that assign to value, state or other scope variables instead of returning a new value"
+    ),
+    object_config(
+        name = CONFIG_ROUTE_TABLE,
+        title = "Route Table",
+        description = "Maps the key the script returns to the port the input is forwarded to; unlisted keys use the default entry"
+    ),
+    string_config(
+        name = CONFIG_RESULT_VAR,
+        title = "Result Variable",
+        description = "Pass the input through unchanged and attach the result as this context variable instead"
+    ),
+    object_config(
+        name = CONFIG_OUTPUT_SCHEMA,
+        title = "Output Schema",
+        description = "Keys the result object must have, mapped to their type: unit, boolean, integer, number, string, array, object or any"
+    ),
+    boolean_config(
+        name = CONFIG_SCHEMA_ERRORS_TO_PORT,
+        title = "Schema Errors to Port",
+        description = "Emit results that don't match output_schema on error instead of failing"
+    ),
+    text_config(
+        name = CONFIG_FALLBACK_SCRIPT,
+        title = "Fallback Script",
+        description = "Run on the same input when the script fails, after any retries"
+    ),
+    object_config(
+        name = CONFIG_TABLES,
+        title = "Tables",
+        description = "Named reference tables for table_lookup, each an object or CSV text of key,value lines"
+    ),
+    string_config(
+        name = CONFIG_LOG_PREFIX,
+        title = "Log Prefix",
+        description = "Printed before the output of print and debug; the agent id when empty"
+    ),
+    integer_config(
+        name = CONFIG_COALESCE_MS,
+        title = "Coalesce (ms)",
+        description = "Only run the script for the latest input received within this long, dropping the others (0 to disable)"
+    ),
+    string_config(
+        name = CONFIG_ELEMENT_TYPE,
+        title = "Element Type",
+        description = "Reject array inputs with an element of another type: a type as in output_schema, or same for all of one type"
+    ),
+    string_config(
+        name = CONFIG_CHANGE_KEY,
+        title = "Change Key",
+        description = "Input field whose change triggers the script; inputs with the same value as the previous one are dropped"
+    ),
+    string_config(
+        name = CONFIG_ERROR_KEY,
+        title = "Error Key",
+        description = "Emit results that are objects with a non-unit value under this key on error, before any other handling of the result"
+    ),
+    string_config(
+        name = CONFIG_CHANNELS,
+        title = "Channels",
+        description = "Comma separated channels whose latest published value the script reads as channels.<name>"
+    ),
+    boolean_config(
+        name = CONFIG_LIMIT_ERRORS_TO_PORT,
+        title = "Limit Errors to Port",
+        description = "Emit the input with the error on error when the script runs into a resource limit or is terminated, instead of failing"
+    )
+)]
+pub struct RhaiScriptAgent {
+    data: AgentData,
+    /// Keeps the opaque values of the flow while the agent runs.
+    opaque_flow: Option<OpaqueFlow>,
+    ast: Option<Arc<AST>>,
+    /// Given by the host with `set_ast`, run when no script is configured.
+    precompiled: Option<Arc<AST>>,
+    source_len: usize,
+    /// Whether the script calls `value_size`, so the input is measured for it.
+    sizes_input: bool,
+    last_error: Option<String>,
+    degraded: bool,
+    pure: bool,
+    pre_transform: Option<Arc<AST>>,
+    fallback: Option<Arc<AST>>,
+    auto_iterate: bool,
+    convert_options: ConvertOptions,
+    seed_from: String,
+    constants: Vec<(String, Dynamic)>,
+    tables: Option<Arc<BTreeMap<String, rhai::Map>>>,
+    log_prefix: String,
+    state: StateStore,
+    max_retries: u32,
+    retry_backoff: Duration,
+
+    /// Bounds the evaluations of this agent on top of the global limit.
+    eval_limit: Option<Arc<Semaphore>>,
+    tee: bool,
+    route_on_type_only: bool,
+    route_table: AgentValueMap<String, String>,
+    sends: Vec<(String, AgentValue)>,
+    /// Delivers the values of the latest `send_to` calls after the earlier ones.
+    delivery: Option<JoinHandle<()>>,
+    publishes: Vec<(String, AgentValue)>,
+    channels: Channels,
+    emits: Vec<(Duration, AgentValue)>,
+
+    /// Emissions scheduled by `emit_after`, aborted when the agent stops.
+    delayed: Vec<JoinHandle<()>>,
+
+    /// Pause requested by the scripts run for the current input.
+    pause: Duration,
+    no_output: bool,
+    debug_stages: bool,
+    timings: bool,
+    debug_outputs: Vec<(&'static str, AgentValue)>,
+    wrap_output: String,
+    wrap_always: bool,
+    result_var: String,
+    output_schema: AgentValueMap<String, String>,
+    element_type: String,
+    change_key: String,
+    /// The `change_key` field of the previous input, `None` before the first.
+    last_change: Option<AgentValue>,
+    schema_errors_to_port: bool,
+    error_key: String,
+    limit_errors_to_port: bool,
+    heartbeat: Heartbeat,
+
+    /// The latest input of the current coalescing window, run when the
+    /// window's timer fires or the agent stops.
+    coalesce: Duration,
+    coalesced: Option<(AgentContext, AgentValue)>,
+    coalesce_task: Option<JoinHandle<()>>,
+    history_size: usize,
+    recent: VecDeque<Dynamic>,
+
+    /// When each input pin last received a value, as Rhai timestamps.
+    pin_timestamps: rhai::Map,
+
+    /// Sequence number of the next output, attached to outputs as the `seq`
+    /// context variable so that consumers can detect reordering or loss.
+    seq: Arc<AtomicU64>,
+    persist_seq: bool,
+    skip_on_unit: bool,
+    pass_unit: bool,
+
+    /// Number of messages processed since the agent was last configured.
+    msg_index: u64,
+
+    /// Number of times the agent has been reconfigured, so that scripts can
+    /// tell when to drop what they derived from an older configuration.
+    generation: u64,
+}
+
+/// The state of a [`RhaiScriptAgent`]'s compiled script, for dashboards.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptStatus {
+    /// Whether a compiled script is being run.
+    pub has_ast: bool,
+
+    /// Length of the source of the compiled script.
+    pub source_len: usize,
+
+    /// The error of the last configuration, if it was rejected because of an
+    /// invalid config, a script failing to compile or failing `self_test`.
+    /// The previous configuration keeps running in that case.
+    pub last_error: Option<String>,
+
+    /// Whether that error was only logged because of `keep_last_good`, so the
+    /// agent runs a script older than its configuration.
+    pub degraded: bool,
+}
+
+/// The parts of a [`RhaiScriptAgent`] set by its configs, parsed and compiled
+/// before any of them replaces the agent's own.
+struct Configured {
+    ast: Option<Arc<AST>>,
+    source_len: usize,
+    sizes_input: bool,
+    pre_transform: Option<Arc<AST>>,
+    fallback: Option<Arc<AST>>,
+    pure: bool,
+    heartbeat_interval: Duration,
+    heartbeat_ast: Option<Arc<AST>>,
+    auto_iterate: bool,
+    tee: bool,
+    route_on_type_only: bool,
+    route_table: AgentValueMap<String, String>,
+    wrap_output: String,
+    wrap_always: bool,
+    result_var: String,
+    output_schema: AgentValueMap<String, String>,
+    schema_errors_to_port: bool,
+    error_key: String,
+    limit_errors_to_port: bool,
+    element_type: String,
+    change_key: String,
+    persist_seq: bool,
+    skip_on_unit: bool,
+    pass_unit: bool,
+    debug_stages: bool,
+    timings: bool,
+    history_size: usize,
+    coalesce: Duration,
+    convert_options: ConvertOptions,
+    seed_from: String,
+    log_prefix: String,
+    constants: Vec<(String, Dynamic)>,
+    tables: Option<Arc<BTreeMap<String, rhai::Map>>>,
+    state_limits: StateLimits,
+    max_retries: u32,
+    retry_backoff: Duration,
+    eval_limit: Option<Arc<Semaphore>>,
+
+    /// Applied once the rest is, since they can't be swapped back.
+    channels: Vec<String>,
+
+    /// Checked against the new script before the configuration is kept.
+    self_test: Option<AgentValue>,
+}
+
+/// Delay before retry `attempt + 1`: `backoff` doubled for each earlier retry,
+/// capped at [`MAX_RETRY_DELAY`].
+fn retry_delay(backoff: Duration, attempt: u32) -> Duration {
+    backoff
+        .checked_mul(2u32.saturating_pow(attempt))
+        .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+}
+
+impl RhaiScriptAgent {
+    fn update_configs(&mut self) -> Result<(), AgentError> {
+        // Without configs, only a precompiled script can run
+        let configs = self.data.spec.configs.clone().unwrap_or_default();
+        let keep_last_good = configs.get_bool_or_default(CONFIG_KEEP_LAST_GOOD);
+        let _scope = opaque_scope(self.flow_id());
+        if let Err(e) = self.apply_configs(&configs) {
+            // Nothing was applied, so the previous configuration keeps running
+            self.last_error = Some(e.to_string());
+            self.degraded = keep_last_good;
+            if keep_last_good {
+                log::error!("{}: keeping the previous script: {}", self.id(), e);
+                return Ok(());
+            }
+            return Err(e);
+        }
+        self.last_error = None;
+        self.degraded = false;
+        Ok(())
+    }
+
+    /// Apply `configs` all at once, or none of them when one is invalid, a
+    /// script fails to compile or the `self_test` cases fail.
+    fn apply_configs(&mut self, configs: &AgentConfigs) -> Result<(), AgentError> {
+        let mut configured = self.parse_configs(configs)?;
+        // The cases run with the rest of the new configuration, which is
+        // swapped back out if they fail
+        self.swap_configured(&mut configured);
+        if let (Some(ast), Some(cases)) = (self.ast.clone(), &configured.self_test)
+            && let Err(e) = self.self_test(&ast, self.pre_transform.as_deref(), cases)
+        {
+            self.swap_configured(&mut configured);
+            return Err(e);
+        }
+        self.channels.set_names(&configured.channels);
+        while self.recent.len() > self.history_size {
+            self.recent.pop_front();
+        }
+        // Scripts counting on msg_index start over with the new configuration
+        self.msg_index = 0;
+        self.last_change = None;
+        self.set_debug_ports();
+        Ok(())
+    }
+
+    fn parse_configs(&self, configs: &AgentConfigs) -> Result<Configured, AgentError> {
+        if configs.contains_key(CONFIG_CHECKED_ARITHMETIC) {
+            return Err(AgentError::InvalidConfig(format!(
+                "{} can't be set per agent: script arithmetic is checked unless this crate is built with its unchecked feature",
+                CONFIG_CHECKED_ARITHMETIC
+            )));
+        }
+        let script = configs.get_string_or_default(CONFIG_SCRIPT);
+        let normalize = configs.get_bool_or_default(CONFIG_NORMALIZE_CACHE_KEY);
+        let disabled_symbols =
+            split_symbols(&configs.get_string_or_default(CONFIG_DISABLED_SYMBOLS));
+        let pure = configs.get_bool_or_default(CONFIG_PURE);
+        let ast = compile_restricted(&script, normalize, &disabled_symbols)?
+            .or_else(|| self.precompiled.clone());
+        let shared_lib = configs.get_string_or_default(CONFIG_SHARED_LIB);
+        let ast = match (ast, compile_shared_lib(&shared_lib, &disabled_symbols)?) {
+            (Some(ast), Some(lib)) => Some(merge_shared_lib(ast, &lib)),
+            (ast, _) => ast,
+        };
+        let pre_transform = compile_restricted(
+            &configs.get_string_or_default(CONFIG_PRE_TRANSFORM),
+            normalize,
+            &disabled_symbols,
+        )?;
+        let fallback = compile_restricted(
+            &configs.get_string_or_default(CONFIG_FALLBACK_SCRIPT),
+            normalize,
+            &disabled_symbols,
+        )?;
+        let heartbeat_ast = compile_restricted(
+            &configs.get_string_or_default(CONFIG_HEARTBEAT_SCRIPT),
+            false,
+            &disabled_symbols,
+        )?;
+        if pure {
+            let scripts = [&ast, &pre_transform, &fallback, &heartbeat_ast];
+            for ast in scripts.into_iter().flatten() {
+                check_pure(ast)?;
+            }
+        }
+        let self_test = configs.get_string_or_default(CONFIG_SELF_TEST);
+        let self_test = match &ast {
+            Some(_) if !self_test.trim().is_empty() => {
+                let json = serde_json::from_str(&self_test).map_err(|e| {
+                    AgentError::InvalidConfig(format!("self_test is not valid JSON: {}", e))
+                })?;
+                Some(AgentValue::from_json(json)?)
+            }
+            _ => None,
+        };
+        let heartbeat_ms = configs.get_integer_or_default(CONFIG_HEARTBEAT_MS).max(0);
+
+        let route_table = configs
+            .get_object_or_default(CONFIG_ROUTE_TABLE)
+            .into_iter()
+            .map(|(key, port)| match port.as_str() {
+                Some(port) => Ok((key, port.to_string())),
+                None => Err(AgentError::InvalidConfig(format!(
+                    "route_table entry {} must be a port name",
+                    key
+                ))),
+            })
+            .collect::<Result<_, _>>()?;
+        let output_schema = configs
+            .get_object_or_default(CONFIG_OUTPUT_SCHEMA)
+            .into_iter()
+            .map(|(key, ty)| match ty.as_str() {
+                Some(ty) if SCHEMA_TYPES.contains(&ty) => Ok((key, ty.to_string())),
+                _ => Err(AgentError::InvalidConfig(format!(
+                    "output_schema type of {} must be one of {}",
+                    key,
+                    SCHEMA_TYPES.join(", ")
+                ))),
+            })
+            .collect::<Result<_, _>>()?;
+        let element_type = configs.get_string_or_default(CONFIG_ELEMENT_TYPE);
+        if !element_type.is_empty()
+            && element_type != "same"
+            && !SCHEMA_TYPES.contains(&element_type.as_str())
+        {
+            return Err(AgentError::InvalidConfig(format!(
+                "element_type must be same or one of {}",
+                SCHEMA_TYPES.join(", ")
+            )));
+        }
+        let constants = configs
+            .get_object_or_default(CONFIG_CONSTANTS)
+            .into_iter()
+            .map(|(k, v)| Ok((k, from_value_to_dynamic(v)?)))
+            .collect::<Result<_, AgentError>>()?;
+        let tables = configs.get_object_or_default(CONFIG_TABLES);
+        let tables = if tables.is_empty() {
+            None
+        } else {
+            Some(Arc::new(
+                tables
+                    .into_iter()
+                    .map(|(name, table)| load_table(&name, table).map(|t| (name, t)))
+                    .collect::<Result<_, AgentError>>()?,
+            ))
+        };
+        let state_limits = StateLimits {
+            max_entries: configs
+                .get_integer_or_default(CONFIG_STATE_MAX_ENTRIES)
+                .max(0) as usize,
+            max_bytes: configs
+                .get_integer_or_default(CONFIG_STATE_MAX_BYTES)
+                .max(0) as usize,
+            eviction: Eviction::from_name(&configs.get_string_or_default(CONFIG_STATE_EVICTION))?,
+        };
+        let round = configs.get_integer_or(CONFIG_OUTPUT_ROUND, -1);
+        let coalesce_ms = configs.get_integer_or_default(CONFIG_COALESCE_MS).max(0);
+        let backoff = configs.get_integer_or(CONFIG_RETRY_BACKOFF_MS, 100).max(0);
+        // Evaluations already waiting keep the previous limit
+        let max_evals = configs
+            .get_integer_or_default(CONFIG_MAX_CONCURRENT_EVALS)
+            .max(0) as usize;
+
+        Ok(Configured {
+            sizes_input: ast
+                .as_ref()
+                .is_some_and(|ast| script_metadata(ast).called_fns.contains("value_size")),
+            ast,
+            source_len: script.len(),
+            pre_transform,
+            fallback,
+            pure,
+            heartbeat_interval: Duration::from_millis(heartbeat_ms as u64),
+            heartbeat_ast,
+            auto_iterate: configs.get_bool_or_default(CONFIG_AUTO_ITERATE),
+            tee: configs.get_bool_or_default(CONFIG_TEE),
+            route_on_type_only: configs.get_bool_or_default(CONFIG_ROUTE_ON_TYPE_ONLY),
+            route_table,
+            wrap_output: configs.get_string_or_default(CONFIG_WRAP_OUTPUT),
+            wrap_always: configs.get_bool_or_default(CONFIG_WRAP_ALWAYS),
+            result_var: configs.get_string_or_default(CONFIG_RESULT_VAR),
+            output_schema,
+            schema_errors_to_port: configs.get_bool_or_default(CONFIG_SCHEMA_ERRORS_TO_PORT),
+            error_key: configs.get_string_or_default(CONFIG_ERROR_KEY),
+            limit_errors_to_port: configs.get_bool_or_default(CONFIG_LIMIT_ERRORS_TO_PORT),
+            element_type,
+            change_key: configs.get_string_or_default(CONFIG_CHANGE_KEY),
+            persist_seq: configs.get_bool_or_default(CONFIG_PERSIST_SEQ),
+            skip_on_unit: configs.get_bool_or_default(CONFIG_SKIP_ON_UNIT),
+            pass_unit: configs.get_bool_or_default(CONFIG_PASS_UNIT),
+            debug_stages: configs.get_bool_or_default(CONFIG_DEBUG_STAGES),
+            timings: configs.get_bool_or_default(CONFIG_TIMINGS),
+            history_size: configs.get_integer_or_default(CONFIG_HISTORY_SIZE).max(0) as usize,
+            coalesce: Duration::from_millis(coalesce_ms as u64),
+            convert_options: ConvertOptions {
+                round: i32::try_from(round).ok().filter(|r| *r >= 0),
+                round_recursive: configs.get_bool_or(CONFIG_OUTPUT_ROUND_RECURSIVE, true),
+                ..Default::default()
+            },
+            seed_from: configs.get_string_or_default(CONFIG_SEED_FROM),
+            log_prefix: configs.get_string_or_default(CONFIG_LOG_PREFIX),
+            constants,
+            tables,
+            state_limits,
+            max_retries: configs
+                .get_integer_or_default(CONFIG_MAX_RETRIES)
+                .clamp(0, 32) as u32,
+            retry_backoff: Duration::from_millis(backoff as u64),
+            eval_limit: (max_evals > 0).then(|| Arc::new(Semaphore::new(max_evals))),
+            channels: split_symbols(&configs.get_string_or_default(CONFIG_CHANNELS)),
+            self_test,
+        })
+    }
+
+    /// Exchange the parts of the agent set by its configs with `configured`,
+    /// so that doing it again restores them.
+    fn swap_configured(&mut self, c: &mut Configured) {
+        use std::mem::swap;
+        swap(&mut self.ast, &mut c.ast);
+        swap(&mut self.source_len, &mut c.source_len);
+        swap(&mut self.sizes_input, &mut c.sizes_input);
+        swap(&mut self.pre_transform, &mut c.pre_transform);
+        swap(&mut self.fallback, &mut c.fallback);
+        swap(&mut self.pure, &mut c.pure);
+        swap(&mut self.heartbeat.interval, &mut c.heartbeat_interval);
+        swap(&mut self.heartbeat.ast, &mut c.heartbeat_ast);
+        swap(&mut self.auto_iterate, &mut c.auto_iterate);
+        swap(&mut self.tee, &mut c.tee);
+        swap(&mut self.route_on_type_only, &mut c.route_on_type_only);
+        swap(&mut self.route_table, &mut c.route_table);
+        swap(&mut self.wrap_output, &mut c.wrap_output);
+        swap(&mut self.wrap_always, &mut c.wrap_always);
+        swap(&mut self.result_var, &mut c.result_var);
+        swap(&mut self.output_schema, &mut c.output_schema);
+        swap(
+            &mut self.schema_errors_to_port,
+            &mut c.schema_errors_to_port,
+        );
+        swap(&mut self.error_key, &mut c.error_key);
+        swap(&mut self.limit_errors_to_port, &mut c.limit_errors_to_port);
+        swap(&mut self.element_type, &mut c.element_type);
+        swap(&mut self.change_key, &mut c.change_key);
+        swap(&mut self.persist_seq, &mut c.persist_seq);
+        swap(&mut self.skip_on_unit, &mut c.skip_on_unit);
+        swap(&mut self.pass_unit, &mut c.pass_unit);
+        swap(&mut self.debug_stages, &mut c.debug_stages);
+        swap(&mut self.timings, &mut c.timings);
+        swap(&mut self.history_size, &mut c.history_size);
+        swap(&mut self.coalesce, &mut c.coalesce);
+        swap(&mut self.convert_options, &mut c.convert_options);
+        swap(&mut self.seed_from, &mut c.seed_from);
+        swap(&mut self.log_prefix, &mut c.log_prefix);
+        swap(&mut self.constants, &mut c.constants);
+        swap(&mut self.tables, &mut c.tables);
+        swap(&mut self.state.limits, &mut c.state_limits);
+        swap(&mut self.max_retries, &mut c.max_retries);
+        swap(&mut self.retry_backoff, &mut c.retry_backoff);
+        swap(&mut self.eval_limit, &mut c.eval_limit);
+    }
+
+    /// Run `ast`, compiled by the host, e.g. once for many agents, while the
+    /// `script` config is empty. It is refused while a script is configured.
+    ///
+    /// The other configs apply to it as to a configured script, such as
+    /// `shared_lib`, `pure` and `self_test`, except `disabled_symbols`, which
+    /// only restricts parsing.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use agent_stream_kit::{ASKit, AgentValue, AsAgent};
+    /// use askit_rhai_agents::agents::RhaiScriptAgent;
+    ///
+    /// let ast = Arc::new(askit_rhai_agents::engine::get_engine().compile("value * 2").unwrap());
+    /// let spec = RhaiScriptAgent::agent_definition().to_spec();
+    /// let mut agent = RhaiScriptAgent::new(ASKit::new(), "double".into(), spec).unwrap();
+    /// agent.set_ast(ast).unwrap();
+    /// assert!(agent.status().has_ast);
+    ///
+    /// let empty = Arc::new(askit_rhai_agents::engine::get_engine().compile("").unwrap());
+    /// assert!(agent.set_ast(empty).is_err());
+    /// ```
+    pub fn set_ast(&mut self, ast: Arc<AST>) -> Result<(), AgentError> {
+        if ast.statements().is_empty() && !ast.has_functions() {
+            return Err(AgentError::InvalidConfig(
+                "The precompiled script is empty".to_string(),
+            ));
+        }
+        let configured = self
+            .data
+            .spec
+            .configs
+            .as_ref()
+            .is_some_and(|configs| !configs.get_string_or_default(CONFIG_SCRIPT).is_empty());
+        if configured {
+            return Err(AgentError::InvalidConfig(format!(
+                "A precompiled script can't replace the {} config",
+                CONFIG_SCRIPT
+            )));
+        }
+        let prev = self.precompiled.replace(ast);
+        if let Err(e) = self.update_configs() {
+            self.precompiled = prev;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// The state of the compiled script.
+    pub fn status(&self) -> ScriptStatus {
+        ScriptStatus {
+            has_ast: self.ast.is_some(),
+            source_len: self.source_len,
+            last_error: self.last_error.clone(),
+            degraded: self.degraded,
+        }
+    }
+
+    /// A scope with the configured constants and `value`.
+    fn new_scope(&self, value: Dynamic) -> Scope<'static> {
+        let mut scope = Scope::new();
+        for (name, constant) in &self.constants {
+            // Constants can't be reassigned by the script
+            scope.push_constant(name.as_str(), constant.clone());
+        }
+        scope.push("value", value);
+        scope
+    }
+
+    /// Evaluate the script, retrying with exponential backoff while it fails
+    /// with a retryable error.
+    async fn eval_with_retry(
+        &mut self,
+        ctx: &AgentContext,
+        value: &AgentValue,
+    ) -> Result<AgentValue, AgentError> {
+        let mut attempt = 0;
+        loop {
+            let result = {
+                let _permits = eval_permits(self.eval_limit.as_ref()).await;
+                self.eval(ctx, value.clone())
+            };
+            match result {
+                Ok(out_value) => return Ok(out_value),
+                Err(e) if e.retryable && attempt < self.max_retries => {
+                    let delay = retry_delay(self.retry_backoff, attempt);
+                    attempt += 1;
+                    log::warn!(
+                        "{}: retrying in {:?} ({}/{}): {}",
+                        self.id(),
+                        delay,
+                        attempt,
+                        self.max_retries,
+                        e.error
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return self.eval_fallback(ctx, value, e.error).await,
+            }
+        }
+    }
+
+    /// Run the fallback script after the script failed with `error`.
+    async fn eval_fallback(
+        &mut self,
+        ctx: &AgentContext,
+        value: &AgentValue,
+        error: AgentError,
+    ) -> Result<AgentValue, AgentError> {
+        let Some(fallback) = self.fallback.clone() else {
+            return Err(error);
+        };
+        log::warn!("{}: running the fallback script: {}", self.id(), error);
+        let _permits = eval_permits(self.eval_limit.as_ref()).await;
+        self.eval_script(&fallback, ctx, value.clone())
+            .map_err(|e| e.error)
+    }
+
+    fn eval(&mut self, ctx: &AgentContext, value: AgentValue) -> Result<AgentValue, EvalError> {
+        let Some(ast) = self.ast.clone() else {
+            return Ok(AgentValue::unit());
+        };
+        self.eval_script(&ast, ctx, value)
+    }
+
+    fn eval_script(
+        &mut self,
+        ast: &AST,
+        ctx: &AgentContext,
+        value: AgentValue,
+    ) -> Result<AgentValue, EvalError> {
+        let _scope = opaque_scope(self.flow_id());
+        let pre_transform = self.pre_transform.clone();
+        let evaluated =
+            self.evaluate(ast, pre_transform.as_deref(), ctx, value, self.state.map())?;
+        self.state.update(&self.data.id, evaluated.state)?;
+        self.sends = evaluated.sends;
+        self.publishes = evaluated.publishes;
+        self.emits = evaluated.emits;
+        self.pause = self.pause.max(evaluated.pause);
+        self.no_output = evaluated.no_output;
+        self.debug_outputs = evaluated.debug_outputs;
+        Ok(evaluated.value)
+    }
+
+    /// Evaluate `ast` for `value` without changing the agent.
+    fn evaluate(
+        &self,
+        ast: &AST,
+        pre_transform: Option<&AST>,
+        ctx: &AgentContext,
+        value: AgentValue,
+        state: rhai::Map,
+    ) -> Result<Evaluated, EvalError> {
+        let _scope = opaque_scope(self.flow_id());
+        let rng = if self.seed_from.is_empty() {
+            None
+        } else {
+            value.get(&self.seed_from).map(seed_from_value)
+        };
+
+        let input_size = self.sizes_input.then(|| agent_value_size(&value));
+        let mut debug_outputs = Vec::new();
+        let started = Instant::now();
+        let mut input = from_value_to_dynamic(value)?;
+        let converted_in = started.elapsed();
+
+        let started = Instant::now();
+        if let Some(pre_transform) = pre_transform {
+            let mut scope = self.new_scope(input);
+            input = eval_ast(self.id(), ctx, pre_transform, &mut scope)?;
+            if self.debug_stages {
+                debug_outputs.push((PORT_STAGE_1, from_dynamic_to_value(&input)?));
+            }
+        }
+
+        // scope.push("ctx", Dynamic::from(ctx.clone()));
+        let mut scope = self.new_scope(input);
+        scope.push("state", state);
+        scope.push_constant("msg_index", self.msg_index as INT);
+        scope.push_constant("generation", self.generation as INT);
+        scope.push_constant("seq", self.seq.load(Ordering::Relaxed) as INT);
+        // Scripts can check e.g. `pin_timestamps.other.elapsed > 5.0` (seconds)
+        scope.push_constant("pin_timestamps", self.pin_timestamps.clone());
+        if let Some(channels) = self.channels.snapshot() {
+            scope.push_constant("channels", channels);
+        }
+        if self.history_size > 0 {
+            let recent: rhai::Array = self.recent.iter().cloned().collect();
+            scope.push_constant("recent", recent);
+        }
+        // The declared output ports, so that a script can check a port
+        // before routing to it with __port__
+        let outputs: rhai::Array = self
+            .spec()
+            .outputs
+            .iter()
+            .flatten()
+            .map(|port| port.as_str().into())
+            .collect();
+        scope.push_constant("outputs", outputs);
+
+        let mut caller = Caller {
+            agent_id: self.id().to_string(),
+            ctx: ctx.clone(),
+            rng,
+            askit: Some(self.askit().clone()),
+            sends: Vec::new(),
+            publishes: Vec::new(),
+            emits: Vec::new(),
+            pause: Duration::ZERO,
+            output: None,
+            tables: self.tables.clone(),
+            log_prefix: self.log_prefix.clone(),
+            input_size,
+        };
+        let mut result =
+            try_eval_ast_with(&mut caller, ast, &mut scope).map_err(|e| EvalError {
+                retryable: is_retryable(&e),
+                error: runtime_error(e),
+            })?;
+        let no_output = matches!(caller.output, Some(ScriptOutput::None));
+        if let Some(ScriptOutput::Value(value)) = caller.output.take() {
+            result = value;
+        }
+        let Some(state) = scope.remove::<rhai::Map>("state") else {
+            return Err(AgentError::InvalidValue("state must be an object".to_string()).into());
+        };
+        let evaluated = started.elapsed();
+
+        let started = Instant::now();
+        let value = from_dynamic_to_value_with(&result, &self.convert_options)?;
+        let converted_out = started.elapsed();
+        if self.debug_stages {
+            debug_outputs.push((PORT_STAGE_2, value.clone()));
+        }
+        if self.timings {
+            let micros = |d: Duration| AgentValue::integer(d.as_micros() as i64);
+            let mut map = AgentValueMap::new();
+            map.insert("convert_in_micros".to_string(), micros(converted_in));
+            map.insert("eval_micros".to_string(), micros(evaluated));
+            map.insert("convert_out_micros".to_string(), micros(converted_out));
+            debug_outputs.push((PORT_DIAGNOSTICS, AgentValue::object(map)));
+        }
+        Ok(Evaluated {
+            value,
+            state,
+            sends: caller.sends,
+            publishes: caller.publishes,
+            emits: caller.emits,
+            pause: caller.pause,
+            no_output,
+            debug_outputs,
+        })
+    }
+
+    /// Check the `self_test` cases against a newly compiled script, starting
+    /// each from an empty state.
+    fn self_test(
+        &self,
+        ast: &AST,
+        pre_transform: Option<&AST>,
+        cases: &AgentValue,
+    ) -> Result<(), AgentError> {
+        let Some(cases) = cases.as_array() else {
+            return Err(AgentError::InvalidConfig(
+                "self_test must be an array of {input, expected}".to_string(),
+            ));
+        };
+        let ctx = AgentContext::new();
+        for (i, case) in cases.iter().enumerate() {
+            let input = case.get("input").cloned().unwrap_or_default();
+            let expected = case.get("expected").cloned().unwrap_or_default();
+            let actual = self
+                .evaluate(ast, pre_transform, &ctx, input, rhai::Map::new())
+                .map_err(|e| {
+                    AgentError::InvalidConfig(format!("self_test case {}: {}", i, e.error))
+                })?
+                .value;
+            if actual != expected {
+                return Err(AgentError::InvalidConfig(format!(
+                    "self_test case {}: expected {}, got {}",
+                    i,
+                    expected.to_json(),
+                    actual.to_json()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Output a script result, on the port named by its `__port__` key if it has one.
+    fn emit(&self, ctx: AgentContext, value: AgentValue) -> Result<(), AgentError> {
+        let (port, value) = route_by_port_key(self.spec().outputs.as_deref(), value)?;
+        self.output(ctx, port, value)
+    }
+
+    /// Output a value stamped with the next sequence number.
+    fn output(
+        &self,
+        ctx: AgentContext,
+        port: impl Into<String>,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        self.try_output(stamp_seq(&self.seq, &ctx), port, value)
+    }
+
+    async fn run(&mut self, ctx: &AgentContext, value: &AgentValue) -> Result<(), AgentError> {
+        let result = match self.run_script(ctx, value).await {
+            Err(AgentError::IoError(message))
+                if self.limit_errors_to_port && message.starts_with(LIMIT_ERROR_PREFIX) =>
+            {
+                let mut map = AgentValueMap::new();
+                map.insert("error".to_string(), AgentValue::string(message));
+                map.insert("value".to_string(), value.clone());
+                self.output(ctx.clone(), PORT_ERROR, AgentValue::object(map))
+            }
+            result => result,
+        };
+        if self.history_size > 0 {
+            if self.recent.len() >= self.history_size {
+                self.recent.pop_front();
+            }
+            let _scope = opaque_scope(self.flow_id());
+            self.recent.push_back(from_value_to_dynamic(value.clone())?);
+        }
+        result
+    }
+
+    async fn run_script(
+        &mut self,
+        ctx: &AgentContext,
+        value: &AgentValue,
+    ) -> Result<(), AgentError> {
+        if self.skip_on_unit && value.is_unit() {
+            if self.pass_unit {
+                return self.output(ctx.clone(), PORT_VALUE, AgentValue::unit());
+            }
+            return Ok(());
+        }
+        if !self.change_key.is_empty() {
+            // A missing field counts as unit
+            let key = value.get(&self.change_key).cloned().unwrap_or_default();
+            if self.last_change.as_ref() == Some(&key) {
+                return Ok(());
+            }
+            self.last_change = Some(key);
+        }
+        if self.route_on_type_only {
+            self.route_by_type(ctx, value).await?;
+            return self.deliver_sends(ctx);
+        }
+        if !self.route_table.is_empty() {
+            self.route_by_table(ctx, value).await?;
+            return self.deliver_sends(ctx);
+        }
+        let out_value = self.eval_with_retry(ctx, value).await?;
+        self.emit_pending(ctx)?;
+        if std::mem::take(&mut self.no_output) {
+            return self.deliver_sends(ctx);
+        }
+        // A returned error takes precedence over the `__port__` key and the
+        // output schema, which only apply to successful results
+        if !self.error_key.is_empty()
+            && out_value
+                .get(&self.error_key)
+                .is_some_and(|error| !error.is_unit())
+        {
+            self.output(ctx.clone(), PORT_ERROR, out_value)?;
+            return self.deliver_sends(ctx);
+        }
+        if let Err(e) = check_schema(&self.output_schema, &out_value) {
+            if !self.schema_errors_to_port {
+                // Like a failed script, a rejected result sends nothing
+                self.sends.clear();
+                self.publishes.clear();
+                return Err(AgentError::InvalidValue(e));
+            }
+            let mut map = AgentValueMap::new();
+            map.insert("error".to_string(), AgentValue::string(e));
+            map.insert("value".to_string(), out_value);
+            self.output(ctx.clone(), PORT_ERROR, AgentValue::object(map))?;
+            return self.deliver_sends(ctx);
+        }
+        if !self.result_var.is_empty() {
+            let out_ctx = ctx.with_var(self.result_var.clone(), out_value);
+            self.output(out_ctx, PORT_VALUE, value.clone())?;
+            return self.deliver_sends(ctx);
+        }
+        let out_value = self.wrap(out_value);
+        if self.tee {
+            self.output(ctx.clone(), PORT_DERIVED, out_value)?;
+            self.output(ctx.clone(), PORT_VALUE, value.clone())?;
+        } else {
+            self.emit(ctx.clone(), out_value)?;
+        }
+        self.deliver_sends(ctx)
+    }
+
+    /// Emit the debug outputs and the values passed to `emit_now`, and
+    /// schedule those passed to `emit_after`, ahead of the script's result.
+    fn emit_pending(&mut self, ctx: &AgentContext) -> Result<(), AgentError> {
+        for (port, value) in std::mem::take(&mut self.debug_outputs) {
+            self.output(ctx.clone(), port, value)?;
+        }
+        self.delayed.retain(|task| !task.is_finished());
+        for (delay, value) in std::mem::take(&mut self.emits) {
+            if delay.is_zero() {
+                self.output(ctx.clone(), PORT_VALUE, value)?;
+                continue;
+            }
+            let (askit, agent_id) = (self.askit().clone(), self.id().to_string());
+            let (seq, ctx) = (self.seq.clone(), ctx.clone());
+            self.delayed.push(tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let ctx = stamp_seq(&seq, &ctx);
+                if let Err(e) =
+                    askit.try_send_agent_out(agent_id.clone(), ctx, PORT_VALUE.to_string(), value)
+                {
+                    log::warn!("{}: emit_after failed: {}", agent_id, e);
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    /// Declare the stage, diagnostics and error ports only while they are used.
+    fn set_debug_ports(&mut self) {
+        let debug_ports = [PORT_STAGE_1, PORT_STAGE_2, PORT_DIAGNOSTICS, PORT_ERROR];
+        let outputs = self.data.spec.outputs.get_or_insert_default();
+        outputs.retain(|port| !debug_ports.contains(&port.as_str()));
+        if self.debug_stages {
+            outputs.push(PORT_STAGE_1.to_string());
+            outputs.push(PORT_STAGE_2.to_string());
+        }
+        if self.timings {
+            outputs.push(PORT_DIAGNOSTICS.to_string());
+        }
+        if self.schema_errors_to_port || !self.error_key.is_empty() || self.limit_errors_to_port {
+            outputs.push(PORT_ERROR.to_string());
+        }
+    }
+
+    /// Wrap a result as `#{ <wrap_output>: value }` when configured.
+    fn wrap(&self, value: AgentValue) -> AgentValue {
+        if self.wrap_output.is_empty()
+            || (!self.wrap_always && (value.is_array() || value.is_object()))
+        {
+            return value;
+        }
+        let mut map = AgentValueMap::new();
+        map.insert(self.wrap_output.clone(), value);
+        AgentValue::object(map)
+    }
+
+    /// Deliver the values the script passed to `send_to` and `publish`.
+    ///
+    /// Sends are delivered in order by a task of their own rather than while
+    /// this agent is locked, since delivering waits for the target's lock and
+    /// two agents sending to each other would wait on each other forever.
+    fn deliver_sends(&mut self, ctx: &AgentContext) -> Result<(), AgentError> {
+        for (channel, value) in std::mem::take(&mut self.publishes) {
+            self.askit().write_board_value(channel, value)?;
+        }
+        let sends = std::mem::take(&mut self.sends);
+        if sends.is_empty() {
+            return Ok(());
+        }
+        let (askit, agent_id, ctx) = (self.askit().clone(), self.id().to_string(), ctx.clone());
+        let previous = self.delivery.take();
+        self.delivery = Some(tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            for (target, value) in sends {
+                if let Err(e) = askit
+                    .agent_input(target.clone(), ctx.clone(), PORT_VALUE.to_string(), value)
+                    .await
+                {
+                    log::warn!("{}: send_to {} failed: {}", agent_id, target, e);
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    /// Forward `value` to the port the script picks from its type alone,
+    /// without converting the value for the script.
+    async fn route_by_type(
+        &mut self,
+        ctx: &AgentContext,
+        value: &AgentValue,
+    ) -> Result<(), AgentError> {
+        let tag = AgentValue::string(type_tag(value));
+        let port = self.eval_with_retry(ctx, &tag).await?;
+        self.emit_pending(ctx)?;
+        let port = match port {
+            // Drop the value
+            _ if std::mem::take(&mut self.no_output) => return Ok(()),
+            AgentValue::Unit => return Ok(()),
+            AgentValue::String(port) => port.to_string(),
+            _ => {
+                return Err(AgentError::InvalidValue(
+                    "route_on_type_only script must return a port name".to_string(),
+                ));
+            }
+        };
+        self.check_port(&port)?;
+        self.output(ctx.clone(), port, value.clone())
+    }
+
+    /// Forward `value` to the port the route table gives for the key the
+    /// script returns.
+    async fn route_by_table(
+        &mut self,
+        ctx: &AgentContext,
+        value: &AgentValue,
+    ) -> Result<(), AgentError> {
+        let key = self.eval_with_retry(ctx, value).await?;
+        self.emit_pending(ctx)?;
+        let key = match key {
+            // Drop the value
+            _ if std::mem::take(&mut self.no_output) => return Ok(()),
+            AgentValue::Unit => return Ok(()),
+            AgentValue::String(key) => key,
+            _ => {
+                return Err(AgentError::InvalidValue(
+                    "Script must return a route_table key".to_string(),
+                ));
+            }
+        };
+        let Some(port) = self
+            .route_table
+            .get(key.as_str())
+            .or_else(|| self.route_table.get(ROUTE_DEFAULT))
+        else {
+            return Err(AgentError::InvalidValue(format!(
+                "No route for {} and no default route",
+                key
+            )));
+        };
+        self.check_port(port)?;
+        self.output(ctx.clone(), port.clone(), value.clone())
+    }
+
+    fn check_port(&self, port: &str) -> Result<(), AgentError> {
+        if !self
+            .spec()
+            .outputs
+            .as_ref()
+            .is_some_and(|outputs| outputs.iter().any(|o| o == port))
+        {
+            return Err(AgentError::PinNotFound(port.to_string()));
+        }
+        Ok(())
+    }
+
+    /// End the coalescing window after `coalesce` by taking the agent's lock,
+    /// as an input would, and processing the latest input of the window.
+    fn start_coalesce_timer(&mut self) {
+        let (askit, id, delay) = (self.askit().clone(), self.id().to_string(), self.coalesce);
+        self.coalesce_task = Some(tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let Some(agent) = askit.get_agent(&id) else {
+                return;
+            };
+            let mut agent = agent.lock().await;
+            let Some(agent) = agent.as_agent_mut::<RhaiScriptAgent>() else {
+                return;
+            };
+            if let Err(e) = agent.end_coalesce().await {
+                log::warn!("{}: failed to process the coalesced input: {}", id, e);
+            }
+        }));
+    }
+
+    async fn end_coalesce(&mut self) -> Result<(), AgentError> {
+        self.coalesce_task = None;
+        match self.coalesced.take() {
+            Some((ctx, value)) => self.process_input(ctx, value).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn receive(
+        &mut self,
+        ctx: AgentContext,
+        pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        self.heartbeat.touch();
+        self.pin_timestamps
+            .insert(pin.into(), Dynamic::from(Instant::now()));
+        if !self.coalesce.is_zero() {
+            self.coalesced = Some((ctx, value));
+            if self.coalesce_task.is_none() {
+                self.start_coalesce_timer();
+            }
+            return Ok(());
+        }
+        self.process_input(ctx, value).await
+    }
+
+    async fn process_input(
+        &mut self,
+        ctx: AgentContext,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        if self.ast.is_none() {
+            return Ok(());
+        }
+
+        let result = self.process_value(&ctx, &value).await;
+        self.msg_index += 1;
+        let pause = std::mem::take(&mut self.pause);
+        if result.is_ok() && !pause.is_zero() {
+            // Holding on to the input lets the framework's bounded channels
+            // push back on upstream agents
+            tokio::time::sleep(pause).await;
+        }
+        result
+    }
+
+    fn start_heartbeat(&mut self) {
+        let (askit, id) = (self.askit().clone(), self.id().to_string());
+        let flow_id = self.flow_id().to_string();
+        let limit = self.eval_limit.clone();
+        self.heartbeat
+            .start(askit, id, flow_id, self.seq.clone(), limit);
+    }
+
+    async fn process_value(
+        &mut self,
+        ctx: &AgentContext,
+        value: &AgentValue,
+    ) -> Result<(), AgentError> {
+        if !self.element_type.is_empty() {
+            check_elements(&self.element_type, value).map_err(AgentError::InvalidValue)?;
+        }
+        if self.auto_iterate
+            && let AgentValue::Array(arr) = value
+        {
+            for v in arr.iter() {
+                self.run(ctx, v).await?;
+            }
+            return Ok(());
+        }
+
+        self.run(ctx, value).await
+    }
+}
+
+fn stamp_seq(seq: &AtomicU64, ctx: &AgentContext) -> AgentContext {
+    let n = seq.fetch_add(1, Ordering::Relaxed);
+    ctx.with_var(SEQ_VAR.to_string(), AgentValue::integer(n as i64))
+}
+
+/// The latest values published on the channels an agent subscribes to, by
+/// channel name.
+///
+/// Channels are boards of agent-stream-kit, so values published by scripts
+/// and by board agents alike are seen. A channel is unit until a value is
+/// published on it after the agent starts, and then keeps that value, however
+/// old, until the next one.
+#[derive(Default)]
+struct Channels {
+    latest: Arc<Mutex<rhai::Map>>,
+    observer: Option<usize>,
+}
+
+impl Channels {
+    /// Subscribe to `names`, keeping the values of channels already subscribed to.
+    fn set_names(&self, names: &[String]) {
+        let mut latest = self.latest.lock().unwrap();
+        *latest = names
+            .iter()
+            .map(|name| {
+                let value = latest.get(name.as_str()).cloned().unwrap_or_default();
+                (name.into(), value)
+            })
+            .collect();
+    }
+
+    fn snapshot(&self) -> Option<rhai::Map> {
+        let latest = self.latest.lock().unwrap();
+        (!latest.is_empty()).then(|| latest.clone())
+    }
+
+    fn subscribe(&mut self, askit: &ASKit, flow_id: &str) {
+        if self.observer.is_none() {
+            let observer = ChannelObserver(self.latest.clone(), flow_id.to_string());
+            self.observer = Some(askit.subscribe(Box::new(observer)));
+        }
+    }
+
+    fn unsubscribe(&mut self, askit: &ASKit) {
+        if let Some(id) = self.observer.take() {
+            askit.unsubscribe(id);
+        }
+    }
+}
+
+/// Updates the latest values, converted in the scope of the subscribing flow.
+struct ChannelObserver(Arc<Mutex<rhai::Map>>, String);
+
+impl ASKitObserver for ChannelObserver {
+    fn notify(&self, event: &ASKitEvent) {
+        let ASKitEvent::Board(name, value) = event else {
+            return;
+        };
+        let mut latest = self.0.lock().unwrap();
+        let Some(slot) = latest.get_mut(name.as_str()) else {
+            return;
+        };
+        let _scope = opaque_scope(&self.1);
+        match from_value_to_dynamic(value.clone()) {
+            Ok(value) => *slot = value,
+            Err(e) => log::warn!("Failed to read channel {}: {}", name, e),
+        }
+    }
+}
+
+/// Emits a value whenever an agent has had no input for a while.
+#[derive(Default)]
+struct Heartbeat {
+    interval: Duration,
+    ast: Option<Arc<AST>>,
+
+    /// When the last input arrived or heartbeat was emitted.
+    last_activity: Arc<Mutex<Option<Instant>>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn start(
+        &mut self,
+        askit: ASKit,
+        agent_id: String,
+        flow_id: String,
+        seq: Arc<AtomicU64>,
+        limit: Option<Arc<Semaphore>>,
+    ) {
+        self.stop();
+        if self.interval.is_zero() {
+            return;
+        }
+        self.touch();
+        let interval = self.interval;
+        let ast = self.ast.clone();
+        let last_activity = self.last_activity.clone();
+        self.task = Some(tokio::spawn(async move {
+            loop {
+                let since = last_activity
+                    .lock()
+                    .unwrap()
+                    .map_or(interval, |t| t.elapsed());
+                if since < interval {
+                    tokio::time::sleep(interval - since).await;
+                    continue;
+                }
+                *last_activity.lock().unwrap() = Some(Instant::now());
+
+                let ctx = AgentContext::new();
+                let value = match &ast {
+                    Some(ast) => {
+                        let _permits = eval_permits(limit.as_ref()).await;
+                        let _scope = opaque_scope(&flow_id);
+                        eval_ast(&agent_id, &ctx, ast, &mut Scope::new())
+                            .and_then(|result| from_dynamic_to_value(&result))
+                    }
+                    None => Ok(AgentValue::unit()),
+                };
+                let sent = value.and_then(|value| {
+                    let ctx = stamp_seq(&seq, &ctx);
+                    askit.try_send_agent_out(agent_id.clone(), ctx, PORT_VALUE.to_string(), value)
+                });
+                if let Err(e) = sent {
+                    log::warn!("{}: heartbeat failed: {}", agent_id, e);
+                }
+            }
+        }));
+    }
+
+    fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// A span for an agent processing an input on `pin`, to trace script runs.
+///
+/// Its `outcome` field is left empty for [`outcome`] to fill in once the
+/// input is processed.
+fn process_span(agent_id: &str, pin: &str) -> tracing::Span {
+    tracing::info_span!(
+        "process",
+        agent = agent_id,
+        pin = pin,
+        outcome = tracing::field::Empty
+    )
+}
+
+/// How processing an input ended: `ok`, `timeout` when the script was
+/// stopped by a resource limit or terminated, or `error`.
+fn outcome(result: &Result<(), AgentError>) -> &'static str {
+    match result {
+        Ok(()) => "ok",
+        Err(e) if script_error_kind(e) == Some(ScriptErrorKind::Limit) => "timeout",
+        Err(_) => "error",
+    }
+}
+
+#[async_trait]
+impl AsAgent for RhaiScriptAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        let mut agent = Self {
+            data: AgentData::new(askit, id, spec),
+            opaque_flow: None,
+            ast: None,
+            precompiled: None,
+            source_len: 0,
+            sizes_input: false,
+            last_error: None,
+            degraded: false,
+            pure: false,
+            pre_transform: None,
+            fallback: None,
+            auto_iterate: false,
+            convert_options: ConvertOptions::default(),
+            seed_from: String::new(),
+            constants: Vec::new(),
+            tables: None,
+            log_prefix: String::new(),
+            state: StateStore::default(),
+            max_retries: 0,
+            retry_backoff: Duration::ZERO,
+            eval_limit: None,
+            tee: false,
+            route_on_type_only: false,
+            route_table: AgentValueMap::new(),
+            sends: Vec::new(),
+            delivery: None,
+            publishes: Vec::new(),
+            channels: Channels::default(),
+            emits: Vec::new(),
+            delayed: Vec::new(),
+            pause: Duration::ZERO,
+            no_output: false,
+            debug_stages: false,
+            timings: false,
+            debug_outputs: Vec::new(),
+            wrap_output: String::new(),
+            wrap_always: false,
+            result_var: String::new(),
+            output_schema: AgentValueMap::new(),
+            element_type: String::new(),
+            change_key: String::new(),
+            last_change: None,
+            schema_errors_to_port: false,
+            error_key: String::new(),
+            limit_errors_to_port: false,
+            heartbeat: Heartbeat::default(),
+            coalesce: Duration::ZERO,
+            coalesced: None,
+            coalesce_task: None,
+            history_size: 0,
+            recent: VecDeque::new(),
+            pin_timestamps: rhai::Map::new(),
+            seq: Arc::new(AtomicU64::new(0)),
+            persist_seq: false,
+            skip_on_unit: false,
+            pass_unit: false,
+            msg_index: 0,
+            generation: 0,
+        };
+        agent.update_configs()?;
+        Ok(agent)
+    }
+
+    fn configs_changed(&mut self) -> Result<(), AgentError> {
+        let outputs = self.spec().outputs.clone();
+        self.update_configs()?;
+        self.generation += 1;
+        if self.spec().outputs != outputs {
+            self.emit_agent_spec_updated();
+        }
+        // The running heartbeat holds on to the previous script and eval
+        // limit, so it starts over with the new ones
+        if self.heartbeat.task.is_some() {
+            self.start_heartbeat();
+        }
+        Ok(())
+    }
+
+    async fn start(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = Some(hold_opaque_flow(self.flow_id()));
+        if !self.persist_seq {
+            self.seq.store(0, Ordering::Relaxed);
+        }
+        self.start_heartbeat();
+        let (askit, flow_id) = (self.askit().clone(), self.flow_id().to_string());
+        self.channels.subscribe(&askit, &flow_id);
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        self.heartbeat.stop();
+        let askit = self.askit().clone();
+        self.channels.unsubscribe(&askit);
+        for task in self.delayed.drain(..) {
+            task.abort();
+        }
+        if let Some(task) = self.coalesce_task.take() {
+            task.abort();
+        }
+        // The last input is processed even when its window is cut short
+        let processed = match self.coalesced.take() {
+            Some((ctx, value)) => self.process_input(ctx, value).await,
+            None => Ok(()),
+        };
+        self.opaque_flow = None;
+        processed
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let span = process_span(self.id(), &pin);
+        let result = self.receive(ctx, pin, value).instrument(span.clone()).await;
+        span.record("outcome", outcome(&result));
+        result
+    }
+}
+
+static MODE_PARSE: &str = "parse";
+static MODE_SERIALIZE: &str = "serialize";
+
+// Rhai Parse
+#[askit_agent(
+    title = "Rhai Parse",
+    category = CATEGORY,
+    inputs = [PORT_VALUE],
+    outputs = [PORT_VALUE],
+    string_config(
+        name = CONFIG_FORMAT,
+        default = "json",
+        title = "Format",
+        description = "json, csv-line or kv"
+    ),
+    string_config(
+        name = CONFIG_MODE,
+        default = MODE_PARSE,
+        title = "Mode",
+        description = "parse a string into a value, or serialize a value into a string"
+    ),
+    string_config(
+        name = CONFIG_FLOAT_FORMAT,
+        title = "Float Format",
+        description = "How the csv-line and kv output of this agent writes numbers: shortest (1.0, 1e21) or fixed:N; 1.0 becomes 1 when empty. JSON output is not affected"
+    ),
+    text_config(
+        name = CONFIG_SCRIPT,
+        title = "Script",
+        description = "Applied to the parsed value, or to the value before it is serialized"
+    )
+)]
+struct RhaiParseAgent {
+    data: AgentData,
+    opaque_flow: Option<OpaqueFlow>,
+    format: Format,
+    float_format: FloatFormat,
+    serialize: bool,
+    ast: Option<Arc<AST>>,
+}
+
+impl RhaiParseAgent {
+    fn update_configs(&mut self) -> Result<(), AgentError> {
+        let Some(configs) = self.data.spec.configs.as_ref() else {
+            return Ok(());
+        };
+        self.format = Format::from_name(&configs.get_string_or_default(CONFIG_FORMAT))?;
+        self.float_format =
+            FloatFormat::from_name(&configs.get_string_or_default(CONFIG_FLOAT_FORMAT))?;
+        let mode = configs.get_string_or(CONFIG_MODE, MODE_PARSE);
+        self.serialize = if mode == MODE_SERIALIZE {
+            true
+        } else if mode == MODE_PARSE || mode.is_empty() {
+            false
+        } else {
+            return Err(AgentError::InvalidConfig(format!("Unknown mode: {}", mode)));
+        };
+        self.ast = compile_script(&configs.get_string_or_default(CONFIG_SCRIPT), false)?;
+        Ok(())
+    }
+
+    fn apply_script(
+        &self,
+        ctx: &AgentContext,
+        value: AgentValue,
+    ) -> Result<AgentValue, AgentError> {
+        let Some(ast) = &self.ast else {
+            return Ok(value);
+        };
+        let mut scope = Scope::new();
+        scope.push("value", from_value_to_dynamic(value)?);
+        let result = eval_ast(self.id(), ctx, ast, &mut scope)?;
+        from_dynamic_to_value(&result)
+    }
+}
+
+#[async_trait]
+impl AsAgent for RhaiParseAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        let mut agent = Self {
+            data: AgentData::new(askit, id, spec),
+            opaque_flow: None,
+            format: Format::default(),
+            float_format: FloatFormat::default(),
+            serialize: false,
+            ast: None,
+        };
+        agent.update_configs()?;
+        Ok(agent)
+    }
+
+    fn configs_changed(&mut self) -> Result<(), AgentError> {
+        self.update_configs()
+    }
+
+    async fn start(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = Some(hold_opaque_flow(self.flow_id()));
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = None;
+        Ok(())
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let _permit = eval_permit().await;
+        let _scope = opaque_scope(self.flow_id());
+        let out_value = if self.serialize {
+            let value = self.apply_script(&ctx, value)?;
+            AgentValue::string(self.format.serialize(&value, self.float_format)?)
+        } else {
+            let Some(s) = value.as_str() else {
+                return Err(AgentError::InvalidValue(
+                    "Rhai Parse expects a string input".to_string(),
+                ));
+            };
+            let value = self.format.parse(s)?;
+            self.apply_script(&ctx, value)?
+        };
+        self.try_output(ctx, PORT_VALUE, out_value)
+    }
+}
+
+static FANOUT_TRUNCATE: &str = "truncate";
+static FANOUT_ERROR: &str = "error";
+
+// Rhai Split
+#[askit_agent(
+    title = "Rhai Split",
+    category = CATEGORY,
+    inputs = [PORT_VALUE],
+    outputs = [PORT_VALUE],
+    text_config(
+        name = CONFIG_SCRIPT,
+        title = "Script",
+        description = "Returns an array whose elements are emitted individually (the input is used when empty)"
+    ),
+    integer_config(
+        name = CONFIG_MAX_FANOUT,
+        title = "Max Fanout",
+        description = "Maximum number of elements emitted per input (0 for no limit)"
+    ),
+    string_config(
+        name = CONFIG_FANOUT_POLICY,
+        default = FANOUT_TRUNCATE,
+        title = "Fanout Policy",
+        description = "truncate or error when the array exceeds max_fanout"
+    )
+)]
+struct RhaiSplitAgent {
+    data: AgentData,
+    opaque_flow: Option<OpaqueFlow>,
+    ast: Option<Arc<AST>>,
+    max_fanout: usize,
+    truncate: bool,
+}
+
+impl RhaiSplitAgent {
+    fn update_configs(&mut self) -> Result<(), AgentError> {
+        let Some(configs) = self.data.spec.configs.as_ref() else {
+            return Ok(());
+        };
+        self.max_fanout = configs.get_integer_or_default(CONFIG_MAX_FANOUT).max(0) as usize;
+        let policy = configs.get_string_or(CONFIG_FANOUT_POLICY, FANOUT_TRUNCATE);
+        self.truncate = if policy == FANOUT_TRUNCATE || policy.is_empty() {
+            true
+        } else if policy == FANOUT_ERROR {
+            false
+        } else {
+            return Err(AgentError::InvalidConfig(format!(
+                "Unknown fanout policy: {}",
+                policy
+            )));
+        };
+        self.ast = compile_script(&configs.get_string_or_default(CONFIG_SCRIPT), false)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AsAgent for RhaiSplitAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        let mut agent = Self {
+            data: AgentData::new(askit, id, spec),
+            opaque_flow: None,
+            ast: None,
+            max_fanout: 0,
+            truncate: true,
+        };
+        agent.update_configs()?;
+        Ok(agent)
+    }
+
+    fn configs_changed(&mut self) -> Result<(), AgentError> {
+        self.update_configs()
+    }
+
+    async fn start(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = Some(hold_opaque_flow(self.flow_id()));
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = None;
+        Ok(())
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let _permit = eval_permit().await;
+        let _scope = opaque_scope(self.flow_id());
+        let value = if let Some(ast) = &self.ast {
+            let mut scope = Scope::new();
+            scope.push("value", from_value_to_dynamic(value)?);
+            let result = eval_ast(self.id(), &ctx, ast, &mut scope)?;
+            from_dynamic_to_value(&result)?
+        } else {
+            value
+        };
+
+        let Some(arr) = value.as_array() else {
+            return Err(AgentError::InvalidValue(
+                "Rhai Split expects an array".to_string(),
+            ));
+        };
+
+        let mut items = arr.as_slice();
+        if self.max_fanout > 0 && items.len() > self.max_fanout {
+            if !self.truncate {
+                return Err(AgentError::InvalidValue(format!(
+                    "array of {} elements exceeds max_fanout {}",
+                    items.len(),
+                    self.max_fanout
+                )));
+            }
+            log::warn!(
+                "{}: truncating array of {} elements to max_fanout {}",
+                self.id(),
+                items.len(),
+                self.max_fanout
+            );
+            items = &items[..self.max_fanout];
+        }
+
+        for item in items {
+            self.try_output(ctx.clone(), PORT_VALUE, item.clone())?;
+        }
+        Ok(())
+    }
+}
+
+// Rhai Batch
+#[askit_agent(
+    title = "Rhai Batch",
+    category = CATEGORY,
+    inputs = [PORT_VALUE],
+    outputs = [PORT_VALUE],
+    integer_config(
+        name = CONFIG_BATCH_SIZE,
+        default = 10,
+        title = "Batch Size",
+        description = "Number of values collected before the batch is emitted"
+    ),
+    text_config(
+        name = CONFIG_SCRIPT,
+        title = "Script",
+        description = "Applied to each batch as value; the batch is emitted as is when empty"
+    )
+)]
+struct RhaiBatchAgent {
+    data: AgentData,
+    opaque_flow: Option<OpaqueFlow>,
+    ast: Option<Arc<AST>>,
+    batch_size: usize,
+    pending: Vec<AgentValue>,
+    pending_ctx: Option<AgentContext>,
+}
+
+impl RhaiBatchAgent {
+    fn update_configs(&mut self) -> Result<(), AgentError> {
+        let Some(configs) = self.data.spec.configs.as_ref() else {
+            return Ok(());
+        };
+        self.batch_size = configs.get_integer_or(CONFIG_BATCH_SIZE, 10).max(1) as usize;
+        self.ast = compile_script(&configs.get_string_or_default(CONFIG_SCRIPT), false)?;
+        Ok(())
+    }
+
+    /// Emit the pending values, even if the batch is not full.
+    fn flush(&mut self) -> Result<(), AgentError> {
+        let Some(ctx) = self.pending_ctx.take() else {
+            return Ok(());
+        };
+        let batch = std::mem::take(&mut self.pending);
+        let value = if let Some(ast) = &self.ast {
+            let mut scope = Scope::new();
+            scope.push("value", from_values_to_dynamic(batch)?);
+            let result = eval_ast(self.id(), &ctx, ast, &mut scope)?;
+            from_dynamic_to_value(&result)?
+        } else {
+            AgentValue::array(batch)
+        };
+        self.try_output(ctx, PORT_VALUE, value)
+    }
+}
+
+#[async_trait]
+impl AsAgent for RhaiBatchAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        let mut agent = Self {
+            data: AgentData::new(askit, id, spec),
+            opaque_flow: None,
+            ast: None,
+            batch_size: 10,
+            pending: Vec::new(),
+            pending_ctx: None,
+        };
+        agent.update_configs()?;
+        Ok(agent)
+    }
+
+    fn configs_changed(&mut self) -> Result<(), AgentError> {
+        self.update_configs()
+    }
+
+    async fn start(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = Some(hold_opaque_flow(self.flow_id()));
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        // Don't lose a partial batch when the flow stops
+        let _permit = eval_permit().await;
+        let flushed = {
+            let _scope = opaque_scope(self.flow_id());
+            self.flush()
+        };
+        self.opaque_flow = None;
+        flushed
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        self.pending.push(value);
+        // The batch is emitted with the context of its last value
+        self.pending_ctx = Some(ctx);
+        if self.pending.len() >= self.batch_size {
+            let _permit = eval_permit().await;
+            let _scope = opaque_scope(self.flow_id());
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
 
-// Rhai Script
+// Rhai Render
 #[askit_agent(
-    title = "Rhai Script",
+    title = "Rhai Render",
     category = CATEGORY,
     inputs = [PORT_VALUE],
     outputs = [PORT_VALUE],
     text_config(
         name = CONFIG_SCRIPT,
-        title = "Script"
+        title = "Script",
+        description = "Produces the string written by a downstream sink, such as a file or socket; blobs that aren't UTF-8 go out as blob values"
     )
 )]
-struct RhaiScriptAgent {
+struct RhaiRenderAgent {
     data: AgentData,
-    ast: Option<AST>,
+    opaque_flow: Option<OpaqueFlow>,
+    ast: Option<Arc<AST>>,
 }
 
-impl RhaiScriptAgent {
-    fn set_script(&mut self, script: String) -> Result<(), AgentError> {
-        let engine = get_engine();
-        if script.is_empty() {
-            self.ast = None;
+impl RhaiRenderAgent {
+    fn update_configs(&mut self) -> Result<(), AgentError> {
+        let Some(configs) = self.data.spec.configs.as_ref() else {
             return Ok(());
-        }
-        let ast = engine
-            .compile(&script)
-            .map_err(|e| AgentError::IoError(format!("Rhai Compile Error: {}", e)))?;
-        self.ast = Some(ast);
+        };
+        self.ast = compile_script(&configs.get_string_or_default(CONFIG_SCRIPT), false)?;
         Ok(())
     }
 }
 
+/// The output of a render script: a string as is, a blob holding UTF-8 text as that text,
+/// or any other blob as a blob value.
+fn render_result(result: Dynamic) -> Result<AgentValue, AgentError> {
+    if result.is_string() {
+        return from_dynamic_to_value(&result);
+    }
+    if result.is_blob() {
+        // Bytes that aren't text keep the blob representation of the crate
+        return Ok(match String::from_utf8(result.cast::<rhai::Blob>()) {
+            Ok(s) => AgentValue::string(s),
+            Err(e) => blob_to_value(e.as_bytes()),
+        });
+    }
+    Err(AgentError::InvalidValue(format!(
+        "Rhai Render expects the script to produce a string or blob, not {}",
+        result.type_name()
+    )))
+}
+
 #[async_trait]
-impl AsAgent for RhaiScriptAgent {
+impl AsAgent for RhaiRenderAgent {
     fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
-        let script = spec
-            .configs
-            .as_ref()
-            .and_then(|c| c.get_string(CONFIG_SCRIPT).ok())
-            .unwrap_or_default();
         let mut agent = Self {
             data: AgentData::new(askit, id, spec),
+            opaque_flow: None,
             ast: None,
         };
-        if !script.is_empty() {
-            agent.set_script(script)?;
-        }
+        agent.update_configs()?;
         Ok(agent)
     }
 
     fn configs_changed(&mut self) -> Result<(), AgentError> {
-        let engine = get_engine();
-        let script = self.configs()?.get_string(CONFIG_SCRIPT)?;
-        if script.is_empty() {
-            self.ast = None;
-            return Ok(());
-        }
-        let ast = engine
-            .compile(&script)
-            .map_err(|e| AgentError::IoError(format!("Rhai Compile Error: {}", e)))?;
-        self.ast = Some(ast);
+        self.update_configs()
+    }
+
+    async fn start(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = Some(hold_opaque_flow(self.flow_id()));
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = None;
         Ok(())
     }
 
@@ -91,112 +2536,538 @@ impl AsAgent for RhaiScriptAgent {
         let Some(ast) = &self.ast else {
             return Ok(());
         };
-        let engine = get_engine();
-
+        let _permit = eval_permit().await;
+        let _scope = opaque_scope(self.flow_id());
         let mut scope = Scope::new();
-        // scope.push("ctx", Dynamic::from(ctx.clone()));
         scope.push("value", from_value_to_dynamic(value)?);
+        let result = eval_ast(self.id(), &ctx, ast, &mut scope)?;
+        let out_value = render_result(result)?;
+        self.try_output(ctx, PORT_VALUE, out_value)
+    }
+}
+
+// Rhai Template
+#[askit_agent(
+    title = "Rhai Template",
+    category = CATEGORY,
+    inputs = [PORT_VALUE],
+    outputs = [PORT_VALUE],
+    text_config(
+        name = CONFIG_TEMPLATE,
+        title = "Template",
+        description = "Text with ${expr} placeholders evaluated against value; \\${ for a literal ${"
+    )
+)]
+struct RhaiTemplateAgent {
+    data: AgentData,
+    opaque_flow: Option<OpaqueFlow>,
+    template: Option<Template>,
+}
+
+impl RhaiTemplateAgent {
+    fn update_configs(&mut self) -> Result<(), AgentError> {
+        let Some(configs) = self.data.spec.configs.as_ref() else {
+            return Ok(());
+        };
+        let template = configs.get_string_or_default(CONFIG_TEMPLATE);
+        self.template = if template.is_empty() {
+            None
+        } else {
+            Some(Template::parse(&template)?)
+        };
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AsAgent for RhaiTemplateAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        let mut agent = Self {
+            data: AgentData::new(askit, id, spec),
+            opaque_flow: None,
+            template: None,
+        };
+        agent.update_configs()?;
+        Ok(agent)
+    }
+
+    fn configs_changed(&mut self) -> Result<(), AgentError> {
+        self.update_configs()
+    }
 
-        let result = engine
-            .eval_ast_with_scope::<Dynamic>(&mut scope, ast)
-            .map_err(|e| AgentError::IoError(format!("Rhai Runtime Error: {}", e)))?;
+    async fn start(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = Some(hold_opaque_flow(self.flow_id()));
+        Ok(())
+    }
 
-        let out_value: AgentValue = from_dynamic_to_value(&result)?;
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = None;
+        Ok(())
+    }
 
-        self.try_output(ctx, PORT_VALUE, out_value)
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let _permit = eval_permit().await;
+        let _scope = opaque_scope(self.flow_id());
+        let Some(template) = &self.template else {
+            return Ok(());
+        };
+        let out = template.render(self.id(), &ctx, from_value_to_dynamic(value)?)?;
+        self.try_output(ctx, PORT_VALUE, AgentValue::string(out))
     }
 }
 
-fn from_value_to_dynamic(value: AgentValue) -> Result<Dynamic, AgentError> {
-    match value {
-        AgentValue::Unit => Ok(().into()),
-        AgentValue::Boolean(b) => Ok(Dynamic::from(b)),
-        AgentValue::Integer(i) => Ok(Dynamic::from(i)),
-        AgentValue::Number(f) => Ok(Dynamic::from(f)),
-        AgentValue::String(s) => Ok(Dynamic::from((*s).clone())),
-        AgentValue::Array(arr) => {
-            let mut dyn_arr: Vec<Dynamic> = Vec::with_capacity(arr.len());
-            for v in arr.iter() {
-                let d = from_value_to_dynamic(v.clone())?;
-                dyn_arr.push(d);
+// Rhai Sample
+#[askit_agent(
+    title = "Rhai Sample",
+    category = CATEGORY,
+    inputs = [PORT_VALUE],
+    outputs = [PORT_VALUE],
+    integer_config(
+        name = CONFIG_SAMPLE_RATE,
+        default = 1,
+        title = "Sample Rate",
+        description = "Emit 1 of every N values with the same key"
+    ),
+    text_config(
+        name = CONFIG_SCRIPT,
+        title = "Script",
+        description = "Returns true or false to decide directly, or a key to sample each key separately"
+    )
+)]
+struct RhaiSampleAgent {
+    data: AgentData,
+    opaque_flow: Option<OpaqueFlow>,
+    ast: Option<Arc<AST>>,
+    sample_rate: u64,
+    counters: HashMap<String, u64>,
+}
+
+impl RhaiSampleAgent {
+    fn update_configs(&mut self) -> Result<(), AgentError> {
+        let Some(configs) = self.data.spec.configs.as_ref() else {
+            return Ok(());
+        };
+        self.sample_rate = configs.get_integer_or(CONFIG_SAMPLE_RATE, 1).max(1) as u64;
+        self.ast = compile_script(&configs.get_string_or_default(CONFIG_SCRIPT), false)?;
+        self.counters.clear();
+        Ok(())
+    }
+
+    /// Count a value with `key`, passing the first of every `sample_rate`.
+    fn sample(&mut self, key: String) -> bool {
+        let count = self.counters.entry(key).or_default();
+        let pass = count.is_multiple_of(self.sample_rate);
+        *count += 1;
+        pass
+    }
+}
+
+#[async_trait]
+impl AsAgent for RhaiSampleAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        let mut agent = Self {
+            data: AgentData::new(askit, id, spec),
+            opaque_flow: None,
+            ast: None,
+            sample_rate: 1,
+            counters: HashMap::new(),
+        };
+        agent.update_configs()?;
+        Ok(agent)
+    }
+
+    fn configs_changed(&mut self) -> Result<(), AgentError> {
+        self.update_configs()
+    }
+
+    async fn start(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = Some(hold_opaque_flow(self.flow_id()));
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = None;
+        Ok(())
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let _permit = eval_permit().await;
+        let _scope = opaque_scope(self.flow_id());
+        let key = if let Some(ast) = &self.ast {
+            let mut scope = Scope::new();
+            scope.push("value", from_value_to_dynamic(value.clone())?);
+            match from_dynamic_to_value(&eval_ast(self.id(), &ctx, ast, &mut scope)?)? {
+                AgentValue::Boolean(pass) => {
+                    if pass {
+                        self.try_output(ctx, PORT_VALUE, value)?;
+                    }
+                    return Ok(());
+                }
+                AgentValue::String(key) => key.to_string(),
+                key => key.to_json().to_string(),
             }
-            Ok(Dynamic::from_array(dyn_arr))
+        } else {
+            String::new()
+        };
+
+        if self.sample(key) {
+            self.try_output(ctx, PORT_VALUE, value)?;
         }
-        AgentValue::Object(map) => {
-            let mut dyn_map = rhai::Map::new();
-            for (k, v) in map.iter() {
-                let d = from_value_to_dynamic(v.clone())?;
-                dyn_map.insert(k.into(), d);
-            }
-            Ok(Dynamic::from_map(dyn_map))
+        Ok(())
+    }
+}
+
+// Rhai Dynamic Script
+#[askit_agent(
+    title = "Rhai Dynamic Script",
+    category = CATEGORY,
+    inputs = [PORT_VALUE],
+    outputs = [PORT_VALUE],
+    boolean_config(
+        name = CONFIG_ALLOW_DYNAMIC_SCRIPTS,
+        title = "Allow Dynamic Scripts",
+        description = "Run scripts taken from the input, without access to files, secrets or other agents. Refused when built with the unchecked feature"
+    ),
+    string_config(
+        name = CONFIG_SCRIPT_KEY,
+        default = "script",
+        title = "Script Key",
+        description = "Input field holding the script"
+    ),
+    string_config(
+        name = CONFIG_DATA_KEY,
+        default = "data",
+        title = "Data Key",
+        description = "Input field passed to the script as value"
+    ),
+    string_config(
+        name = CONFIG_DISABLED_SYMBOLS,
+        title = "Disabled Symbols",
+        description = "Comma separated keywords or operators the scripts may not use, e.g. print, while"
+    ),
+    integer_config(
+        name = CONFIG_MAX_OPERATIONS,
+        default = 1_000_000,
+        title = "Max Operations",
+        description = "Operations a script may run before it is stopped (0 for no limit)"
+    ),
+    integer_config(
+        name = CONFIG_MAX_STRING_SIZE,
+        default = 1 << 20,
+        title = "Max String Size",
+        description = "Longest string a script may build, in bytes (0 for no limit)"
+    ),
+    integer_config(
+        name = CONFIG_MAX_ARRAY_SIZE,
+        default = 1 << 16,
+        title = "Max Array Size",
+        description = "Most elements of an array or blob a script may build (0 for no limit)"
+    ),
+    integer_config(
+        name = CONFIG_MAX_MAP_SIZE,
+        default = 1 << 16,
+        title = "Max Map Size",
+        description = "Most entries of a map a script may build (0 for no limit)"
+    ),
+    integer_config(
+        name = CONFIG_MAX_CALL_LEVELS,
+        default = 32,
+        title = "Max Call Levels",
+        description = "Deepest nesting of function calls (0 for the Rhai default)"
+    ),
+    integer_config(
+        name = CONFIG_MAX_EXPR_DEPTH,
+        default = 64,
+        title = "Max Expression Depth",
+        description = "Deepest nesting of expressions a script may have (0 for the Rhai default)"
+    )
+)]
+struct RhaiDynamicScriptAgent {
+    data: AgentData,
+    opaque_flow: Option<OpaqueFlow>,
+    allow: bool,
+    script_key: String,
+    data_key: String,
+    /// Runs the scripts with the configured symbols and limits.
+    engine: Arc<rhai::Engine>,
+    /// Scripts from the input, kept apart from the configured ones so a
+    /// stream of distinct scripts can't evict them from the shared cache.
+    asts: AstCache,
+}
+
+impl RhaiDynamicScriptAgent {
+    fn update_configs(&mut self) {
+        let Some(configs) = self.data.spec.configs.as_ref() else {
+            return;
+        };
+        self.allow = configs.get_bool_or_default(CONFIG_ALLOW_DYNAMIC_SCRIPTS);
+        self.script_key = configs.get_string_or(CONFIG_SCRIPT_KEY, "script");
+        self.data_key = configs.get_string_or(CONFIG_DATA_KEY, "data");
+        let disabled_symbols =
+            split_symbols(&configs.get_string_or_default(CONFIG_DISABLED_SYMBOLS));
+        self.engine = Arc::new(sandboxed_engine(configs, &disabled_symbols));
+        // Scripts compiled by the previous engine may exceed the new limits
+        self.asts.clear();
+    }
+}
+
+#[async_trait]
+impl AsAgent for RhaiDynamicScriptAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        let mut agent = Self {
+            data: AgentData::new(askit, id, spec),
+            opaque_flow: None,
+            allow: false,
+            script_key: String::new(),
+            data_key: String::new(),
+            engine: Arc::new(sandboxed_engine(&AgentConfigs::default(), &[])),
+            asts: AstCache::new(DYNAMIC_SCRIPT_CACHE_CAPACITY),
+        };
+        agent.update_configs();
+        Ok(agent)
+    }
+
+    fn configs_changed(&mut self) -> Result<(), AgentError> {
+        self.update_configs();
+        Ok(())
+    }
+
+    async fn start(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = Some(hold_opaque_flow(self.flow_id()));
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = None;
+        Ok(())
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        if !self.allow {
+            return Err(AgentError::InvalidConfig(format!(
+                "Dynamic scripts are disabled, set {} to run them",
+                CONFIG_ALLOW_DYNAMIC_SCRIPTS
+            )));
+        }
+        if cfg!(feature = "unchecked") {
+            return Err(AgentError::InvalidConfig(format!(
+                "Dynamic scripts can't be limited when built with the unchecked feature, so {} has no effect",
+                CONFIG_ALLOW_DYNAMIC_SCRIPTS
+            )));
         }
+        let Some(script) = value.get(&self.script_key).and_then(|s| s.as_str()) else {
+            return Err(AgentError::InvalidValue(format!(
+                "Rhai Dynamic Script expects a string {} field",
+                self.script_key
+            )));
+        };
+        if script.is_empty() {
+            return Ok(());
+        }
+        // A repeated script is only compiled once
+        let engine = &self.engine;
+        let ast = self.asts.get_or_compile(0, script, false, || {
+            let ast = engine.compile(script).map_err(compile_error)?;
+            check_sandboxed(&ast)?;
+            Ok(ast)
+        })?;
+        let data = value.get(&self.data_key).cloned().unwrap_or_default();
+
+        let _permit = eval_permit().await;
+
+        let _scope = opaque_scope(self.flow_id());
+        let mut scope = Scope::new();
+        scope.push("value", from_value_to_dynamic(data)?);
+        let result = eval_ast_on(&self.engine, self.id(), &ctx, &ast, &mut scope)?;
+        self.try_output(ctx, PORT_VALUE, from_dynamic_to_value(&result)?)
+    }
+}
+
+// Rhai Const
+#[askit_agent(
+    title = "Rhai Const",
+    category = CATEGORY,
+    inputs = [PORT_VALUE],
+    outputs = [PORT_VALUE],
+    text_config(
+        name = CONFIG_SCRIPT,
+        title = "Script",
+        description = "Evaluated whenever any input arrives, with config and state but without value, and its result emitted"
+    )
+)]
+struct RhaiConstAgent {
+    data: AgentData,
+    opaque_flow: Option<OpaqueFlow>,
+    ast: Option<Arc<AST>>,
+
+    /// The configs of the agent, given to the script as `config`.
+    config: rhai::Map,
 
-        // Just store AgentValue directly
-        _ => Ok(Dynamic::from(value)),
+    /// The `state` map kept across messages.
+    state: rhai::Map,
+}
+
+impl RhaiConstAgent {
+    fn update_configs(&mut self) -> Result<(), AgentError> {
+        let Some(configs) = self.data.spec.configs.as_ref() else {
+            return Ok(());
+        };
+        self.ast = compile_script(&configs.get_string_or_default(CONFIG_SCRIPT), false)?;
+        self.config = configs
+            .into_iter()
+            .map(|(key, value)| Ok((key.into(), from_value_to_dynamic(value.clone())?)))
+            .collect::<Result<_, AgentError>>()?;
+        Ok(())
     }
 }
 
-fn from_dynamic_to_value(value: &Dynamic) -> Result<AgentValue, AgentError> {
-    if value.is_unit() {
-        return Ok(AgentValue::unit());
+#[async_trait]
+impl AsAgent for RhaiConstAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        let mut agent = Self {
+            data: AgentData::new(askit, id, spec),
+            opaque_flow: None,
+            ast: None,
+            config: rhai::Map::new(),
+            state: rhai::Map::new(),
+        };
+        agent.update_configs()?;
+        Ok(agent)
+    }
+
+    fn configs_changed(&mut self) -> Result<(), AgentError> {
+        self.update_configs()
+    }
+
+    async fn start(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = Some(hold_opaque_flow(self.flow_id()));
+        Ok(())
     }
-    if value.is_bool() {
-        let value = value
-            .as_bool()
-            .map_err(|e| AgentError::InvalidValue(format!("Failed as_bool: {}", e)))?;
-        return Ok(AgentValue::boolean(value));
+
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = None;
+        Ok(())
     }
-    if value.is_int() {
-        let value = value
-            .as_int()
-            .map_err(|e| AgentError::InvalidValue(format!("Failed as_int: {}", e)))?;
-        return Ok(AgentValue::integer(value));
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        _value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let Some(ast) = self.ast.clone() else {
+            return Ok(());
+        };
+        // Evaluated for each input, so scripts using random() or uuid()
+        // produce a fresh value every time
+        let _permit = eval_permit().await;
+        let _scope = opaque_scope(self.flow_id());
+        let mut scope = Scope::new();
+        scope.push_constant("config", self.config.clone());
+        scope.push("state", self.state.clone());
+        let result = eval_ast(self.id(), &ctx, &ast, &mut scope)?;
+        let Some(state) = scope.remove::<rhai::Map>("state") else {
+            return Err(AgentError::InvalidValue(
+                "state must be an object".to_string(),
+            ));
+        };
+        self.state = state;
+        self.try_output(ctx, PORT_VALUE, from_dynamic_to_value(&result)?)
     }
-    if value.is_float() {
-        let value = value
-            .as_float()
-            .map_err(|e| AgentError::InvalidValue(format!("Failed as_float: {}", e)))?;
-        return Ok(AgentValue::number(value));
+}
+
+// Rhai Patch
+#[askit_agent(
+    title = "Rhai Patch",
+    category = CATEGORY,
+    inputs = [PORT_VALUE],
+    outputs = [PORT_VALUE],
+    text_config(
+        name = CONFIG_SCRIPT,
+        title = "Script",
+        description = "Returns JSON Patch operations, e.g. [#{op: \"add\", path: \"/x\", value: 1}], applied to doc"
+    )
+)]
+struct RhaiPatchAgent {
+    data: AgentData,
+    opaque_flow: Option<OpaqueFlow>,
+    ast: Option<Arc<AST>>,
+
+    /// The document kept across messages, starting as an empty object.
+    doc: AgentValue,
+}
+
+impl RhaiPatchAgent {
+    fn update_configs(&mut self) -> Result<(), AgentError> {
+        let Some(configs) = self.data.spec.configs.as_ref() else {
+            return Ok(());
+        };
+        self.ast = compile_script(&configs.get_string_or_default(CONFIG_SCRIPT), false)?;
+        Ok(())
     }
-    if value.is_string() {
-        let value = value
-            .clone()
-            .into_string()
-            .map_err(|e| AgentError::InvalidValue(format!("Failed into_string: {}", e)))?;
-        return Ok(AgentValue::string(value));
+}
+
+#[async_trait]
+impl AsAgent for RhaiPatchAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        let mut agent = Self {
+            data: AgentData::new(askit, id, spec),
+            opaque_flow: None,
+            ast: None,
+            doc: AgentValue::object_default(),
+        };
+        agent.update_configs()?;
+        Ok(agent)
     }
 
-    if value.is_array() {
-        let arr = value
-            .as_array_ref()
-            .map_err(|e| AgentError::InvalidValue(format!("Failed as_array_ref: {}", e)))?;
-        let mut value_array: Vec<AgentValue> = Vec::with_capacity(arr.len());
-        for v in arr.iter() {
-            let d = from_dynamic_to_value(v)?;
-            value_array.push(d);
-        }
-        return Ok(AgentValue::array(value_array));
+    fn configs_changed(&mut self) -> Result<(), AgentError> {
+        self.update_configs()
     }
 
-    if value.is_map() {
-        let map = value
-            .as_map_ref()
-            .map_err(|e| AgentError::InvalidValue(format!("Failed as_map_ref: {}", e)))?;
-        let mut value_map = AgentValueMap::new();
-        for (k, v) in map.iter() {
-            let av = from_dynamic_to_value(v)?;
-            value_map.insert(k.to_string(), av);
-        }
-        return Ok(AgentValue::object(value_map));
+    async fn start(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = Some(hold_opaque_flow(self.flow_id()));
+        Ok(())
     }
 
-    if value.is::<AgentValue>() {
-        let value = value.clone().cast::<AgentValue>();
-        return Ok(value);
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        self.opaque_flow = None;
+        Ok(())
     }
 
-    Err(AgentError::InvalidValue(format!(
-        "Unsupported Rhai data type: {}",
-        value.type_name()
-    )))
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let Some(ast) = &self.ast else {
+            return Ok(());
+        };
+        let _permit = eval_permit().await;
+        let _scope = opaque_scope(self.flow_id());
+        let mut scope = Scope::new();
+        scope.push("value", from_value_to_dynamic(value)?);
+        scope.push_constant("doc", from_value_to_dynamic(self.doc.clone())?);
+        let ops = from_dynamic_to_value(&eval_ast(self.id(), &ctx, ast, &mut scope)?)?;
+        apply_patch(&mut self.doc, &ops)?;
+        self.try_output(ctx, PORT_VALUE, self.doc.clone())
+    }
 }
+
+#[cfg(test)]
+mod tests;
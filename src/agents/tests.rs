@@ -0,0 +1,2594 @@
+use std::sync::atomic::AtomicUsize;
+
+use agent_stream_kit::AgentValue;
+use serde_json::{Value, json};
+
+use super::*;
+use crate::engine::set_max_concurrent_evals;
+#[cfg(not(feature = "unchecked"))]
+use crate::error::{ScriptErrorKind, script_error_kind};
+use crate::testing::{
+    Probe, TestFlow, block_on, capture_logs, capture_traces, lock_globals, logs, spans,
+};
+
+/// Target of the messages logged by the agents.
+const LOG_TARGET: &str = "askit_rhai_agents::agents";
+
+fn int(n: i64) -> AgentValue {
+    AgentValue::integer(n)
+}
+
+fn value(json: Value) -> AgentValue {
+    AgentValue::from_json(json).unwrap()
+}
+
+/// Add a Rhai Script agent and return a probe on its value port.
+async fn script_agent(flow: &TestFlow, id: &str, configs: Value) -> Probe {
+    flow.add(id, RhaiScriptAgent::DEF_NAME, configs).await;
+    flow.probe(id, PORT_VALUE).await
+}
+
+#[test]
+fn auto_iterate_runs_per_element() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "iterate",
+            json!({"script": "value * 2", "auto_iterate": true}),
+        )
+        .await;
+
+        flow.process("iterate", "value", value(json!([1, 2, 3])))
+            .await
+            .unwrap();
+        for expected in [2, 4, 6] {
+            assert_eq!(probe.recv().await, int(expected));
+        }
+        probe.assert_empty().await;
+
+        // Other inputs run once
+        flow.process("iterate", "value", int(5)).await.unwrap();
+        assert_eq!(probe.recv().await, int(10));
+    });
+}
+
+#[test]
+fn arrays_are_passed_whole_by_default() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(&flow, "whole", json!({"script": "value.len()"})).await;
+
+        flow.process("whole", "value", value(json!([1, 2, 3])))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, int(3));
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn closures_pass_to_later_stages_of_the_flow() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "make-fn",
+            RhaiScriptAgent::DEF_NAME,
+            json!({"script": "|x| x * 10"}),
+        )
+        .await;
+        let probe = script_agent(&flow, "call-fn", json!({"script": "value.call(4)"})).await;
+        flow.connect("make-fn", PORT_VALUE, "call-fn", PORT_VALUE);
+
+        flow.process("make-fn", "value", AgentValue::unit())
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, int(40));
+    });
+}
+
+#[test]
+fn output_round_rounds_results() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "round",
+            json!({"script": "#{ a: value / 3.0, b: [2.0 / 3.0] }", "output_round": 3}),
+        )
+        .await;
+
+        flow.process("round", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, value(json!({"a": 0.333, "b": [0.667]})));
+    });
+}
+
+#[test]
+fn parse_agent_parses_and_post_processes() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "parse-csv",
+            RhaiParseAgent::DEF_NAME,
+            json!({"format": "csv-line", "script": "#{ name: value[0], city: value[1] }"}),
+        )
+        .await;
+        let probe = flow.probe("parse-csv", PORT_VALUE).await;
+
+        let line = AgentValue::string("\"Doe, Jane\",Paris");
+        flow.process("parse-csv", "value", line).await.unwrap();
+        assert_eq!(
+            probe.recv().await,
+            value(json!({"name": "Doe, Jane", "city": "Paris"}))
+        );
+
+        let err = flow.process("parse-csv", "value", int(1)).await;
+        assert!(err.is_err());
+    });
+}
+
+#[test]
+fn parse_agent_serializes() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "serialize-json",
+            RhaiParseAgent::DEF_NAME,
+            json!({"mode": "serialize", "script": "value + 1"}),
+        )
+        .await;
+        let probe = flow.probe("serialize-json", PORT_VALUE).await;
+
+        flow.process("serialize-json", "value", int(1))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, AgentValue::string("2"));
+    });
+}
+
+#[test]
+fn split_truncates_to_max_fanout() {
+    block_on(async {
+        capture_logs();
+        let flow = TestFlow::new().await;
+        flow.add(
+            "split-truncate",
+            RhaiSplitAgent::DEF_NAME,
+            json!({"script": "value.map(|x| x * 2)", "max_fanout": 2}),
+        )
+        .await;
+        let probe = flow.probe("split-truncate", PORT_VALUE).await;
+
+        flow.process("split-truncate", "value", value(json!([1, 2, 3])))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, int(2));
+        assert_eq!(probe.recv().await, int(4));
+        probe.assert_empty().await;
+        let warnings = logs(LOG_TARGET, "split-truncate");
+        assert!(warnings[0].contains("truncating array of 3 elements"));
+    });
+}
+
+#[test]
+fn split_can_reject_large_arrays() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "split-error",
+            RhaiSplitAgent::DEF_NAME,
+            json!({"max_fanout": 2, "fanout_policy": "error"}),
+        )
+        .await;
+        let probe = flow.probe("split-error", PORT_VALUE).await;
+
+        let err = flow
+            .process("split-error", "value", value(json!([1, 2, 3])))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("exceeds max_fanout 2"), "{}", err);
+        probe.assert_empty().await;
+
+        flow.process("split-error", "value", value(json!([1, 2])))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, int(1));
+        assert_eq!(probe.recv().await, int(2));
+    });
+}
+
+#[test]
+fn scripts_call_shared_library_functions() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let lib = "fn double(x) { x * 2 }\nfn label(x) { `n=${x}` }\nthrow \"not run\";";
+        let probe = script_agent(
+            &flow,
+            "shared-lib",
+            json!({"script": "fn label(x) { `#${x}` }\nlabel(double(value))", "shared_lib": lib}),
+        )
+        .await;
+
+        // The script's own label takes precedence
+        flow.process("shared-lib", "value", int(21)).await.unwrap();
+        assert_eq!(probe.recv().await, AgentValue::string("#42"));
+    });
+}
+
+#[test]
+fn shared_library_is_read_from_a_file() {
+    let path = std::env::temp_dir().join(format!("askit-shared-lib-{}.rhai", std::process::id()));
+    std::fs::write(&path, "fn triple(x) { x * 3 }").unwrap();
+    let lib = compile_shared_lib(path.to_str().unwrap(), &[])
+        .unwrap()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let ast = compile_script("triple(value)", false).unwrap().unwrap();
+    let ast = merge_shared_lib(ast, &lib);
+    let mut scope = Scope::new();
+    scope.push("value", 2 as INT);
+    let out = eval_ast("lib-file", &AgentContext::new(), &ast, &mut scope).unwrap();
+    assert_eq!(out.as_int().unwrap(), 6);
+
+    assert!(compile_shared_lib("/nonexistent/lib.rhai", &[]).is_err());
+}
+
+#[test]
+fn seed_from_makes_random_output_reproducible() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "seeded",
+            json!({"script": "[random(), uuid()]", "seed_from": "id"}),
+        )
+        .await;
+
+        let mut outputs = Vec::new();
+        for id in [1, 1, 2] {
+            flow.process(
+                "seeded",
+                "value",
+                value(json!({"id": id, "n": outputs.len()})),
+            )
+            .await
+            .unwrap();
+            outputs.push(probe.recv().await);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert_ne!(outputs[0], outputs[2]);
+    });
+}
+
+#[test]
+fn random_output_differs_without_a_seed() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(&flow, "unseeded", json!({"script": "uuid()"})).await;
+
+        flow.process("unseeded", "value", int(1)).await.unwrap();
+        flow.process("unseeded", "value", int(1)).await.unwrap();
+        assert_ne!(probe.recv().await, probe.recv().await);
+    });
+}
+
+#[test]
+fn port_key_routes_results() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"if value == 0 { #{ x: 0 } } else { #{ __port__: value, x: 1 } }"#;
+        let probe = script_agent(&flow, "port-key", json!({ "script": script })).await;
+        let derived = flow.probe("port-key", PORT_DERIVED).await;
+
+        flow.process("port-key", "value", AgentValue::string("derived"))
+            .await
+            .unwrap();
+        assert_eq!(derived.recv().await, value(json!({"x": 1})));
+        probe.assert_empty().await;
+
+        flow.process("port-key", "value", int(0)).await.unwrap();
+        assert_eq!(probe.recv().await, value(json!({"x": 0})));
+        derived.assert_empty().await;
+
+        let err = flow
+            .process("port-key", "value", AgentValue::string("nowhere"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::PinNotFound(port) if port == "nowhere"));
+        assert!(flow.process("port-key", "value", int(1)).await.is_err());
+    });
+}
+
+#[test]
+fn pre_transform_runs_before_the_script() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "pre-transform",
+            json!({"pre_transform": "value.trim(); value", "script": "value.len()"}),
+        )
+        .await;
+
+        flow.process("pre-transform", "value", AgentValue::string("  abc \n"))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, int(3));
+    });
+}
+
+#[test]
+fn failed_reconfigure_keeps_the_previous_script() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(&flow, "status", json!({"script": "value + 1"})).await;
+        let status = flow
+            .with_agent("status", |a: &mut RhaiScriptAgent| a.status())
+            .await;
+        assert_eq!(
+            status,
+            ScriptStatus {
+                has_ast: true,
+                source_len: 9,
+                last_error: None,
+                degraded: false,
+            }
+        );
+
+        let err = flow.configure("status", json!({"script": "value +"})).await;
+        assert!(err.is_err());
+        let status = flow
+            .with_agent("status", |a: &mut RhaiScriptAgent| a.status())
+            .await;
+        assert!(status.has_ast);
+        assert_eq!(status.source_len, 9);
+        assert!(status.last_error.unwrap().contains("Compile"));
+        flow.process("status", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, int(2));
+
+        // Any other rejected script or config is recorded the same way
+        let valid = json!({
+            "script": "value + 1",
+            "heartbeat_script": "",
+            "fallback_script": "",
+            "route_table": {},
+            "state_eviction": "",
+        });
+        for (key, bad, error) in [
+            ("heartbeat_script", json!("1 +"), "Compile"),
+            ("fallback_script", json!("1 +"), "Compile"),
+            ("route_table", json!({"a": 1}), "route_table"),
+            ("state_eviction", json!("random"), "random"),
+        ] {
+            let mut config = valid.clone();
+            config[key] = bad;
+            let err = flow.configure("status", config).await;
+            assert!(err.is_err(), "{}", key);
+            let status = flow
+                .with_agent("status", |a: &mut RhaiScriptAgent| a.status())
+                .await;
+            let last_error = status.last_error.unwrap();
+            assert!(last_error.contains(error), "{}: {}", key, last_error);
+            assert_eq!(status.source_len, 9);
+            flow.process("status", "value", int(1)).await.unwrap();
+            assert_eq!(probe.recv().await, int(2));
+        }
+
+        let mut config = valid;
+        config["script"] = json!("value * 3");
+        flow.configure("status", config).await.unwrap();
+        let status = flow
+            .with_agent("status", |a: &mut RhaiScriptAgent| a.status())
+            .await;
+        assert_eq!(status.last_error, None);
+    });
+}
+
+#[test]
+fn configured_constants_are_read_only() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let constants = json!({"limit": 10, "names": ["a", "b"]});
+        let probe = script_agent(
+            &flow,
+            "constants",
+            json!({"script": "if value > limit { names[1] } else { names[0] }", "constants": constants}),
+        )
+        .await;
+        flow.process("constants", "value", int(11)).await.unwrap();
+        assert_eq!(probe.recv().await, AgentValue::string("b"));
+
+        flow.configure(
+            "constants",
+            json!({"script": "limit = 5; value", "constants": constants}),
+        )
+        .await
+        .unwrap();
+        let err = flow
+            .process("constants", "value", int(1))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("limit"), "{}", err);
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn batch_flushes_a_partial_batch_on_stop() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "batch",
+            RhaiBatchAgent::DEF_NAME,
+            json!({"batch_size": 3, "script": "value.reduce(|sum, x| sum + x, 0)"}),
+        )
+        .await;
+        let probe = flow.probe("batch", PORT_VALUE).await;
+
+        for n in 1..=4 {
+            flow.process("batch", "value", int(n)).await.unwrap();
+        }
+        assert_eq!(probe.recv().await, int(6));
+        probe.assert_empty().await;
+
+        flow.stop("batch").await;
+        assert_eq!(probe.recv().await, int(4));
+    });
+}
+
+#[test]
+fn state_limits_apply_to_the_script_state() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = "state[value.to_string()] = value; state.len()";
+        let probe = script_agent(
+            &flow,
+            "state-lru",
+            json!({"script": script, "state_max_entries": 2, "state_eviction": "lru"}),
+        )
+        .await;
+        for n in 1..=3 {
+            flow.process("state-lru", "value", int(n)).await.unwrap();
+        }
+        // The script sees its own state before the limits apply
+        for expected in [1, 2, 3] {
+            assert_eq!(probe.recv().await, int(expected));
+        }
+
+        let probe = script_agent(
+            &flow,
+            "state-reject",
+            json!({"script": script, "state_max_entries": 1}),
+        )
+        .await;
+        flow.process("state-reject", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, int(1));
+        assert!(flow.process("state-reject", "value", int(2)).await.is_err());
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn render_emits_strings_and_blobs_as_is() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"switch value { 0 => "line\n", 1 => "hé".to_blob(), 2 => [0xff].to_blob(), _ => 42 }"#;
+        flow.add(
+            "render",
+            RhaiRenderAgent::DEF_NAME,
+            json!({ "script": script }),
+        )
+        .await;
+        let probe = flow.probe("render", PORT_VALUE).await;
+
+        flow.process("render", "value", int(0)).await.unwrap();
+        assert_eq!(probe.recv().await, AgentValue::string("line\n"));
+        flow.process("render", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, AgentValue::string("hé"));
+
+        flow.process("render", "value", int(2)).await.unwrap();
+        assert_eq!(probe.recv().await, value(json!({"__rhai_blob__": "/w=="})));
+        let err = flow.process("render", "value", int(3)).await.unwrap_err();
+        assert!(err.to_string().contains("string or blob"), "{}", err);
+    });
+}
+
+#[test]
+fn sample_passes_every_nth_value_per_key() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "sample",
+            RhaiSampleAgent::DEF_NAME,
+            json!({"script": "value.key", "sample_rate": 3}),
+        )
+        .await;
+        let probe = flow.probe("sample", PORT_VALUE).await;
+
+        for n in 0..7 {
+            flow.process("sample", "value", value(json!({"key": "a", "n": n})))
+                .await
+                .unwrap();
+            // Another key keeps its own count
+            flow.process("sample", "value", value(json!({"key": "b", "n": n})))
+                .await
+                .unwrap();
+        }
+        for n in [0, 3, 6] {
+            assert_eq!(probe.recv().await, value(json!({"key": "a", "n": n})));
+            assert_eq!(probe.recv().await, value(json!({"key": "b", "n": n})));
+        }
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn sample_script_can_pick_values_itself() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "sample-bool",
+            RhaiSampleAgent::DEF_NAME,
+            json!({"script": "value % 2 == 0", "sample_rate": 100}),
+        )
+        .await;
+        let probe = flow.probe("sample-bool", PORT_VALUE).await;
+
+        for n in 0..5 {
+            flow.process("sample-bool", "value", int(n)).await.unwrap();
+        }
+        for expected in [0, 2, 4] {
+            assert_eq!(probe.recv().await, int(expected));
+        }
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn disabled_symbols_fail_to_compile() {
+    let disabled = split_symbols(" while, print ,,");
+    assert_eq!(disabled, ["while", "print"]);
+    assert!(compile_restricted("let n = 0; while n < 3 { n += 1 }", false, &disabled).is_err());
+    assert!(compile_restricted("let n = 0; loop { break; }", false, &disabled).is_ok());
+    // Other agents aren't affected
+    assert!(compile_restricted("while false {}", false, &[]).is_ok());
+}
+
+#[test]
+fn disabled_symbols_reject_a_reconfigured_script() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "disabled",
+            json!({"script": "value + 1", "disabled_symbols": "+="}),
+        )
+        .await;
+
+        let err = flow
+            .configure("disabled", json!({"script": "let n = value; n += 1; n"}))
+            .await;
+        assert!(err.is_err());
+        flow.process("disabled", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, int(2));
+    });
+}
+
+#[test]
+fn disabled_symbols_apply_to_the_shared_library() {
+    let disabled = split_symbols("while");
+    let lib = "fn count(n) { let i = 0; while i < n { i += 1 } i }";
+    let err = compile_shared_lib(lib, &disabled).unwrap_err();
+    assert_eq!(script_error_kind(&err), Some(ScriptErrorKind::Compile));
+    assert!(compile_shared_lib(lib, &[]).is_ok());
+
+    block_on(async {
+        let flow = TestFlow::new().await;
+        script_agent(
+            &flow,
+            "disabled-lib",
+            json!({"script": "value", "disabled_symbols": "while"}),
+        )
+        .await;
+        let err = flow
+            .configure("disabled-lib", json!({"shared_lib": lib}))
+            .await
+            .unwrap_err();
+        assert_eq!(script_error_kind(&err), Some(ScriptErrorKind::Compile));
+    });
+}
+
+#[test]
+fn disabled_symbols_and_pure_apply_to_the_heartbeat() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        script_agent(
+            &flow,
+            "disabled-heartbeat",
+            json!({"script": "value", "disabled_symbols": "while", "pure": true}),
+        )
+        .await;
+
+        let err = flow
+            .configure(
+                "disabled-heartbeat",
+                json!({"heartbeat_script": "let i = 0; while i < 3 { i += 1 } i"}),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(script_error_kind(&err), Some(ScriptErrorKind::Compile));
+
+        let err = flow
+            .configure(
+                "disabled-heartbeat",
+                json!({"heartbeat_script": "value = 1; value"}),
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("pure script assigns to value"),
+            "{}",
+            err
+        );
+    });
+}
+
+#[test]
+fn tee_emits_the_result_and_the_input() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let value_probe =
+            script_agent(&flow, "tee", json!({"script": "value.n * 2", "tee": true})).await;
+        let derived_probe = flow.probe("tee", PORT_DERIVED).await;
+
+        let input = value(json!({"n": 21}));
+        flow.process("tee", "value", input.clone()).await.unwrap();
+        assert_eq!(derived_probe.recv().await, int(42));
+        assert_eq!(value_probe.recv().await, input);
+
+        // Without tee only the result is emitted, on value
+        flow.configure("tee", json!({"tee": false})).await.unwrap();
+        flow.process("tee", "value", input).await.unwrap();
+        assert_eq!(value_probe.recv().await, int(42));
+        derived_probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn dynamic_scripts_are_rejected_unless_allowed() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add("dynamic-off", RhaiDynamicScriptAgent::DEF_NAME, json!({}))
+            .await;
+        let probe = flow.probe("dynamic-off", PORT_VALUE).await;
+
+        let input = value(json!({"script": "value + 1", "data": 1}));
+        let err = flow
+            .process("dynamic-off", "value", input)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::InvalidConfig(_)), "{}", err);
+        probe.assert_empty().await;
+    });
+}
+
+#[cfg(not(feature = "unchecked"))]
+#[test]
+fn dynamic_scripts_run_when_allowed() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "dynamic-on",
+            RhaiDynamicScriptAgent::DEF_NAME,
+            json!({"allow_dynamic_scripts": true}),
+        )
+        .await;
+        let probe = flow.probe("dynamic-on", PORT_VALUE).await;
+
+        for n in [1, 2] {
+            flow.process(
+                "dynamic-on",
+                "value",
+                value(json!({"script": "let n = value; n += 1; n", "data": n})),
+            )
+            .await
+            .unwrap();
+            assert_eq!(probe.recv().await, int(n + 1));
+        }
+
+        // The cached script is dropped once its symbols are disabled
+        flow.configure("dynamic-on", json!({"disabled_symbols": "+="}))
+            .await
+            .unwrap();
+        let input = value(json!({"script": "let n = value; n += 1; n", "data": 1}));
+        assert!(flow.process("dynamic-on", "value", input).await.is_err());
+        probe.assert_empty().await;
+    });
+}
+
+#[cfg(not(feature = "unchecked"))]
+#[test]
+fn dynamic_scripts_run_within_limits() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let configs = json!({
+            "allow_dynamic_scripts": true,
+            "max_operations": 10_000,
+            "max_string_size": 16,
+        });
+        flow.add("dynamic-limits", RhaiDynamicScriptAgent::DEF_NAME, configs)
+            .await;
+        let probe = flow.probe("dynamic-limits", PORT_VALUE).await;
+
+        for script in ["loop {}", r#"let s = ""; loop { s += "more"; }"#] {
+            let input = value(json!({"script": script}));
+            let err = flow
+                .process("dynamic-limits", "value", input)
+                .await
+                .unwrap_err();
+            assert_eq!(
+                script_error_kind(&err),
+                Some(ScriptErrorKind::Limit),
+                "{}",
+                err
+            );
+        }
+        probe.assert_empty().await;
+
+        // Scripts within the limits still run
+        let input = value(json!({"script": "value * 2", "data": 2}));
+        flow.process("dynamic-limits", "value", input)
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, int(4));
+    });
+}
+
+#[cfg(not(feature = "unchecked"))]
+#[test]
+fn dynamic_scripts_cannot_reach_outside_the_agent() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "dynamic-sandbox",
+            RhaiDynamicScriptAgent::DEF_NAME,
+            json!({"allow_dynamic_scripts": true}),
+        )
+        .await;
+        let probe = flow.probe("dynamic-sandbox", PORT_VALUE).await;
+
+        for script in [
+            r#"read_file_blob("data.bin")"#,
+            r#"secret("token")"#,
+            r#"send_to("other", value)"#,
+            r#"publish("channel", value)"#,
+            "emit_after(10, value)",
+        ] {
+            let input = value(json!({"script": script, "data": 1}));
+            let err = flow
+                .process("dynamic-sandbox", "value", input)
+                .await
+                .unwrap_err();
+            assert_eq!(
+                script_error_kind(&err),
+                Some(ScriptErrorKind::Compile),
+                "{}",
+                err
+            );
+        }
+        probe.assert_empty().await;
+    });
+}
+
+#[cfg(feature = "unchecked")]
+#[test]
+fn dynamic_scripts_are_refused_when_unchecked() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "dynamic-unchecked",
+            RhaiDynamicScriptAgent::DEF_NAME,
+            json!({"allow_dynamic_scripts": true}),
+        )
+        .await;
+
+        let input = value(json!({"script": "value + 1", "data": 1}));
+        let err = flow
+            .process("dynamic-unchecked", "value", input)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::InvalidConfig(_)), "{}", err);
+    });
+}
+
+#[test]
+fn msg_index_counts_processed_messages() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "msg-index",
+            json!({"script": "msg_index", "auto_iterate": true}),
+        )
+        .await;
+
+        flow.process("msg-index", "value", int(0)).await.unwrap();
+        flow.process("msg-index", "value", int(0)).await.unwrap();
+        // An iterated array counts as one message
+        flow.process("msg-index", "value", value(json!([0, 0])))
+            .await
+            .unwrap();
+        flow.process("msg-index", "value", int(0)).await.unwrap();
+        for expected in [0, 1, 2, 2, 3] {
+            assert_eq!(probe.recv().await, int(expected));
+        }
+
+        // and it starts over when the agent is reconfigured
+        flow.configure("msg-index", json!({"script": "msg_index * 10"}))
+            .await
+            .unwrap();
+        flow.process("msg-index", "value", int(0)).await.unwrap();
+        flow.process("msg-index", "value", int(0)).await.unwrap();
+        assert_eq!(probe.recv().await, int(0));
+        assert_eq!(probe.recv().await, int(10));
+    });
+}
+
+#[test]
+fn const_emits_the_same_value_for_any_input() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "const",
+            RhaiConstAgent::DEF_NAME,
+            json!({"script": "#{ level: \"high\", limits: [1, 2] }"}),
+        )
+        .await;
+        let probe = flow.probe("const", PORT_VALUE).await;
+
+        let expected = value(json!({"level": "high", "limits": [1, 2]}));
+        for input in [int(1), value(json!({"x": "y"})), AgentValue::unit()] {
+            flow.process("const", "value", input).await.unwrap();
+            assert_eq!(probe.recv().await, expected);
+        }
+    });
+}
+
+#[test]
+fn const_scripts_dont_see_the_input() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "const-value",
+            RhaiConstAgent::DEF_NAME,
+            json!({"script": "value"}),
+        )
+        .await;
+        let probe = flow.probe("const-value", PORT_VALUE).await;
+
+        assert!(flow.process("const-value", "value", int(1)).await.is_err());
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn const_scripts_see_config_and_state() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"state.count = (state.count ?? 0) + 1; `${config.label}:${state.count}`"#;
+        flow.add(
+            "const-state",
+            RhaiConstAgent::DEF_NAME,
+            json!({"script": script, "label": "tick"}),
+        )
+        .await;
+        let probe = flow.probe("const-state", PORT_VALUE).await;
+
+        for n in 1..=3 {
+            flow.process("const-state", "value", int(n)).await.unwrap();
+            assert_eq!(
+                probe.recv().await,
+                AgentValue::string(format!("tick:{}", n))
+            );
+        }
+    });
+}
+
+#[test]
+fn route_on_type_only_forwards_the_input_unchanged() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"switch value { "object" => "derived", "unit" => (), "array" => "nowhere", _ => "value" }"#;
+        let value_probe = script_agent(
+            &flow,
+            "by-type",
+            json!({"script": script, "route_on_type_only": true}),
+        )
+        .await;
+        let derived_probe = flow.probe("by-type", PORT_DERIVED).await;
+
+        let object = value(json!({"a": [1, 2]}));
+        flow.process("by-type", "value", object.clone())
+            .await
+            .unwrap();
+        assert_eq!(derived_probe.recv().await, object);
+        flow.process("by-type", "value", int(3)).await.unwrap();
+        assert_eq!(value_probe.recv().await, int(3));
+
+        // Unit drops the value and unknown ports fail
+        flow.process("by-type", "value", AgentValue::unit())
+            .await
+            .unwrap();
+        let err = flow
+            .process("by-type", "value", value(json!([1])))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::PinNotFound(_)), "{}", err);
+        value_probe.assert_empty().await;
+        derived_probe.assert_empty().await;
+    });
+}
+
+/// Compare routing a large object on its type against converting it.
+///
+/// Run with `cargo test --release -- --ignored --nocapture route_bench`.
+#[test]
+#[ignore]
+fn route_bench() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script =
+            r#"if type_of(value) == "map" || value == "object" { "derived" } else { "value" }"#;
+        for (id, type_only) in [("bench-full", false), ("bench-type", true)] {
+            flow.add(
+                id,
+                RhaiScriptAgent::DEF_NAME,
+                json!({"script": script, "route_on_type_only": type_only}),
+            )
+            .await;
+        }
+        let fields: serde_json::Map<String, Value> = (0..1000)
+            .map(|i| (format!("field{}", i), json!({"n": i, "s": "text"})))
+            .collect();
+        let payload = value(Value::Object(fields));
+
+        for id in ["bench-full", "bench-type"] {
+            let start = Instant::now();
+            for _ in 0..200 {
+                // Nothing is connected to the ports
+                flow.process(id, "value", payload.clone()).await.unwrap();
+            }
+            println!("{}: {:?} for 200 messages", id, start.elapsed());
+        }
+    });
+}
+
+#[test]
+fn scripts_check_outputs_before_routing() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"if value > 10 && outputs.contains("alarm") { #{ __port__: "alarm", v: value } } else { value }"#;
+        let value_probe = script_agent(&flow, "outputs", json!({"script": script})).await;
+
+        // Without an alarm port the value isn't routed to one
+        flow.process("outputs", "value", int(20)).await.unwrap();
+        assert_eq!(value_probe.recv().await, int(20));
+
+        flow.with_agent("outputs", |a: &mut RhaiScriptAgent| {
+            a.data
+                .spec
+                .outputs
+                .get_or_insert_default()
+                .push("alarm".to_string())
+        })
+        .await;
+        let alarm_probe = flow.probe("outputs", "alarm").await;
+        flow.process("outputs", "value", int(20)).await.unwrap();
+        flow.process("outputs", "value", int(5)).await.unwrap();
+        assert_eq!(alarm_probe.recv().await, value(json!({"v": 20})));
+        assert_eq!(value_probe.recv().await, int(5));
+    });
+}
+
+#[test]
+fn patch_keeps_the_document_across_messages() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"if "count" in doc {
+            [#{ op: "replace", path: "/count", value: doc.count + 1 }, #{ op: "add", path: "/seen/-", value: value }]
+        } else {
+            [#{ op: "add", path: "/count", value: 1 }, #{ op: "add", path: "/seen", value: [value] }]
+        }"#;
+        flow.add("patch", RhaiPatchAgent::DEF_NAME, json!({"script": script}))
+            .await;
+        let probe = flow.probe("patch", PORT_VALUE).await;
+
+        flow.process("patch", "value", value(json!("a")))
+            .await
+            .unwrap();
+        assert_eq!(
+            probe.recv().await,
+            value(json!({"count": 1, "seen": ["a"]}))
+        );
+        flow.process("patch", "value", value(json!("b")))
+            .await
+            .unwrap();
+        assert_eq!(
+            probe.recv().await,
+            value(json!({"count": 2, "seen": ["a", "b"]}))
+        );
+    });
+}
+
+#[test]
+fn reconfiguring_waits_for_the_message_in_flight() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let slow = r#"let n = 0; for i in 0..100000 { n += 1 } "old""#;
+        let probe = script_agent(&flow, "in-flight", json!({"script": slow})).await;
+
+        let agent = flow.askit.get_agent("in-flight").unwrap();
+        let (locked, is_locked) = tokio::sync::oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut agent = agent.lock().await;
+            locked.send(()).unwrap();
+            agent
+                .process(AgentContext::new(), "value".to_string(), AgentValue::unit())
+                .await
+        });
+        is_locked.await.unwrap();
+        flow.configure("in-flight", json!({"script": r#""new""#}))
+            .await
+            .unwrap();
+        task.await.unwrap().unwrap();
+        flow.process("in-flight", "value", AgentValue::unit())
+            .await
+            .unwrap();
+
+        assert_eq!(probe.recv().await, value(json!("old")));
+        assert_eq!(probe.recv().await, value(json!("new")));
+    });
+}
+
+#[test]
+fn send_to_delivers_to_another_agent() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let sender = script_agent(
+            &flow,
+            "send-a",
+            json!({"script": r#"send_to("send-b", value * 2); value"#}),
+        )
+        .await;
+        let target = script_agent(&flow, "send-b", json!({"script": "value + 1"})).await;
+
+        flow.process("send-a", "value", int(5)).await.unwrap();
+        assert_eq!(sender.recv().await, int(5));
+        assert_eq!(target.recv().await, int(11));
+
+        flow.configure("send-a", json!({"script": r#"send_to("missing", 1)"#}))
+            .await
+            .unwrap();
+        let err = flow.process("send-a", "value", int(1)).await.unwrap_err();
+        assert!(
+            err.to_string().contains("agent missing not found"),
+            "{}",
+            err
+        );
+        target.assert_empty().await;
+    });
+}
+
+#[test]
+fn agents_can_send_to_each_other() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = |target: &str| {
+            format!(
+                r#"if value < 20 {{ send_to("{}", value + 1) }} value"#,
+                target
+            )
+        };
+        let a = script_agent(&flow, "ping", json!({"script": script("pong")})).await;
+        let b = script_agent(&flow, "pong", json!({"script": script("ping")})).await;
+
+        flow.process("ping", "value", int(0)).await.unwrap();
+        for n in (0..20).step_by(2) {
+            assert_eq!(a.recv().await, int(n));
+            assert_eq!(b.recv().await, int(n + 1));
+        }
+        assert_eq!(a.recv().await, int(20));
+        a.assert_empty().await;
+        b.assert_empty().await;
+    });
+}
+
+#[test]
+fn wrap_output_wraps_scalars() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "wrap",
+            json!({"script": "value", "wrap_output": "result"}),
+        )
+        .await;
+
+        for input in [int(1), value(json!("s")), AgentValue::unit()] {
+            flow.process("wrap", "value", input.clone()).await.unwrap();
+            assert_eq!(
+                probe.recv().await,
+                value(json!({"result": input.to_json()}))
+            );
+        }
+        // Arrays and objects pass through
+        for input in [value(json!([1])), value(json!({"a": 1}))] {
+            flow.process("wrap", "value", input.clone()).await.unwrap();
+            assert_eq!(probe.recv().await, input);
+        }
+    });
+}
+
+#[test]
+fn wrap_always_wraps_every_result() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "wrap-always",
+            json!({"script": "value", "wrap_output": "r", "wrap_always": true}),
+        )
+        .await;
+
+        for input in [json!(1), json!([1]), json!({"a": 1})] {
+            flow.process("wrap-always", "value", value(input.clone()))
+                .await
+                .unwrap();
+            assert_eq!(probe.recv().await, value(json!({"r": input})));
+        }
+    });
+}
+
+/// Construct a Rhai Script agent with `configs` on top of its defaults.
+fn new_script_agent(flow: &TestFlow, configs: Value) -> Result<RhaiScriptAgent, AgentError> {
+    let mut spec = flow
+        .askit
+        .new_agent_flow_node(RhaiScriptAgent::DEF_NAME)
+        .unwrap()
+        .spec;
+    let merged = spec.configs.get_or_insert_default();
+    if let Value::Object(map) = configs {
+        for (key, config) in map {
+            merged.set(key, value(config));
+        }
+    }
+    <RhaiScriptAgent as AsAgent>::new(flow.askit.clone(), "self-test".to_string(), spec)
+}
+
+#[test]
+fn self_test_cases_check_the_script() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let cases = r#"[{"input": 1, "expected": 2}, {"input": {"n": 2}, "expected": 3}]"#;
+        let script = "if type_of(value) == \"map\" { value.n + 1 } else { value + 1 }";
+        assert!(new_script_agent(&flow, json!({"script": script, "self_test": cases})).is_ok());
+
+        let script_2 = "if type_of(value) == \"map\" { value.n } else { value + 1 }";
+        let err = new_script_agent(&flow, json!({"script": script_2, "self_test": cases}))
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string()
+                .contains("self_test case 1: expected 3, got 2"),
+            "{}",
+            err
+        );
+
+        for (cases, message) in [
+            ("[{", "not valid JSON"),
+            (r#"{"input": 1}"#, "must be an array"),
+        ] {
+            let err = new_script_agent(&flow, json!({"script": script, "self_test": cases}))
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+    });
+}
+
+#[test]
+fn heartbeats_pause_while_input_flows() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "heartbeat",
+            json!({"script": "value", "heartbeat_ms": 100, "heartbeat_script": r#""beat""#}),
+        )
+        .await;
+        let beat = value(json!("beat"));
+
+        assert_eq!(probe.recv().await, beat);
+        for n in 0..20 {
+            flow.process("heartbeat", "value", int(n)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for n in 0..20 {
+            assert_eq!(probe.recv().await, int(n));
+        }
+        // and resume once it stops
+        assert_eq!(probe.recv().await, beat);
+    });
+}
+
+#[test]
+fn heartbeats_wait_for_an_eval_permit() {
+    let _globals = lock_globals();
+    set_max_concurrent_evals(1);
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let permit = eval_permit().await;
+        let probe = script_agent(
+            &flow,
+            "heartbeat-permit",
+            json!({"heartbeat_ms": 20, "heartbeat_script": "1"}),
+        )
+        .await;
+
+        probe.assert_empty().await;
+        drop(permit);
+        assert_eq!(probe.recv().await, int(1));
+        flow.stop("heartbeat-permit").await;
+    });
+    set_max_concurrent_evals(0);
+}
+
+/// Make the current engine one whose `busy(ms)` blocks its thread for `ms`.
+/// Returns the number of calls and the most that ran at once.
+fn set_busy_engine() -> (Arc<AtomicUsize>, Arc<AtomicUsize>) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let running = Arc::new(AtomicUsize::new(0));
+    let mut engine = new_engine();
+    let (busy_calls, busy_peak) = (calls.clone(), peak.clone());
+    engine.register_fn("busy", move |ms: rhai::INT| {
+        busy_calls.fetch_add(1, Ordering::SeqCst);
+        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+        busy_peak.fetch_max(now, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(ms as u64));
+        running.fetch_sub(1, Ordering::SeqCst);
+    });
+    crate::engine::set_engine(engine);
+    (calls, peak)
+}
+
+#[test]
+fn agents_bound_their_own_concurrent_evals() {
+    let _globals = lock_globals();
+    let (calls, peak) = set_busy_engine();
+    block_on(async {
+        let flow = TestFlow::new().await;
+        // Heartbeats fire while the inputs are evaluated
+        let configs = json!({
+            "script": "busy(20)",
+            "heartbeat_ms": 5,
+            "heartbeat_script": "busy(1)",
+            "max_concurrent_evals": 1,
+        });
+        script_agent(&flow, "own-limit", configs.clone()).await;
+        for n in 0..10 {
+            flow.process("own-limit", "value", int(n)).await.unwrap();
+        }
+        flow.stop("own-limit").await;
+        assert!(calls.load(Ordering::SeqCst) > 10, "no heartbeat ran");
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+
+        // A limit set by reconfiguring applies to the running heartbeat too
+        let mut unlimited = configs;
+        unlimited["max_concurrent_evals"] = json!(0);
+        script_agent(&flow, "own-limit-later", unlimited).await;
+        flow.configure("own-limit-later", json!({"max_concurrent_evals": 1}))
+            .await
+            .unwrap();
+        calls.store(0, Ordering::SeqCst);
+        peak.store(0, Ordering::SeqCst);
+        for n in 0..10 {
+            flow.process("own-limit-later", "value", int(n))
+                .await
+                .unwrap();
+        }
+        flow.stop("own-limit-later").await;
+    });
+    crate::engine::set_engine(new_engine());
+    assert!(calls.load(Ordering::SeqCst) > 10, "no heartbeat ran");
+    assert_eq!(peak.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn outputs_carry_increasing_sequence_numbers() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(&flow, "seq", json!({"script": "emit_now(seq); seq"})).await;
+
+        for _ in 0..3 {
+            flow.process("seq", "value", AgentValue::unit())
+                .await
+                .unwrap();
+        }
+        // Each output, including emit_now's, takes the next number, and the
+        // script sees the number of the next one
+        for n in 0..6 {
+            let (ctx, out) = probe.recv_ctx().await;
+            assert_eq!(ctx.get_var("seq"), Some(&int(n)));
+            assert_eq!(out, int(n / 2 * 2));
+        }
+    });
+}
+
+#[test]
+fn sequence_numbers_restart_unless_persisted() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        for (id, persist) in [("seq-reset", false), ("seq-persist", true)] {
+            let probe =
+                script_agent(&flow, id, json!({"script": "seq", "persist_seq": persist})).await;
+            flow.process(id, "value", AgentValue::unit()).await.unwrap();
+            flow.process(id, "value", AgentValue::unit()).await.unwrap();
+            flow.stop(id).await;
+            flow.restart(id).await;
+            flow.process(id, "value", AgentValue::unit()).await.unwrap();
+
+            let expected = if persist { [0, 1, 2] } else { [0, 1, 0] };
+            for n in expected {
+                assert_eq!(probe.recv().await, int(n), "{}", id);
+            }
+        }
+    });
+}
+
+#[test]
+fn skip_on_unit_skips_the_script() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "skip-unit",
+            json!({"script": "value + 1", "skip_on_unit": true}),
+        )
+        .await;
+
+        flow.process("skip-unit", "value", AgentValue::unit())
+            .await
+            .unwrap();
+        flow.process("skip-unit", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, int(2));
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn pass_unit_emits_the_skipped_input() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "pass-unit",
+            json!({"script": "value + 1", "skip_on_unit": true, "pass_unit": true}),
+        )
+        .await;
+
+        flow.process("pass-unit", "value", AgentValue::unit())
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, AgentValue::unit());
+
+        // Without skip_on_unit the script runs, and fails, on unit
+        flow.configure("pass-unit", json!({"skip_on_unit": false}))
+            .await
+            .unwrap();
+        assert!(
+            flow.process("pass-unit", "value", AgentValue::unit())
+                .await
+                .is_err()
+        );
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn template_renders_the_input() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "template",
+            RhaiTemplateAgent::DEF_NAME,
+            json!({"template": "${value.level}: ${value.msg}"}),
+        )
+        .await;
+        let probe = flow.probe("template", PORT_VALUE).await;
+
+        flow.process(
+            "template",
+            "value",
+            value(json!({"level": "warn", "msg": "disk full"})),
+        )
+        .await
+        .unwrap();
+        assert_eq!(probe.recv().await, value(json!("warn: disk full")));
+    });
+}
+
+#[test]
+fn debug_stages_emit_each_stage() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "stages",
+            json!({"pre_transform": "value * 10", "script": "value + 1", "debug_stages": true}),
+        )
+        .await;
+        let stage_1 = flow.probe("stages", PORT_STAGE_1).await;
+        let stage_2 = flow.probe("stages", PORT_STAGE_2).await;
+
+        flow.process("stages", "value", int(4)).await.unwrap();
+        assert_eq!(stage_1.recv().await, int(40));
+        assert_eq!(stage_2.recv().await, int(41));
+        assert_eq!(probe.recv().await, int(41));
+
+        // The stage ports go away when disabled
+        flow.configure("stages", json!({"debug_stages": false}))
+            .await
+            .unwrap();
+        let outputs = flow
+            .with_agent("stages", |a: &mut RhaiScriptAgent| a.spec().outputs.clone())
+            .await
+            .unwrap();
+        assert!(!outputs.iter().any(|port| port.starts_with("stage_")));
+        flow.process("stages", "value", int(4)).await.unwrap();
+        assert_eq!(probe.recv().await, int(41));
+        stage_1.assert_empty().await;
+        stage_2.assert_empty().await;
+    });
+}
+
+#[test]
+fn keep_last_good_runs_the_previous_script_after_a_bad_edit() {
+    capture_logs();
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "last-good",
+            json!({"script": "value * 2", "keep_last_good": true}),
+        )
+        .await;
+
+        flow.configure("last-good", json!({"script": "value *"}))
+            .await
+            .unwrap();
+        let status = flow
+            .with_agent("last-good", |a: &mut RhaiScriptAgent| a.status())
+            .await;
+        assert!(status.degraded);
+        assert!(status.last_error.is_some());
+        assert_eq!(
+            logs(LOG_TARGET, "last-good: keeping the previous script").len(),
+            1
+        );
+        flow.process("last-good", "value", int(4)).await.unwrap();
+        assert_eq!(probe.recv().await, int(8));
+
+        // A good edit clears the degraded state
+        flow.configure("last-good", json!({"script": "value * 3"}))
+            .await
+            .unwrap();
+        let status = flow
+            .with_agent("last-good", |a: &mut RhaiScriptAgent| a.status())
+            .await;
+        assert!(!status.degraded);
+        assert_eq!(status.last_error, None);
+        flow.process("last-good", "value", int(4)).await.unwrap();
+        assert_eq!(probe.recv().await, int(12));
+    });
+}
+
+#[test]
+fn a_rejected_edit_applies_none_of_its_configs() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "all-or-nothing",
+            json!({"script": "value * 2", "keep_last_good": true}),
+        )
+        .await;
+
+        // The script and wrap_output are fine, but the self_test fails
+        flow.configure(
+            "all-or-nothing",
+            json!({
+                "script": "value * 3",
+                "wrap_output": "result",
+                "self_test": r#"[{"input": 1, "expected": 2}]"#,
+            }),
+        )
+        .await
+        .unwrap();
+        let status = flow
+            .with_agent("all-or-nothing", |a: &mut RhaiScriptAgent| a.status())
+            .await;
+        assert!(status.degraded);
+        assert_eq!(status.source_len, 9);
+        flow.process("all-or-nothing", "value", int(4))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, int(8));
+
+        // A config rejected after the scripts compile is no different
+        flow.configure(
+            "all-or-nothing",
+            json!({"script": "value * 3", "wrap_output": "result", "element_type": "nope"}),
+        )
+        .await
+        .unwrap();
+        let status = flow
+            .with_agent("all-or-nothing", |a: &mut RhaiScriptAgent| a.status())
+            .await;
+        assert!(status.degraded);
+        assert!(status.last_error.unwrap().contains("element_type"));
+        flow.process("all-or-nothing", "value", int(4))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, int(8));
+    });
+}
+
+#[test]
+fn recent_holds_the_previous_inputs() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = "if recent.is_empty() { () } else { value - recent[-1] }";
+        let probe = script_agent(
+            &flow,
+            "recent",
+            json!({"script": script, "history_size": 2}),
+        )
+        .await;
+
+        for n in [10, 13, 19] {
+            flow.process("recent", "value", int(n)).await.unwrap();
+        }
+        assert_eq!(probe.recv().await, AgentValue::unit());
+        assert_eq!(probe.recv().await, int(3));
+        assert_eq!(probe.recv().await, int(6));
+
+        // Only the last history_size inputs are kept
+        flow.configure("recent", json!({"script": "recent"}))
+            .await
+            .unwrap();
+        flow.process("recent", "value", int(0)).await.unwrap();
+        assert_eq!(probe.recv().await, value(json!([13, 19])));
+    });
+}
+
+#[test]
+fn recent_is_undefined_without_history() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(&flow, "no-recent", json!({"script": "recent"})).await;
+        assert!(flow.process("no-recent", "value", int(1)).await.is_err());
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn pin_timestamps_tell_when_a_pin_went_stale() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"if "other" in pin_timestamps && pin_timestamps.other.elapsed < 0.2 { "fresh" } else { "stale" }"#;
+        let probe = script_agent(&flow, "pins", json!({"script": script})).await;
+        let (fresh, stale) = (value(json!("fresh")), value(json!("stale")));
+
+        flow.process("pins", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, stale);
+        flow.process("pins", "other", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, fresh);
+        flow.process("pins", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, fresh);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        flow.process("pins", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, stale);
+    });
+}
+
+#[test]
+fn pure_scripts_cant_assign_to_value() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "pure",
+            json!({"script": "let v = value; v += 1; v", "pure": true}),
+        )
+        .await;
+        flow.process("pure", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, int(2));
+
+        let err = flow
+            .configure("pure", json!({"script": "value += 1; value"}))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("pure script assigns to value"),
+            "{}",
+            err
+        );
+
+        // The fallback script is held to it too
+        let err = flow
+            .configure(
+                "pure",
+                json!({"script": "value", "fallback_script": "state.errors = 1; ()"}),
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("pure script assigns to state"),
+            "{}",
+            err
+        );
+
+        // and can without pure
+        flow.configure(
+            "pure",
+            json!({"script": "value += 1; value", "pure": false}),
+        )
+        .await
+        .unwrap();
+        flow.process("pure", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, int(2));
+    });
+}
+
+#[test]
+fn value_size_measures_the_input() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "input-size",
+            json!({"script": "let before = value_size(); value = (); [before, value_size(), value_size(value)]"}),
+        )
+        .await;
+
+        let mut last = 0;
+        for n in [1, 10, 100] {
+            let input = json!({"items": vec!["item"; n]});
+            flow.process("input-size", "value", value(input.clone()))
+                .await
+                .unwrap();
+            let size = input.to_string().len() as i64;
+            assert_eq!(probe.recv().await, value(json!([size, size, 4])));
+            assert!(size > last);
+            last = size;
+        }
+    });
+}
+
+#[test]
+fn route_table_maps_keys_to_ports() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let value_probe = script_agent(
+            &flow,
+            "table",
+            json!({"script": "value.kind", "route_table": {"metric": "derived", "default": "value"}}),
+        )
+        .await;
+        let derived_probe = flow.probe("table", PORT_DERIVED).await;
+
+        let metric = value(json!({"kind": "metric", "n": 1}));
+        let other = value(json!({"kind": "log"}));
+        flow.process("table", "value", metric.clone())
+            .await
+            .unwrap();
+        flow.process("table", "value", other.clone()).await.unwrap();
+        assert_eq!(derived_probe.recv().await, metric);
+        assert_eq!(value_probe.recv().await, other);
+    });
+}
+
+#[test]
+fn route_table_fails_unmapped_keys_without_a_default() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "table-strict",
+            json!({"script": "value", "route_table": {"a": "value", "b": "nowhere"}}),
+        )
+        .await;
+
+        flow.process("table-strict", "value", value(json!("a")))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, value(json!("a")));
+        let err = flow
+            .process("table-strict", "value", value(json!("c")))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("No route for c"), "{}", err);
+        let err = flow
+            .process("table-strict", "value", value(json!("b")))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::PinNotFound(_)), "{}", err);
+        assert!(flow.process("table-strict", "value", int(1)).await.is_err());
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn timings_report_each_phase() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "timings",
+            json!({"script": "value.len()", "timings": true}),
+        )
+        .await;
+        let diagnostics = flow.probe("timings", PORT_DIAGNOSTICS).await;
+
+        flow.process("timings", "value", value(json!([1, 2, 3])))
+            .await
+            .unwrap();
+        let AgentValue::Object(timings) = diagnostics.recv().await else {
+            panic!("diagnostics aren't an object");
+        };
+        for key in ["convert_in_micros", "eval_micros", "convert_out_micros"] {
+            let micros = timings.get(key).and_then(|v| v.as_i64());
+            assert!(micros.is_some_and(|m| m >= 0), "{}: {:?}", key, micros);
+        }
+        assert_eq!(probe.recv().await, int(3));
+    });
+}
+
+#[test]
+fn result_var_attaches_the_result_to_the_context() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let enriched = script_agent(
+            &flow,
+            "enrich",
+            json!({"script": "value.len()", "result_var": "score"}),
+        )
+        .await;
+        let reader = script_agent(
+            &flow,
+            "read-score",
+            json!({"script": r#"[ctx_var("score"), ctx_var("missing"), value.len()]"#}),
+        )
+        .await;
+        flow.connect("enrich", PORT_VALUE, "read-score", PORT_VALUE);
+
+        let input = value(json!(["a", "b", "c"]));
+        flow.process("enrich", "value", input.clone())
+            .await
+            .unwrap();
+        let (ctx, out) = enriched.recv_ctx().await;
+        assert_eq!(out, input);
+        assert_eq!(ctx.get_var("score"), Some(&int(3)));
+        assert_eq!(reader.recv().await, value(json!([3, null, 3])));
+    });
+}
+
+#[test]
+fn checked_arithmetic_is_refused() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        flow.add(
+            "checked",
+            RhaiScriptAgent::DEF_NAME,
+            json!({"script": "value + 1"}),
+        )
+        .await;
+
+        let err = flow
+            .configure("checked", json!({"checked_arithmetic": false}))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::InvalidConfig(_)), "{}", err);
+        assert!(err.to_string().contains("unchecked feature"), "{}", err);
+    });
+}
+
+#[test]
+fn frozen_state_survives_changes_to_the_input() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"
+            if !("kept" in state) { state.kept = freeze(value); }
+            value.push(99);
+            [state.kept, value]
+        "#;
+        let probe = script_agent(&flow, "freeze-state", json!({"script": script})).await;
+        flow.process("freeze-state", "value", value(json!([1])))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, value(json!([[1], [1, 99]])));
+        flow.process("freeze-state", "value", value(json!([2])))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, value(json!([[1], [2, 99]])));
+    });
+}
+
+#[test]
+fn output_schema_checks_results() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let schema = json!({"name": "string", "score": "number"});
+        let script = r#"send_to("schema-sink", value.score); value"#;
+        let probe = script_agent(
+            &flow,
+            "schema",
+            json!({"script": script, "output_schema": schema}),
+        )
+        .await;
+        let sink = script_agent(&flow, "schema-sink", json!({"script": "value"})).await;
+
+        // Integers count as numbers, and other keys are allowed
+        let good = value(json!({"name": "a", "score": 3, "extra": true}));
+        flow.process("schema", "value", good.clone()).await.unwrap();
+        assert_eq!(probe.recv().await, good);
+        assert_eq!(sink.recv().await, int(3));
+
+        let bad = value(json!({"name": 1, "score": 2}));
+        let err = flow.process("schema", "value", bad).await.unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("output name must be string, not integer"),
+            "{}",
+            err
+        );
+        probe.assert_empty().await;
+        sink.assert_empty().await;
+    });
+}
+
+#[test]
+fn schema_errors_to_port_emits_and_still_sends() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"send_to("schema-port-sink", value.len()); value"#;
+        let probe = script_agent(
+            &flow,
+            "schema-port",
+            json!({
+                "script": script,
+                "output_schema": {"name": "string"},
+                "schema_errors_to_port": true,
+            }),
+        )
+        .await;
+        let errors = flow.probe("schema-port", "error").await;
+        let sink = script_agent(&flow, "schema-port-sink", json!({"script": "value"})).await;
+
+        let bad = value(json!({"score": 2}));
+        flow.process("schema-port", "value", bad).await.unwrap();
+        assert_eq!(
+            errors.recv().await,
+            value(json!({"error": "output is missing name", "value": {"score": 2}}))
+        );
+        assert_eq!(sink.recv().await, int(1));
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn fallback_script_runs_when_the_script_fails() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "fallback",
+            json!({
+                "script": r#"if value < 0 { throw "negative" } value * 2"#,
+                "fallback_script": "#{ fallback: value }",
+            }),
+        )
+        .await;
+
+        flow.process("fallback", "value", int(3)).await.unwrap();
+        assert_eq!(probe.recv().await, int(6));
+        flow.process("fallback", "value", int(-1)).await.unwrap();
+        assert_eq!(probe.recv().await, value(json!({"fallback": -1})));
+
+        flow.configure(
+            "fallback",
+            json!({"fallback_script": r#"throw "also failed""#}),
+        )
+        .await
+        .unwrap();
+        let err = flow
+            .process("fallback", "value", int(-1))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("also failed"), "{}", err);
+        probe.assert_empty().await;
+    });
+}
+
+// Without the checks of the engine, the recursion would overflow the stack
+#[cfg(not(feature = "unchecked"))]
+#[test]
+fn limit_errors_go_to_the_error_port() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"fn f(x) { f(x) } if value > 0 { f(value) } else { throw "negative" }"#;
+        let probe = script_agent(
+            &flow,
+            "limit-port",
+            json!({"script": script, "limit_errors_to_port": true}),
+        )
+        .await;
+        let errors = flow.probe("limit-port", "error").await;
+
+        flow.process("limit-port", "value", int(1)).await.unwrap();
+        let out = errors.recv().await;
+        assert_eq!(out.get("value"), Some(&int(1)));
+        let error = out.get_str("error").unwrap();
+        assert!(error.starts_with("Rhai Limit Error: "), "{}", error);
+        probe.assert_empty().await;
+
+        // Other errors still fail the message
+        let err = flow
+            .process("limit-port", "value", int(-1))
+            .await
+            .unwrap_err();
+        assert_eq!(script_error_kind(&err), Some(ScriptErrorKind::Runtime));
+        errors.assert_empty().await;
+    });
+}
+
+#[test]
+fn table_lookup_reads_configured_tables() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"[table_lookup("codes", value), table_lookup("units", value)]"#;
+        let probe = script_agent(
+            &flow,
+            "tables",
+            json!({
+                "script": script,
+                "tables": {
+                    "codes": {"a": "Alpha", "b": 2},
+                    "units": "a,meter\nb,\"kilo, gram\"\n",
+                },
+            }),
+        )
+        .await;
+
+        flow.process("tables", "value", value(json!("b")))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, value(json!([2, "kilo, gram"])));
+        flow.process("tables", "value", value(json!("z")))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, value(json!([null, null])));
+
+        flow.configure(
+            "tables",
+            json!({"script": r#"table_lookup("missing", value)"#}),
+        )
+        .await
+        .unwrap();
+        let err = flow
+            .process("tables", "value", value(json!("a")))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown table missing"), "{}", err);
+
+        let err = flow
+            .configure("tables", json!({"tables": {"bad": "a,b,c"}}))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("key and a value"), "{}", err);
+    });
+}
+
+#[test]
+fn emit_now_and_emit_after() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = "emit_now(value + 100); emit_after(200, value + 200); value";
+        let probe = script_agent(&flow, "emits", json!({"script": script})).await;
+
+        let start = std::time::Instant::now();
+        flow.process("emits", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, int(101));
+        assert_eq!(probe.recv().await, int(1));
+        assert_eq!(probe.try_recv(50).await, None);
+        assert_eq!(probe.recv().await, int(201));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // Stopping the agent cancels what is still scheduled
+        flow.process("emits", "value", int(2)).await.unwrap();
+        assert_eq!(probe.recv().await, int(102));
+        assert_eq!(probe.recv().await, int(2));
+        flow.stop("emits").await;
+        assert_eq!(probe.try_recv(400).await, None);
+    });
+}
+
+#[test]
+fn emits_of_a_failed_script_are_dropped() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"emit_now(1); emit_after(10, 2); throw "failed""#;
+        let probe = script_agent(&flow, "emits-failed", json!({"script": script})).await;
+        assert!(flow.process("emits-failed", "value", int(0)).await.is_err());
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn generation_counts_reconfigurations() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(&flow, "generation", json!({"script": "generation"})).await;
+        flow.process("generation", "value", int(0)).await.unwrap();
+        assert_eq!(probe.recv().await, int(0));
+
+        for expected in 1..=2 {
+            flow.configure("generation", json!({"script": "generation + value"}))
+                .await
+                .unwrap();
+            flow.process("generation", "value", int(0)).await.unwrap();
+            assert_eq!(probe.recv().await, int(expected));
+        }
+
+        // A rejected configuration doesn't count
+        assert!(
+            flow.configure("generation", json!({"script": "1 +"}))
+                .await
+                .is_err()
+        );
+        flow.process("generation", "value", int(0)).await.unwrap();
+        assert_eq!(probe.recv().await, int(2));
+    });
+}
+
+#[test]
+fn pause_holds_off_the_next_input() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = "if value > 0 { pause(value); pause(10) } value";
+        let probe = script_agent(&flow, "pause", json!({"script": script})).await;
+
+        let start = std::time::Instant::now();
+        flow.process("pause", "value", int(300)).await.unwrap();
+        // The longest pause applies, after the output is sent
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(probe.try_recv(0).await, Some(int(300)));
+
+        let start = std::time::Instant::now();
+        flow.process("pause", "value", int(0)).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(300));
+        assert_eq!(probe.recv().await, int(0));
+
+        flow.configure("pause", json!({"script": r#"pause(300); throw "failed""#}))
+            .await
+            .unwrap();
+        let start = std::time::Instant::now();
+        assert!(flow.process("pause", "value", int(0)).await.is_err());
+        assert!(start.elapsed() < Duration::from_millis(300));
+    });
+}
+
+#[test]
+fn no_output_emits_nothing() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"if value == 0 { no_output() } send_to("no-output-sink", value); value"#;
+        let probe = script_agent(&flow, "no-output", json!({"script": script})).await;
+        let sink = script_agent(&flow, "no-output-sink", json!({"script": "value"})).await;
+
+        flow.process("no-output", "value", int(0)).await.unwrap();
+        // Sends are delivered either way
+        assert_eq!(sink.recv().await, int(0));
+        probe.assert_empty().await;
+
+        flow.process("no-output", "value", int(1)).await.unwrap();
+        assert_eq!(probe.recv().await, int(1));
+    });
+}
+
+#[test]
+fn output_replaces_the_result() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = "output(()); if value > 0 { output(value * 10) } 42";
+        let probe = script_agent(&flow, "output", json!({"script": script})).await;
+
+        // Unit is emitted when asked for, whatever the script evaluates to
+        flow.process("output", "value", int(0)).await.unwrap();
+        assert_eq!(probe.recv().await, AgentValue::unit());
+        // The last call wins
+        flow.process("output", "value", int(2)).await.unwrap();
+        assert_eq!(probe.recv().await, int(20));
+    });
+}
+
+#[test]
+fn coalesce_runs_only_the_latest_input() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "coalesce",
+            json!({"script": "value * 10", "coalesce_ms": 100}),
+        )
+        .await;
+
+        for n in 1..=3 {
+            flow.process("coalesce", "value", int(n)).await.unwrap();
+        }
+        assert_eq!(probe.recv().await, int(30));
+        probe.assert_empty().await;
+
+        // A new window starts with the next input
+        flow.process("coalesce", "value", int(4)).await.unwrap();
+        assert_eq!(probe.recv().await, int(40));
+
+        // and stopping the agent runs the input its window still holds
+        flow.process("coalesce", "value", int(5)).await.unwrap();
+        flow.stop("coalesce").await;
+        assert_eq!(probe.recv().await, int(50));
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn no_input_ends_a_coalescing_window_early() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "coalesce-pin",
+            json!({"script": "value * 10", "coalesce_ms": 100}),
+        )
+        .await;
+
+        // Any pin, whatever its name, is an input to coalesce
+        flow.process("coalesce-pin", "value", int(1)).await.unwrap();
+        flow.process("coalesce-pin", "__coalesce__", int(2))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, int(20));
+        probe.assert_empty().await;
+    });
+}
+
+#[test]
+fn change_key_runs_the_script_on_changes_only() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = "state.runs = (state.runs ?? 0) + 1; [value.v, state.runs]";
+        let probe = script_agent(
+            &flow,
+            "change-key",
+            json!({"script": script, "change_key": "id"}),
+        )
+        .await;
+
+        for (input, expected) in [
+            // The first input always runs
+            (json!({"id": 1, "v": "a"}), Some(json!(["a", 1]))),
+            (json!({"id": 1, "v": "b"}), None),
+            (json!({"id": 2, "v": "c"}), Some(json!(["c", 2]))),
+            // A missing field counts as unit
+            (json!({"v": "d"}), Some(json!(["d", 3]))),
+            (json!({"id": null, "v": "e"}), None),
+            (json!({"id": 1, "v": "f"}), Some(json!(["f", 4]))),
+        ] {
+            flow.process("change-key", "value", value(input))
+                .await
+                .unwrap();
+            match expected {
+                Some(expected) => assert_eq!(probe.recv().await, value(expected)),
+                None => probe.assert_empty().await,
+            }
+        }
+    });
+}
+
+#[test]
+fn error_key_routes_returned_errors() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"
+            send_to("error-key-sink", value);
+            if value < 0 { #{ error: "negative", __port__: "value" } } else { #{ error: (), n: value } }
+        "#;
+        let probe = script_agent(
+            &flow,
+            "error-key",
+            json!({"script": script, "error_key": "error", "output_schema": {"n": "integer"}}),
+        )
+        .await;
+        let errors = flow.probe("error-key", "error").await;
+        let sink = script_agent(&flow, "error-key-sink", json!({"script": "value"})).await;
+
+        // Ahead of the `__port__` key and the output schema
+        flow.process("error-key", "value", int(-1)).await.unwrap();
+        assert_eq!(
+            errors.recv().await,
+            value(json!({"error": "negative", "__port__": "value"}))
+        );
+        assert_eq!(sink.recv().await, int(-1));
+        probe.assert_empty().await;
+
+        // A unit error is no error
+        flow.process("error-key", "value", int(2)).await.unwrap();
+        assert_eq!(probe.recv().await, value(json!({"error": null, "n": 2})));
+        errors.assert_empty().await;
+    });
+}
+
+#[test]
+fn channels_expose_values_published_by_other_agents() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let _publisher = script_agent(
+            &flow,
+            "publisher",
+            json!({"script": r#"publish("prices", value); value"#}),
+        )
+        .await;
+        let reader = script_agent(
+            &flow,
+            "subscriber",
+            json!({"script": "[channels.prices, value]", "channels": "prices"}),
+        )
+        .await;
+
+        // A channel is unit until something is published on it
+        flow.process("subscriber", "value", int(0)).await.unwrap();
+        assert_eq!(reader.recv().await, value(json!([null, 0])));
+
+        flow.process("publisher", "value", value(json!({"a": 1})))
+            .await
+            .unwrap();
+        let mut last = AgentValue::unit();
+        for _ in 0..100 {
+            flow.process("subscriber", "value", int(1)).await.unwrap();
+            last = reader.recv().await;
+            if last != value(json!([null, 1])) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(last, value(json!([{"a": 1}, 1])));
+
+        // A failed script publishes nothing
+        flow.configure(
+            "publisher",
+            json!({"script": r#"publish("prices", 2); throw "failed""#}),
+        )
+        .await
+        .unwrap();
+        assert!(flow.process("publisher", "value", int(0)).await.is_err());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        flow.process("subscriber", "value", int(2)).await.unwrap();
+        assert_eq!(reader.recv().await, value(json!([{"a": 1}, 2])));
+    });
+}
+
+#[test]
+fn element_type_rejects_mixed_arrays() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(
+            &flow,
+            "element-type",
+            json!({"script": "value.len()", "element_type": "same"}),
+        )
+        .await;
+
+        // Empty arrays and other values pass
+        for (input, len) in [(json!([1, 2, 3]), 3), (json!([]), 0), (json!("abcd"), 4)] {
+            flow.process("element-type", "value", value(input))
+                .await
+                .unwrap();
+            assert_eq!(probe.recv().await, int(len));
+        }
+        let err = flow
+            .process("element-type", "value", value(json!(["a", "b", 3])))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("array element 2 must be string, not integer"),
+            "{}",
+            err
+        );
+        probe.assert_empty().await;
+
+        // Integers count as numbers
+        flow.configure("element-type", json!({"element_type": "number"}))
+            .await
+            .unwrap();
+        flow.process("element-type", "value", value(json!([1.5, 2])))
+            .await
+            .unwrap();
+        assert_eq!(probe.recv().await, int(2));
+        let err = flow
+            .process("element-type", "value", value(json!([1, "2"])))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("array element 1 must be number, not string"),
+            "{}",
+            err
+        );
+
+        let err = flow
+            .configure("element-type", json!({"element_type": "float"}))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("element_type must be same or one of"),
+            "{}",
+            err
+        );
+    });
+}
+
+#[test]
+fn process_runs_in_a_span_recording_its_outcome() {
+    capture_traces();
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let script = r#"if value < 0 { throw "negative" } value"#;
+        script_agent(&flow, "traced", json!({ "script": script })).await;
+
+        flow.process("traced", "value", int(1)).await.unwrap();
+        flow.process("traced", "value", int(-1)).await.unwrap_err();
+        let traced = spans("process", "agent", "traced");
+        let outcomes: Vec<&str> = traced
+            .iter()
+            .map(|s| s.fields["outcome"].as_str())
+            .collect();
+        assert_eq!(outcomes, ["ok", "error"]);
+        for span in &traced {
+            assert!(span.entered);
+            assert_eq!(span.fields["pin"], "value");
+        }
+    });
+}
+
+#[cfg(not(feature = "unchecked"))]
+#[test]
+fn process_span_records_scripts_stopped_by_a_limit_as_timeouts() {
+    let _globals = lock_globals();
+    capture_traces();
+    block_on(async {
+        let flow = TestFlow::new().await;
+        script_agent(&flow, "traced-limit", json!({"script": "loop {}"})).await;
+
+        let mut engine = crate::engine::new_engine();
+        engine.set_max_operations(1000);
+        crate::engine::set_engine(engine);
+        let result = flow.process("traced-limit", "value", int(0)).await;
+        crate::engine::set_engine(crate::engine::new_engine());
+        assert!(result.is_err());
+        let traced = spans("process", "agent", "traced-limit");
+        assert_eq!(traced.len(), 1);
+        assert_eq!(traced[0].fields["outcome"], "timeout");
+    });
+}
+
+#[test]
+fn agents_pick_up_functions_of_a_swapped_in_engine() {
+    let _globals = lock_globals();
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(&flow, "swap-engine", json!({"script": "double(value)"})).await;
+
+        let err = flow
+            .process("swap-engine", "value", int(4))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("double"), "{}", err);
+
+        let mut engine = new_engine();
+        engine.register_fn("double", |x: rhai::INT| x * 2);
+        crate::engine::set_engine(engine);
+        // The agent keeps its compiled script and runs it on the new engine
+        let result = flow.process("swap-engine", "value", int(4)).await;
+        crate::engine::set_engine(new_engine());
+        result.unwrap();
+        assert_eq!(probe.recv().await, int(8));
+    });
+}
+
+#[test]
+fn agents_run_a_precompiled_script() {
+    block_on(async {
+        let flow = TestFlow::new().await;
+        let probe = script_agent(&flow, "precompiled", json!({})).await;
+        let ast = Arc::new(get_engine().compile("value * 2").unwrap());
+
+        flow.with_agent("precompiled", |agent: &mut RhaiScriptAgent| {
+            agent.set_ast(ast.clone())
+        })
+        .await
+        .unwrap();
+        flow.process("precompiled", "value", int(4)).await.unwrap();
+        assert_eq!(probe.recv().await, int(8));
+
+        // A configured script isn't silently shadowed by the precompiled one
+        flow.configure("precompiled", json!({"script": "value + 1"}))
+            .await
+            .unwrap();
+        let err = flow
+            .with_agent("precompiled", |agent: &mut RhaiScriptAgent| {
+                agent.set_ast(ast)
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::InvalidConfig(_)), "{}", err);
+        flow.process("precompiled", "value", int(4)).await.unwrap();
+        assert_eq!(probe.recv().await, int(5));
+    });
+}
+
+#[test]
+fn set_ast_runs_without_configs() {
+    let mut spec = RhaiScriptAgent::agent_definition().to_spec();
+    spec.configs = None;
+    let mut agent =
+        <RhaiScriptAgent as AsAgent>::new(ASKit::new(), "no-configs".into(), spec).unwrap();
+    assert!(!agent.status().has_ast);
+
+    let ast = Arc::new(get_engine().compile("value * 2").unwrap());
+    agent.set_ast(ast).unwrap();
+    assert!(agent.status().has_ast);
+}
+
+/// Make the current engine one whose `flaky()` fails with a retryable error
+/// until it has been called `failures` times, and whose `broken()` always
+/// fails with a plain one. Returns the number of calls to either.
+fn set_flaky_engine(failures: usize) -> Arc<AtomicUsize> {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut engine = new_engine();
+    let flaky_calls = calls.clone();
+    engine.register_fn(
+        "flaky",
+        move || -> Result<rhai::INT, Box<rhai::EvalAltResult>> {
+            let call = flaky_calls.fetch_add(1, Ordering::SeqCst);
+            if call < failures {
+                return Err(crate::engine::retryable_error("unavailable"));
+            }
+            Ok(call as rhai::INT)
+        },
+    );
+    let broken_calls = calls.clone();
+    engine.register_fn(
+        "broken",
+        move || -> Result<rhai::INT, Box<rhai::EvalAltResult>> {
+            broken_calls.fetch_add(1, Ordering::SeqCst);
+            Err("broken".into())
+        },
+    );
+    crate::engine::set_engine(engine);
+    calls
+}
+
+#[test]
+fn scripts_retry_retryable_errors() {
+    let _globals = lock_globals();
+    let calls = set_flaky_engine(2);
+    let result = block_on(async {
+        let flow = TestFlow::new().await;
+        let config = json!({"script": "flaky()", "max_retries": 3, "retry_backoff_ms": 1});
+        let probe = script_agent(&flow, "retry", config).await;
+        flow.process("retry", "value", int(0)).await?;
+        Ok::<_, AgentError>(probe.recv().await)
+    });
+    crate::engine::set_engine(new_engine());
+    // Two failed attempts, then the one that succeeded
+    assert_eq!(result.unwrap(), int(2));
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn scripts_give_up_after_max_retries() {
+    let _globals = lock_globals();
+    let calls = set_flaky_engine(usize::MAX);
+    let result = block_on(async {
+        let flow = TestFlow::new().await;
+        let config = json!({"script": "flaky()", "max_retries": 2, "retry_backoff_ms": 1});
+        script_agent(&flow, "retry-out", config).await;
+        flow.process("retry-out", "value", int(0)).await
+    });
+    crate::engine::set_engine(new_engine());
+    let err = result.unwrap_err();
+    assert!(err.to_string().contains("unavailable"), "{}", err);
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn scripts_do_not_retry_other_errors() {
+    let _globals = lock_globals();
+    let calls = set_flaky_engine(0);
+    let result = block_on(async {
+        let flow = TestFlow::new().await;
+        let config = json!({"script": "broken()", "max_retries": 3, "retry_backoff_ms": 1});
+        script_agent(&flow, "no-retry", config).await;
+        flow.process("no-retry", "value", int(0)).await
+    });
+    crate::engine::set_engine(new_engine());
+    let err = result.unwrap_err();
+    assert!(err.to_string().contains("broken"), "{}", err);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn retry_delays_double_up_to_a_cap() {
+    let backoff = Duration::from_millis(100);
+    assert_eq!(retry_delay(backoff, 0), backoff);
+    assert_eq!(retry_delay(backoff, 3), Duration::from_millis(800));
+    assert_eq!(retry_delay(backoff, 31), MAX_RETRY_DELAY);
+    assert_eq!(retry_delay(backoff, 32), MAX_RETRY_DELAY);
+    assert_eq!(retry_delay(Duration::MAX, 1), MAX_RETRY_DELAY);
+}
+
+/// Make the current engine one with `slow_len`, backed by an async function.
+fn set_async_engine() {
+    async fn slow_len(s: String) -> rhai::INT {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        s.len() as rhai::INT
+    }
+    let mut engine = new_engine();
+    engine.register_fn("slow_len", |s: &str| {
+        crate::engine::block_on(slow_len(s.to_string()))
+    });
+    crate::engine::set_engine(engine);
+}
+
+async fn call_async_function(id: &str) {
+    let flow = TestFlow::new().await;
+    let probe = script_agent(&flow, id, json!({"script": "slow_len(value)"})).await;
+    flow.process(id, "value", value(json!("abcd")))
+        .await
+        .unwrap();
+    assert_eq!(probe.recv().await, int(4));
+}
+
+#[test]
+fn scripts_call_async_functions_on_a_multi_thread_runtime() {
+    let _globals = lock_globals();
+    set_async_engine();
+    block_on(call_async_function("async-multi"));
+    crate::engine::set_engine(new_engine());
+}
+
+#[test]
+fn scripts_call_async_functions_on_a_current_thread_runtime() {
+    let _globals = lock_globals();
+    set_async_engine();
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(call_async_function("async-current"));
+    crate::engine::set_engine(new_engine());
+}
@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use agent_stream_kit::AgentError;
+use rhai::{AST, Engine};
+
+use crate::error::compile_error;
+
+/// Maximum number of compiled scripts kept in the shared cache.
+const AST_CACHE_CAPACITY: usize = 256;
+
+/// Compiled scripts by source.
+///
+/// An entry is keyed on the whole source, or its normalized form, rather than
+/// a digest of it, so scripts that compile differently never share an AST.
+/// It's also keyed on the generation of the engine that compiled it, so an
+/// AST still being compiled by an engine that has since been replaced is never
+/// handed out for the new one. The cache is cleared when it grows beyond its
+/// capacity.
+pub(crate) struct AstCache {
+    capacity: usize,
+    asts: Mutex<HashMap<(u64, bool, String), Arc<AST>>>,
+}
+
+impl AstCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            asts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        self.asts.lock().unwrap().clear();
+    }
+
+    /// Return the AST cached for `script`, or cache the one `compile` makes.
+    ///
+    /// When `normalize` is set, the cache is keyed on the source with comments
+    /// removed and whitespace collapsed, so formatting-only edits don't cause
+    /// a recompile. `compile` is always given the original source to compile,
+    /// but the AST is shared with the first of such edits to be compiled, so
+    /// error and `debug` positions are those of that edit's source.
+    ///
+    /// `generation` tells apart the engines `compile` may use, see
+    /// [`get_engine_generation`](crate::engine::get_engine_generation).
+    pub(crate) fn get_or_compile(
+        &self,
+        generation: u64,
+        script: &str,
+        normalize: bool,
+        compile: impl FnOnce() -> Result<AST, AgentError>,
+    ) -> Result<Arc<AST>, AgentError> {
+        let source = if normalize {
+            normalize_source(script)
+        } else {
+            script.to_string()
+        };
+        let key = (generation, normalize, source);
+        if let Some(ast) = self.asts.lock().unwrap().get(&key) {
+            return Ok(ast.clone());
+        }
+
+        let ast = Arc::new(compile()?);
+
+        let mut asts = self.asts.lock().unwrap();
+        if asts.len() >= self.capacity {
+            asts.clear();
+        }
+        asts.insert(key, ast.clone());
+        Ok(ast)
+    }
+}
+
+static AST_CACHE: OnceLock<AstCache> = OnceLock::new();
+
+fn ast_cache() -> &'static AstCache {
+    AST_CACHE.get_or_init(|| AstCache::new(AST_CACHE_CAPACITY))
+}
+
+pub(crate) fn clear_ast_cache() {
+    ast_cache().clear();
+}
+
+/// Compile `script` with `engine` of `generation`, reusing the AST compiled
+/// for the same source by any agent. See [`AstCache::get_or_compile`] for
+/// `normalize`.
+pub(crate) fn compile_cached(
+    engine: &Engine,
+    generation: u64,
+    script: &str,
+    normalize: bool,
+) -> Result<Arc<AST>, AgentError> {
+    ast_cache().get_or_compile(generation, script, normalize, || {
+        engine.compile(script).map_err(compile_error)
+    })
+}
+
+/// Strip comments and collapse whitespace outside of literals.
+///
+/// Whitespace runs (including removed comments) are replaced by a single
+/// space rather than dropped, so tokens are never joined together and two
+/// sources only normalize to the same string when they tokenize the same.
+pub(crate) fn normalize_source(script: &str) -> String {
+    let chars: Vec<char> = script.chars().collect();
+    let mut out = String::with_capacity(script.len());
+    let mut i = 0;
+
+    // Keep a shebang line as is
+    if chars.starts_with(&['#', '!']) {
+        while i < chars.len() && chars[i] != '\n' {
+            out.push(chars[i]);
+            i += 1;
+        }
+    }
+
+    normalize_code(&chars, &mut i, &mut out, false);
+    out.trim().to_string()
+}
+
+// Normalize code until the end of input, or until the closing `}` of an
+// interpolation when `in_interpolation` is set.
+fn normalize_code(chars: &[char], i: &mut usize, out: &mut String, in_interpolation: bool) {
+    let mut depth = 0usize;
+    let mut pending_space = false;
+
+    while *i < chars.len() {
+        let c = chars[*i];
+
+        if c.is_whitespace() {
+            pending_space = true;
+            *i += 1;
+            continue;
+        }
+        if c == '/' && chars.get(*i + 1) == Some(&'/') {
+            while *i < chars.len() && chars[*i] != '\n' {
+                *i += 1;
+            }
+            pending_space = true;
+            continue;
+        }
+        if c == '/' && chars.get(*i + 1) == Some(&'*') {
+            skip_block_comment(chars, i);
+            pending_space = true;
+            continue;
+        }
+
+        if pending_space && !out.is_empty() {
+            out.push(' ');
+        }
+        pending_space = false;
+
+        match c {
+            '"' | '\'' => copy_quoted(chars, i, out, c),
+            '`' => copy_backtick(chars, i, out),
+            '#' if is_raw_string_start(chars, *i) => copy_raw_string(chars, i, out),
+            '{' => {
+                depth += 1;
+                out.push(c);
+                *i += 1;
+            }
+            '}' => {
+                if in_interpolation && depth == 0 {
+                    return;
+                }
+                depth = depth.saturating_sub(1);
+                out.push(c);
+                *i += 1;
+            }
+            _ => {
+                out.push(c);
+                *i += 1;
+            }
+        }
+    }
+}
+
+// Rhai block comments nest.
+fn skip_block_comment(chars: &[char], i: &mut usize) {
+    let mut depth = 0usize;
+    while *i < chars.len() {
+        if chars[*i] == '/' && chars.get(*i + 1) == Some(&'*') {
+            depth += 1;
+            *i += 2;
+        } else if chars[*i] == '*' && chars.get(*i + 1) == Some(&'/') {
+            depth -= 1;
+            *i += 2;
+            if depth == 0 {
+                return;
+            }
+        } else {
+            *i += 1;
+        }
+    }
+}
+
+fn copy_quoted(chars: &[char], i: &mut usize, out: &mut String, quote: char) {
+    out.push(quote);
+    *i += 1;
+    while *i < chars.len() {
+        let c = chars[*i];
+        out.push(c);
+        *i += 1;
+        if c == '\\' {
+            if let Some(&next) = chars.get(*i) {
+                out.push(next);
+                *i += 1;
+            }
+        } else if c == quote {
+            return;
+        }
+    }
+}
+
+// Backtick strings may contain `${...}` interpolations, which are code.
+fn copy_backtick(chars: &[char], i: &mut usize, out: &mut String) {
+    out.push('`');
+    *i += 1;
+    while *i < chars.len() {
+        let c = chars[*i];
+        if c == '`' {
+            out.push(c);
+            *i += 1;
+            return;
+        }
+        if c == '$' && chars.get(*i + 1) == Some(&'{') {
+            out.push_str("${");
+            *i += 2;
+            normalize_code(chars, i, out, true);
+            if *i < chars.len() {
+                out.push('}');
+                *i += 1;
+            }
+            continue;
+        }
+        out.push(c);
+        *i += 1;
+    }
+}
+
+fn is_raw_string_start(chars: &[char], i: usize) -> bool {
+    let mut j = i;
+    while chars.get(j) == Some(&'#') {
+        j += 1;
+    }
+    chars.get(j) == Some(&'"')
+}
+
+// Raw strings look like `#"..."#`, with any number of matching `#`.
+fn copy_raw_string(chars: &[char], i: &mut usize, out: &mut String) {
+    let mut hashes = 0;
+    while chars[*i] == '#' {
+        out.push('#');
+        hashes += 1;
+        *i += 1;
+    }
+    out.push('"');
+    *i += 1;
+    while *i < chars.len() {
+        let c = chars[*i];
+        out.push(c);
+        *i += 1;
+        if c == '"'
+            && chars[*i..]
+                .iter()
+                .take(hashes)
+                .filter(|&&h| h == '#')
+                .count()
+                == hashes
+        {
+            for _ in 0..hashes {
+                out.push('#');
+            }
+            *i += hashes;
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rhai::INT;
+
+    use super::*;
+
+    fn compile(cache: &AstCache, script: &str, normalize: bool) -> Arc<AST> {
+        let engine = Engine::new();
+        cache
+            .get_or_compile(0, script, normalize, || {
+                engine.compile(script).map_err(compile_error)
+            })
+            .unwrap()
+    }
+
+    fn eval(ast: &AST) -> INT {
+        Engine::new().eval_ast::<INT>(ast).unwrap()
+    }
+
+    #[test]
+    fn equal_sources_share_an_ast() {
+        let cache = AstCache::new(8);
+        for normalize in [false, true] {
+            let a = compile(&cache, "let x = 1; x + 1", normalize);
+            let b = compile(&cache, "let x = 1; x + 1", normalize);
+            assert!(Arc::ptr_eq(&a, &b));
+        }
+    }
+
+    #[test]
+    fn comment_only_edits_hit_only_when_normalizing() {
+        let cache = AstCache::new(8);
+        let plain = "let x = 1;\nx + 1";
+        let commented = "// add one\nlet x = 1;   /* the input */\nx + 1  ";
+
+        let a = compile(&cache, plain, false);
+        let b = compile(&cache, commented, false);
+        assert!(!Arc::ptr_eq(&a, &b));
+
+        let a = compile(&cache, plain, true);
+        let b = compile(&cache, commented, true);
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(eval(&b), 2);
+    }
+
+    #[test]
+    fn different_sources_never_share_an_ast() {
+        let cache = AstCache::new(8);
+        let scripts = ["1 + 1", "1 + 2", r#""a b".len()"#, r#""a  b".len()"#];
+        for normalize in [false, true] {
+            let asts: Vec<_> = scripts
+                .iter()
+                .map(|script| compile(&cache, script, normalize))
+                .collect();
+            let results: Vec<_> = asts.iter().map(|ast| eval(ast)).collect();
+            assert_eq!(results, [2, 3, 3, 4]);
+            for (i, a) in asts.iter().enumerate() {
+                for b in &asts[i + 1..] {
+                    assert!(!Arc::ptr_eq(a, b));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn engine_generations_never_share_an_ast() {
+        let cache = AstCache::new(8);
+        let engine = Engine::new();
+        let compile = |generation| {
+            cache
+                .get_or_compile(generation, "1 + 1", false, || {
+                    engine.compile("1 + 1").map_err(compile_error)
+                })
+                .unwrap()
+        };
+        let old = compile(1);
+        assert!(!Arc::ptr_eq(&old, &compile(2)));
+        assert!(Arc::ptr_eq(&old, &compile(1)));
+    }
+
+    #[test]
+    fn compile_errors_are_not_cached() {
+        let cache = AstCache::new(8);
+        let engine = Engine::new();
+        let mut attempts = 0;
+        for _ in 0..2 {
+            let result = cache.get_or_compile(0, "1 +", false, || {
+                attempts += 1;
+                engine.compile("1 +").map_err(compile_error)
+            });
+            assert!(result.is_err());
+        }
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn full_cache_is_cleared() {
+        let cache = AstCache::new(2);
+        let first = compile(&cache, "1", false);
+        compile(&cache, "2", false);
+        compile(&cache, "3", false);
+        assert!(!Arc::ptr_eq(&first, &compile(&cache, "1", false)));
+    }
+
+    #[test]
+    fn normalize_collapses_whitespace_and_comments() {
+        assert_eq!(
+            normalize_source("let x =  1; // one\n/* outer /* inner */ */ x"),
+            "let x = 1; x"
+        );
+        assert_eq!(normalize_source("#!/bin/rhai\n  1"), "#!/bin/rhai 1");
+    }
+
+    #[test]
+    fn normalize_keeps_literals() {
+        let scripts = [
+            r#""a  // b""#,
+            "'/'",
+            r##"#"a  /* b */ c"#"##,
+            "`x  ${ 1  +  2 }  // y`",
+        ];
+        let expected = [
+            r#""a  // b""#,
+            "'/'",
+            r##"#"a  /* b */ c"#"##,
+            "`x  ${ 1 + 2 }  // y`",
+        ];
+        for (script, expected) in scripts.iter().zip(expected) {
+            assert_eq!(normalize_source(script), expected);
+        }
+    }
+}
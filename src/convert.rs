@@ -0,0 +1,978 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use agent_stream_kit::{AgentError, AgentValue, AgentValueMap};
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rhai::{Dynamic, FLOAT, INT};
+
+/// Key of the object that stands in for an opaque Rhai value, such as a function pointer.
+static OPAQUE_KEY: &str = "__rhai_opaque__";
+
+/// Key of the object that stands in for a blob, holding its bytes in base64.
+static BLOB_KEY: &str = "__rhai_blob__";
+
+/// Maximum number of opaque values retained for each flow. The oldest are
+/// dropped first.
+const OPAQUE_CAPACITY: usize = 1024;
+
+/// Opaque values kept in this process, referenced by handle from `AgentValue`s.
+///
+/// Values such as closures (`FnPtr`) can't be represented as an `AgentValue`,
+/// so they are stored here and passed along as `#{ __rhai_opaque__: handle }`.
+/// Handles are random, so they can't be guessed from another one, and each
+/// value can only be restored within the flow that stored it. They can only
+/// be restored within the same process, and a closure is only meaningful to
+/// the engine that created it.
+///
+/// Each flow keeps its latest [`OPAQUE_CAPACITY`] values, so a busy flow
+/// doesn't drop the values of another. A value still in flight once its flow
+/// has stored that many newer ones fails to load. A flow's values are dropped
+/// altogether once every agent holding an [`OpaqueFlow`] for it has stopped.
+struct OpaqueStore {
+    capacity: usize,
+    /// Values stored outside of any flow.
+    unscoped: OpaqueValues,
+    flows: HashMap<Arc<str>, OpaqueValues>,
+}
+
+/// The opaque values of one flow, and their handles from oldest to newest.
+#[derive(Default)]
+struct OpaqueValues {
+    values: HashMap<String, Dynamic>,
+    order: VecDeque<String>,
+    /// Number of [`OpaqueFlow`]s held for the flow.
+    holders: usize,
+}
+
+impl OpaqueStore {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            unscoped: OpaqueValues::default(),
+            flows: HashMap::new(),
+        }
+    }
+
+    fn values(&self, scope: Option<&str>) -> Option<&OpaqueValues> {
+        match scope {
+            Some(flow) => self.flows.get(flow),
+            None => Some(&self.unscoped),
+        }
+    }
+
+    fn store(&mut self, scope: Option<Arc<str>>, value: Dynamic) -> Result<String, AgentError> {
+        let mut bytes = [0u8; 16];
+        getrandom::fill(&mut bytes).map_err(|e| {
+            AgentError::InvalidValue(format!("Failed to create an opaque handle: {}", e))
+        })?;
+        let handle = format!("{:032x}", u128::from_ne_bytes(bytes));
+        let flow = match scope {
+            Some(flow) => self.flows.entry(flow).or_default(),
+            None => &mut self.unscoped,
+        };
+        if flow.order.len() >= self.capacity
+            && let Some(old) = flow.order.pop_front()
+        {
+            flow.values.remove(&old);
+        }
+        flow.values.insert(handle.clone(), value);
+        flow.order.push_back(handle.clone());
+        Ok(handle)
+    }
+
+    fn load(&self, scope: Option<&str>, handle: &AgentValue) -> Result<Dynamic, AgentError> {
+        handle
+            .as_str()
+            .and_then(|handle| self.values(scope)?.values.get(handle))
+            .cloned()
+            .ok_or_else(|| {
+                AgentError::InvalidValue(format!(
+                    "Unknown opaque value: it belongs to another flow, or was dropped after its flow stored {} newer ones or stopped",
+                    self.capacity
+                ))
+            })
+    }
+
+    fn hold(&mut self, flow: Arc<str>) {
+        self.flows.entry(flow).or_default().holders += 1;
+    }
+
+    fn release(&mut self, flow: &str) {
+        if let Some(values) = self.flows.get_mut(flow) {
+            values.holders = values.holders.saturating_sub(1);
+            if values.holders == 0 {
+                self.flows.remove(flow);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self, scope: Option<&str>) -> usize {
+        self.values(scope).map_or(0, |flow| flow.order.len())
+    }
+}
+
+static OPAQUE_STORE: OnceLock<Mutex<OpaqueStore>> = OnceLock::new();
+
+fn opaque_store() -> &'static Mutex<OpaqueStore> {
+    OPAQUE_STORE.get_or_init(|| Mutex::new(OpaqueStore::new(OPAQUE_CAPACITY)))
+}
+
+thread_local! {
+    static OPAQUE_SCOPE: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// Scopes the opaque values stored and loaded on this thread to a flow while
+/// it's held. Outside of any scope, only values stored outside of one load.
+///
+/// Conversions are synchronous, so the scope is tracked per thread. The guard
+/// isn't `Send`, so holding it across an `.await` in an agent fails to compile
+/// rather than leaking the scope to another task.
+pub(crate) struct OpaqueScope {
+    prev: Option<Arc<str>>,
+    _not_send: PhantomData<*const ()>,
+}
+
+pub(crate) fn opaque_scope(scope: &str) -> OpaqueScope {
+    let prev = OPAQUE_SCOPE.with(|s| s.replace(Some(scope.into())));
+    OpaqueScope {
+        prev,
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for OpaqueScope {
+    fn drop(&mut self) {
+        OPAQUE_SCOPE.with(|s| *s.borrow_mut() = self.prev.take());
+    }
+}
+
+/// Keeps the opaque values of a flow while it's held. Agents hold one for
+/// their flow while they run, so the values of a flow are dropped once all of
+/// its agents have stopped.
+pub(crate) struct OpaqueFlow(Arc<str>);
+
+pub(crate) fn hold_opaque_flow(flow: &str) -> OpaqueFlow {
+    let flow: Arc<str> = flow.into();
+    opaque_store().lock().unwrap().hold(flow.clone());
+    OpaqueFlow(flow)
+}
+
+impl Drop for OpaqueFlow {
+    fn drop(&mut self) {
+        opaque_store().lock().unwrap().release(&self.0);
+    }
+}
+
+fn store_opaque(value: Dynamic) -> Result<AgentValue, AgentError> {
+    let scope = OPAQUE_SCOPE.with(|s| s.borrow().clone());
+    let handle = opaque_store().lock().unwrap().store(scope, value)?;
+    let mut map = AgentValueMap::new();
+    map.insert(OPAQUE_KEY.to_string(), AgentValue::string(handle));
+    Ok(AgentValue::object(map))
+}
+
+/// The opaque value `map` stands in for, or `None` if it's a plain object.
+fn load_opaque(map: &AgentValueMap<String, AgentValue>) -> Option<Result<Dynamic, AgentError>> {
+    if map.len() != 1 {
+        return None;
+    }
+    let handle = map.get(OPAQUE_KEY)?;
+    let store = opaque_store().lock().unwrap();
+    Some(OPAQUE_SCOPE.with(|s| store.load(s.borrow().as_deref(), handle)))
+}
+
+/// A blob as `#{ __rhai_blob__: base64 }`, since `AgentValue` has no binary
+/// type. It converts back to a blob in the next script, while an array of
+/// bytes stays an array.
+pub(crate) fn blob_to_value(blob: &[u8]) -> AgentValue {
+    let mut map = AgentValueMap::new();
+    map.insert(
+        BLOB_KEY.to_string(),
+        AgentValue::string(BASE64.encode(blob)),
+    );
+    AgentValue::object(map)
+}
+
+/// The blob `map` stands in for, or `None` if it's a plain object. An object
+/// whose tag isn't valid base64 didn't come from a blob, so it stays an object.
+fn load_blob(map: &AgentValueMap<String, AgentValue>) -> Option<Dynamic> {
+    if map.len() != 1 {
+        return None;
+    }
+    let encoded = map.get(BLOB_KEY)?.as_str()?;
+    BASE64.decode(encoded).ok().map(Dynamic::from_blob)
+}
+
+/// Location of a nested value, used to point at the element that failed to convert.
+/// It is only rendered when an error is reported.
+#[derive(Clone, Copy)]
+enum Path<'a> {
+    Root,
+    Index(&'a Path<'a>, usize),
+    Key(&'a Path<'a>, &'a str),
+}
+
+impl fmt::Display for Path<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Path::Root => write!(f, "root"),
+            Path::Index(parent, i) => write!(f, "{}[{}]", parent, i),
+            Path::Key(parent, k) => write!(f, "{}.{}", parent, k),
+        }
+    }
+}
+
+/// Name of the type of an `AgentValue`, such as `"integer"` or `"object"`.
+pub(crate) fn type_tag(value: &AgentValue) -> &'static str {
+    match value {
+        AgentValue::Unit => "unit",
+        AgentValue::Boolean(_) => "boolean",
+        AgentValue::Integer(_) => "integer",
+        AgentValue::Number(_) => "number",
+        AgentValue::String(_) => "string",
+        AgentValue::Array(_) => "array",
+        AgentValue::Object(_) => "object",
+        // Only with agent-stream-kit's image feature
+        #[allow(unreachable_patterns)]
+        _ => "image",
+    }
+}
+
+/// Convert an `AgentValue` for use in a script.
+///
+/// `AgentValue` has no null separate from unit: a JSON `null` is parsed as
+/// `AgentValue::Unit`, which becomes Rhai's `()` and converts back to unit
+/// (and to `null` in JSON). A missing object key also reads as `()` in Rhai,
+/// so scripts that need to tell a missing key from a null one should use
+/// `"key" in value`.
+///
+/// Objects standing in for values `AgentValue` can't hold are restored: blobs
+/// from `#{ __rhai_blob__: base64 }` and opaque values from
+/// `#{ __rhai_opaque__: handle }`.
+pub(crate) fn from_value_to_dynamic(value: AgentValue) -> Result<Dynamic, AgentError> {
+    match value {
+        AgentValue::Unit => Ok(().into()),
+        AgentValue::Boolean(b) => Ok(Dynamic::from(b)),
+        // Rhai's INT is i32 with the `only_i32` feature
+        AgentValue::Integer(i) => INT::try_from(i)
+            .map(Dynamic::from_int)
+            .map_err(|_| AgentError::InvalidValue(format!("Integer {} out of range for Rhai", i))),
+        // and FLOAT is f32 with the `f32_float` feature, losing precision
+        AgentValue::Number(f) => {
+            #[allow(clippy::unnecessary_cast)] // FLOAT may be f64
+            let narrowed = f as FLOAT;
+            if f.is_finite() && !narrowed.is_finite() {
+                return Err(AgentError::InvalidValue(format!(
+                    "Number {} out of range for Rhai",
+                    f
+                )));
+            }
+            Ok(Dynamic::from_float(narrowed))
+        }
+        // Values only this conversion holds are moved rather than copied
+        AgentValue::String(s) => Ok(Dynamic::from(Arc::unwrap_or_clone(s))),
+        AgentValue::Array(arr) => Ok(Dynamic::from_array(from_values_to_dynamic(
+            Arc::unwrap_or_clone(arr),
+        )?)),
+        AgentValue::Object(map) => {
+            if let Some(d) = load_opaque(&map).or_else(|| load_blob(&map).map(Ok)) {
+                return d;
+            }
+            // Both maps are B-trees, which can't be sized up front; collecting
+            // the entries, already sorted, builds the tree in one pass rather
+            // than searching it for each key
+            let dyn_map = Arc::unwrap_or_clone(map)
+                .into_iter()
+                .map(|(k, v)| Ok((k.into(), from_value_to_dynamic(v)?)))
+                .collect::<Result<rhai::Map, AgentError>>()?;
+            Ok(Dynamic::from_map(dyn_map))
+        }
+
+        // Just store AgentValue directly
+        _ => Ok(Dynamic::from(value)),
+    }
+}
+
+/// Convert a list of values in one pass, such as a batch collected by an agent,
+/// without first wrapping them into an `AgentValue` array.
+pub(crate) fn from_values_to_dynamic(values: Vec<AgentValue>) -> Result<rhai::Array, AgentError> {
+    let mut dyn_arr = rhai::Array::with_capacity(values.len());
+    for v in values {
+        dyn_arr.push(from_value_to_dynamic(v)?);
+    }
+    Ok(dyn_arr)
+}
+
+/// Options applied when converting a script result into an `AgentValue`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConvertOptions {
+    /// Round numbers to this many decimal places.
+    pub round: Option<i32>,
+
+    /// Also round numbers nested in arrays and objects, not just a numeric result.
+    pub round_recursive: bool,
+
+    /// Describe opaque values rather than storing them, for a conversion that
+    /// only measures or compares a value and is never restored.
+    pub describe_opaque: bool,
+}
+
+impl ConvertOptions {
+    fn round(&self, value: f64, path: &Path) -> f64 {
+        let Some(decimals) = self.round else {
+            return value;
+        };
+        if !self.round_recursive && !matches!(path, Path::Root) {
+            return value;
+        }
+        let factor = 10f64.powi(decimals);
+        let rounded = (value * factor).round() / factor;
+        if rounded.is_finite() { rounded } else { value }
+    }
+}
+
+pub(crate) fn from_dynamic_to_value(value: &Dynamic) -> Result<AgentValue, AgentError> {
+    dynamic_to_value_at(value, &Path::Root, &ConvertOptions::default())
+}
+
+pub(crate) fn from_dynamic_to_value_with(
+    value: &Dynamic,
+    opts: &ConvertOptions,
+) -> Result<AgentValue, AgentError> {
+    dynamic_to_value_at(value, &Path::Root, opts)
+}
+
+fn dynamic_to_value_at(
+    value: &Dynamic,
+    path: &Path,
+    opts: &ConvertOptions,
+) -> Result<AgentValue, AgentError> {
+    // A value shared with a closure is read once, rather than through its
+    // lock by every check below, and an opaque value is stored as a snapshot
+    // that the script can no longer change.
+    if value.is_shared() {
+        return dynamic_to_value_at(&value.flatten_clone(), path, opts);
+    }
+    if value.is_unit() {
+        return Ok(AgentValue::unit());
+    }
+    if value.is_bool() {
+        let value = value
+            .as_bool()
+            .map_err(|e| AgentError::InvalidValue(format!("Failed as_bool at {}: {}", path, e)))?;
+        return Ok(AgentValue::boolean(value));
+    }
+    if value.is_int() {
+        let value = value
+            .as_int()
+            .map_err(|e| AgentError::InvalidValue(format!("Failed as_int at {}: {}", path, e)))?;
+        #[allow(clippy::useless_conversion)] // INT may be i32
+        let value = i64::from(value);
+        return Ok(AgentValue::integer(value));
+    }
+    if value.is_float() {
+        let value = value
+            .as_float()
+            .map_err(|e| AgentError::InvalidValue(format!("Failed as_float at {}: {}", path, e)))?;
+        #[allow(clippy::useless_conversion)] // FLOAT may be f32
+        let value = f64::from(value);
+        return Ok(AgentValue::number(opts.round(value, path)));
+    }
+    if value.is_string() {
+        let value = value.clone().into_string().map_err(|e| {
+            AgentError::InvalidValue(format!("Failed into_string at {}: {}", path, e))
+        })?;
+        return Ok(AgentValue::string(value));
+    }
+
+    if value.is_array() {
+        let arr = value.as_array_ref().map_err(|e| {
+            AgentError::InvalidValue(format!("Failed as_array_ref at {}: {}", path, e))
+        })?;
+        let mut value_array: Vec<AgentValue> = Vec::with_capacity(arr.len());
+        for (i, v) in arr.iter().enumerate() {
+            let d = dynamic_to_value_at(v, &Path::Index(path, i), opts)?;
+            value_array.push(d);
+        }
+        return Ok(AgentValue::array(value_array));
+    }
+
+    // Rhai map keys are always strings, so keys such as "1" stay strings and
+    // round-trip unchanged; they never become integers.
+    if value.is_map() {
+        let map = value.as_map_ref().map_err(|e| {
+            AgentError::InvalidValue(format!("Failed as_map_ref at {}: {}", path, e))
+        })?;
+        // Collected for the same reason as in from_value_to_dynamic
+        let value_map = map
+            .iter()
+            .map(|(k, v)| {
+                Ok((
+                    k.to_string(),
+                    dynamic_to_value_at(v, &Path::Key(path, k), opts)?,
+                ))
+            })
+            .collect::<Result<AgentValueMap<_, _>, AgentError>>()?;
+        return Ok(AgentValue::object(value_map));
+    }
+
+    if let Some(blob) = value.read_lock::<rhai::Blob>() {
+        return Ok(blob_to_value(&blob));
+    }
+
+    if value.is::<AgentValue>() {
+        let value = value.clone().cast::<AgentValue>();
+        return Ok(value);
+    }
+
+    if value.is_fnptr() {
+        if opts.describe_opaque {
+            let mut map = AgentValueMap::new();
+            map.insert(
+                OPAQUE_KEY.to_string(),
+                AgentValue::string(format!("{:?}", value)),
+            );
+            return Ok(AgentValue::object(map));
+        }
+        return store_opaque(value.clone());
+    }
+
+    Err(AgentError::InvalidValue(format!(
+        "Unsupported Rhai data type at {}: {}",
+        path,
+        value.type_name()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use rhai::{Engine, INT, Scope};
+
+    use super::*;
+
+    #[test]
+    fn errors_name_the_failing_path() {
+        let mut meta = rhai::Map::new();
+        meta.insert("meta".into(), Dynamic::from(Instant::now()));
+        let items: rhai::Array = vec![1.into(), 2.into(), 3.into(), meta.into()];
+        let mut root = rhai::Map::new();
+        root.insert("items".into(), items.into());
+
+        let err = from_dynamic_to_value(&root.into()).unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("Unsupported Rhai data type"),
+            "{}",
+            message
+        );
+        assert!(message.contains("at root.items[3].meta:"), "{}", message);
+    }
+
+    #[test]
+    fn errors_at_the_top_level_name_the_root() {
+        let err = from_dynamic_to_value(&Dynamic::from(Instant::now())).unwrap_err();
+        assert!(err.to_string().contains("at root:"), "{}", err);
+    }
+
+    fn closure(engine: &Engine) -> Dynamic {
+        engine.eval::<Dynamic>("|x| x + 1").unwrap()
+    }
+
+    #[test]
+    fn closures_round_trip_within_a_flow() {
+        let engine = Engine::new();
+        let passed = {
+            let _scope = opaque_scope("flow-a");
+            from_dynamic_to_value(&closure(&engine)).unwrap()
+        };
+        let handle = passed.get(OPAQUE_KEY).and_then(|h| h.as_str()).unwrap();
+        assert_eq!(handle.len(), 32);
+
+        // A later stage of the same flow calls it
+        let _scope = opaque_scope("flow-a");
+        let mut scope = Scope::new();
+        scope.push("f", from_value_to_dynamic(passed).unwrap());
+        let out = engine
+            .eval_with_scope::<INT>(&mut scope, "f.call(2)")
+            .unwrap();
+        assert_eq!(out, 3);
+    }
+
+    #[test]
+    fn other_flows_cannot_load_a_closure() {
+        let engine = Engine::new();
+        let passed = {
+            let _scope = opaque_scope("flow-b");
+            from_dynamic_to_value(&closure(&engine)).unwrap()
+        };
+        for scope in [Some("flow-c"), None] {
+            let _scope = scope.map(opaque_scope);
+            let err = from_value_to_dynamic(passed.clone()).unwrap_err();
+            assert!(err.to_string().contains("Unknown opaque value"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn guessed_handles_are_rejected() {
+        let _scope = opaque_scope("flow-d");
+        for handle in [
+            AgentValue::integer(1),
+            AgentValue::string("00000000000000000000000000000001"),
+        ] {
+            let mut map = AgentValueMap::new();
+            map.insert(OPAQUE_KEY.to_string(), handle);
+            assert!(from_value_to_dynamic(AgentValue::object(map)).is_err());
+        }
+
+        // An object with other keys is a plain object
+        let mut map = AgentValueMap::new();
+        map.insert(OPAQUE_KEY.to_string(), AgentValue::integer(1));
+        map.insert("other".to_string(), AgentValue::integer(2));
+        assert!(
+            from_value_to_dynamic(AgentValue::object(map))
+                .unwrap()
+                .is_map()
+        );
+    }
+
+    #[test]
+    fn handles_differ_for_each_value() {
+        let mut store = OpaqueStore::new(8);
+        let a = store.store(None, Dynamic::UNIT).unwrap();
+        let b = store.store(None, Dynamic::UNIT).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn evicted_values_fail_to_load() {
+        let mut store = OpaqueStore::new(2);
+        let scope: Option<Arc<str>> = Some("flow".into());
+        let handles: Vec<_> = (0..3)
+            .map(|i| store.store(scope.clone(), Dynamic::from_int(i)).unwrap())
+            .collect();
+
+        let load = |handle: &str| store.load(Some("flow"), &AgentValue::string(handle));
+        let err = load(&handles[0]).unwrap_err();
+        assert!(err.to_string().contains("stored 2 newer ones"), "{}", err);
+        assert_eq!(load(&handles[1]).unwrap().as_int().unwrap(), 1);
+        assert_eq!(load(&handles[2]).unwrap().as_int().unwrap(), 2);
+    }
+
+    #[test]
+    fn flows_only_evict_their_own_values() {
+        let mut store = OpaqueStore::new(2);
+        let kept = store
+            .store(Some("quiet".into()), Dynamic::from_int(1))
+            .unwrap();
+        for i in 0..10 {
+            store
+                .store(Some("busy".into()), Dynamic::from_int(i))
+                .unwrap();
+        }
+        assert_eq!(store.len(Some("busy")), 2);
+        let loaded = store
+            .load(Some("quiet"), &AgentValue::string(kept))
+            .unwrap();
+        assert_eq!(loaded.as_int().unwrap(), 1);
+    }
+
+    #[test]
+    fn stopped_flows_drop_their_values() {
+        let mut store = OpaqueStore::new(8);
+        store.hold("stopping".into());
+        store.hold("stopping".into());
+        let handle = store
+            .store(Some("stopping".into()), Dynamic::from_int(1))
+            .unwrap();
+
+        // The values stay while any agent of the flow runs
+        store.release("stopping");
+        assert_eq!(store.len(Some("stopping")), 1);
+        store.release("stopping");
+        assert!(!store.flows.contains_key("stopping"));
+        let err = store
+            .load(Some("stopping"), &AgentValue::string(handle))
+            .unwrap_err();
+        assert!(err.to_string().contains("Unknown opaque value"), "{}", err);
+    }
+
+    #[test]
+    fn measuring_the_state_stores_no_opaque_values() {
+        use crate::state::{StateLimits, StateStore};
+
+        let engine = Engine::new();
+        let _scope = opaque_scope("measured-state");
+        let mut state = StateStore::default();
+        state.limits = StateLimits {
+            max_entries: 10,
+            ..Default::default()
+        };
+        for _ in 0..5 {
+            let mut map = rhai::Map::new();
+            map.insert("f".into(), closure(&engine));
+            state.update("measured-state", map).unwrap();
+        }
+        let store = opaque_store().lock().unwrap();
+        assert_eq!(store.len(Some("measured-state")), 0);
+    }
+
+    fn nested() -> Dynamic {
+        let engine = Engine::new();
+        engine
+            .eval::<Dynamic>("#{ total: 1.234375, items: [#{ price: 2.71828 }, 3] }")
+            .unwrap()
+    }
+
+    #[test]
+    fn rounding_applies_to_nested_numbers() {
+        let opts = ConvertOptions {
+            round: Some(2),
+            round_recursive: true,
+            ..Default::default()
+        };
+        let value = from_dynamic_to_value_with(&nested(), &opts).unwrap();
+        assert_eq!(
+            value.to_json(),
+            serde_json::json!({"total": 1.23, "items": [{"price": 2.72}, 3]})
+        );
+    }
+
+    #[test]
+    fn rounding_can_be_limited_to_the_result() {
+        let opts = ConvertOptions {
+            round: Some(1),
+            round_recursive: false,
+            ..Default::default()
+        };
+        let value = from_dynamic_to_value_with(&nested(), &opts).unwrap();
+        assert_eq!(value.get("total").unwrap().as_f64(), Some(1.234375));
+
+        let value = from_dynamic_to_value_with(&Dynamic::from_float(1.25), &opts).unwrap();
+        assert_eq!(value.as_f64(), Some(1.3));
+    }
+
+    #[test]
+    #[allow(clippy::unnecessary_cast)] // INT may be i64
+    fn integers_round_trip_within_range() {
+        for i in [0, -1, INT::MIN as i64, INT::MAX as i64] {
+            let d = from_value_to_dynamic(AgentValue::integer(i)).unwrap();
+            assert_eq!(from_dynamic_to_value(&d).unwrap(), AgentValue::integer(i));
+        }
+    }
+
+    #[test]
+    fn numbers_round_trip_when_representable() {
+        for f in [0.0, -0.5, 1.25, 1024.0] {
+            let d = from_value_to_dynamic(AgentValue::number(f)).unwrap();
+            assert_eq!(from_dynamic_to_value(&d).unwrap(), AgentValue::number(f));
+        }
+    }
+
+    #[cfg(not(feature = "only_i32"))]
+    #[test]
+    fn integers_are_64_bit() {
+        let d = from_value_to_dynamic(AgentValue::integer(i64::MAX)).unwrap();
+        assert_eq!(d.as_int().unwrap(), i64::MAX);
+    }
+
+    #[cfg(feature = "only_i32")]
+    #[test]
+    fn integers_beyond_32_bits_are_rejected() {
+        for i in [i32::MAX as i64 + 1, i32::MIN as i64 - 1] {
+            let err = from_value_to_dynamic(AgentValue::integer(i)).unwrap_err();
+            assert!(err.to_string().contains("out of range for Rhai"), "{}", err);
+        }
+    }
+
+    #[cfg(not(feature = "f32_float"))]
+    #[test]
+    fn numbers_are_64_bit() {
+        let d = from_value_to_dynamic(AgentValue::number(1e300)).unwrap();
+        assert_eq!(
+            from_dynamic_to_value(&d).unwrap(),
+            AgentValue::number(1e300)
+        );
+    }
+
+    #[cfg(feature = "f32_float")]
+    #[test]
+    fn numbers_beyond_32_bits_are_rejected() {
+        for f in [1e300, -1e300] {
+            let err = from_value_to_dynamic(AgentValue::number(f)).unwrap_err();
+            assert!(err.to_string().contains("out of range for Rhai"), "{}", err);
+        }
+        // Within range, only precision is lost
+        let d = from_value_to_dynamic(AgentValue::number(0.1)).unwrap();
+        let back = from_dynamic_to_value(&d).unwrap().as_f64().unwrap();
+        assert_eq!(back, 0.1f32 as f64);
+    }
+
+    #[test]
+    fn infinite_numbers_stay_infinite() {
+        let d = from_value_to_dynamic(AgentValue::number(f64::INFINITY)).unwrap();
+        assert!(d.as_float().unwrap().is_infinite());
+    }
+
+    #[test]
+    fn null_and_unit_are_the_same() {
+        let value = AgentValue::from_json(serde_json::json!(null)).unwrap();
+        assert!(value.is_unit());
+        let d = from_value_to_dynamic(value).unwrap();
+        assert!(d.is_unit());
+        let back = from_dynamic_to_value(&d).unwrap();
+        assert_eq!(back.to_json(), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn missing_keys_differ_from_null_ones_only_by_in() {
+        let value = AgentValue::from_json(serde_json::json!({"present": null})).unwrap();
+        let mut scope = Scope::new();
+        scope.push("value", from_value_to_dynamic(value).unwrap());
+        let out = Engine::new()
+            .eval_with_scope::<rhai::Array>(
+                &mut scope,
+                r#"[value.present == (), value.absent == (), "present" in value, "absent" in value]"#,
+            )
+            .unwrap();
+        let out: Vec<bool> = out.into_iter().map(|b| b.as_bool().unwrap()).collect();
+        assert_eq!(out, [true, true, true, false]);
+
+        // and both null and unit entries come back as null
+        let back = from_dynamic_to_value(&scope.get_value::<Dynamic>("value").unwrap()).unwrap();
+        assert_eq!(back.to_json(), serde_json::json!({"present": null}));
+    }
+
+    #[test]
+    fn blobs_round_trip_through_a_tagged_object() {
+        let blob: rhai::Blob = vec![0, 1, 254, 255];
+        let value = from_dynamic_to_value(&Dynamic::from_blob(blob.clone())).unwrap();
+        assert_eq!(
+            value.to_json(),
+            serde_json::json!({"__rhai_blob__": "AAH+/w=="})
+        );
+        let back = from_value_to_dynamic(value).unwrap();
+        assert_eq!(back.cast::<rhai::Blob>(), blob);
+
+        // Arrays of bytes stay arrays
+        let arr = Dynamic::from_array(vec![Dynamic::from_int(1), Dynamic::from_int(2)]);
+        let value = from_dynamic_to_value(&arr).unwrap();
+        assert_eq!(value.to_json(), serde_json::json!([1, 2]));
+        assert!(from_value_to_dynamic(value).unwrap().is_array());
+    }
+
+    #[test]
+    fn blob_objects_need_valid_base64() {
+        // Objects that only look like blobs pass through unchanged
+        let value =
+            AgentValue::from_json(serde_json::json!({"__rhai_blob__": "not base64!"})).unwrap();
+        let d = from_value_to_dynamic(value.clone()).unwrap();
+        assert!(d.is_map());
+        assert_eq!(from_dynamic_to_value(&d).unwrap(), value);
+
+        // Objects with more keys are plain objects
+        let value =
+            AgentValue::from_json(serde_json::json!({"__rhai_blob__": "AA==", "n": 1})).unwrap();
+        assert!(from_value_to_dynamic(value).unwrap().is_map());
+    }
+
+    #[test]
+    fn numeric_looking_keys_stay_strings() {
+        let value =
+            AgentValue::from_json(serde_json::json!({"1": "a", "02": "b", "-3": "c"})).unwrap();
+        let mut scope = Scope::new();
+        scope.push("value", from_value_to_dynamic(value.clone()).unwrap());
+        let result = Engine::new()
+            .eval_with_scope::<Dynamic>(&mut scope, r#"value["4"] = "d"; value"#)
+            .unwrap();
+        assert_eq!(
+            from_dynamic_to_value(&result).unwrap().to_json(),
+            serde_json::json!({"1": "a", "02": "b", "-3": "c", "4": "d"})
+        );
+    }
+
+    fn wide_object(keys: usize) -> serde_json::Value {
+        (0..keys)
+            .map(|i| {
+                (
+                    format!("key{}", i),
+                    serde_json::json!({"s": "text", "n": [i]}),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    #[test]
+    fn wide_objects_convert_intact() {
+        let json = wide_object(500);
+        // Both moved, when nothing else holds the value, and cloned
+        let owned = AgentValue::from_json(json.clone()).unwrap();
+        let shared = AgentValue::from_json(json.clone()).unwrap();
+        for value in [owned, shared.clone()] {
+            let d = from_value_to_dynamic(value).unwrap();
+            assert_eq!(d.as_map_ref().unwrap().len(), 500);
+            assert_eq!(from_dynamic_to_value(&d).unwrap().to_json(), json);
+        }
+        assert_eq!(shared.to_json(), json);
+    }
+
+    /// Compare converting wide objects that can be moved against ones that
+    /// must be cloned because another reference to them is held.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture convert_bench`.
+    #[test]
+    #[ignore]
+    fn convert_bench() {
+        let json: serde_json::Value = (0..500)
+            .map(|i| (format!("key{}", i), serde_json::json!("x".repeat(256))))
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+        let shared = AgentValue::from_json(json.clone()).unwrap();
+        let mut owned: Vec<AgentValue> = (0..200)
+            .map(|_| AgentValue::from_json(json.clone()).unwrap())
+            .collect();
+
+        let mut results = Vec::with_capacity(200);
+        let start = Instant::now();
+        for _ in 0..200 {
+            results.push(from_value_to_dynamic(shared.clone()).unwrap());
+        }
+        println!("cloned: {:?} for 200 objects", start.elapsed());
+        results.clear();
+        let start = Instant::now();
+        for value in owned.drain(..) {
+            results.push(from_value_to_dynamic(value).unwrap());
+        }
+        println!("moved: {:?} for 200 objects", start.elapsed());
+    }
+
+    fn sample_values(n: usize) -> Vec<AgentValue> {
+        (0..n)
+            .map(|i| {
+                AgentValue::from_json(
+                    serde_json::json!({"id": i, "tags": ["a", "b"], "ok": i % 2 == 0}),
+                )
+                .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batched_conversion_matches_element_wise() {
+        let values = sample_values(50);
+        let batched = from_values_to_dynamic(values.clone()).unwrap();
+        assert_eq!(batched.len(), values.len());
+        for (d, value) in batched.iter().zip(&values) {
+            let expected = from_value_to_dynamic(value.clone()).unwrap();
+            assert_eq!(d.to_string(), expected.to_string());
+            assert_eq!(&from_dynamic_to_value(d).unwrap(), value);
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture batch_convert_bench`.
+    #[test]
+    #[ignore]
+    fn batch_convert_bench() {
+        // Separate copies, so that no run has to clone values another holds
+        let (for_naive, for_wrapped) = (sample_values(100_000), sample_values(100_000));
+        let for_wrapped = AgentValue::array(for_wrapped);
+        let values = sample_values(100_000);
+
+        let start = Instant::now();
+        let mut naive = rhai::Array::with_capacity(for_naive.len());
+        for value in for_naive {
+            naive.push(from_value_to_dynamic(value).unwrap());
+        }
+        println!(
+            "element-wise: {:?} for {} values",
+            start.elapsed(),
+            naive.len()
+        );
+
+        let start = Instant::now();
+        let wrapped = from_value_to_dynamic(for_wrapped).unwrap();
+        println!("wrapped array: {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let batched = from_values_to_dynamic(values).unwrap();
+        println!("batched: {:?}", start.elapsed());
+        assert_eq!(wrapped.into_array().unwrap().len(), batched.len());
+    }
+
+    #[test]
+    fn shared_values_convert() {
+        // Rhai flattens the values a script evaluates to, but a variable
+        // captured by a closure stays shared in the scope, like the state
+        let mut scope = Scope::new();
+        let script = r#"let x = #{ a: [1, 2] }; let f = || x.a; x.b = "shared";"#;
+        Engine::new().run_with_scope(&mut scope, script).unwrap();
+        let x = scope.get("x").unwrap();
+        assert!(x.is_shared());
+        assert_eq!(
+            from_dynamic_to_value(x).unwrap().to_json(),
+            serde_json::json!({"a": [1, 2], "b": "shared"})
+        );
+
+        let nested: Dynamic = vec![Dynamic::from_int(7).into_shared()].into();
+        assert_eq!(
+            from_dynamic_to_value(&nested).unwrap().to_json(),
+            serde_json::json!([7])
+        );
+    }
+
+    fn large_map(n: usize) -> AgentValue {
+        let json: serde_json::Map<_, _> = (0..n)
+            .map(|i| (format!("key{:05}", i), serde_json::json!(i)))
+            .collect();
+        AgentValue::from_json(json.into()).unwrap()
+    }
+
+    #[test]
+    fn large_maps_convert_both_ways() {
+        let value = large_map(10_000);
+        let d = from_value_to_dynamic(value.clone()).unwrap();
+        let map = d.read_lock::<rhai::Map>().unwrap();
+        assert_eq!(map.len(), 10_000);
+        assert_eq!(map["key09999"].as_int().unwrap(), 9999);
+        drop(map);
+        assert_eq!(from_dynamic_to_value(&d).unwrap(), value);
+    }
+
+    /// Compares collecting the entries of a 10k-key map with inserting them
+    /// one at a time. Run with
+    /// `cargo test --release -- --ignored --nocapture map_convert_bench`.
+    #[test]
+    #[ignore]
+    fn map_convert_bench() {
+        let value = large_map(10_000);
+        let entries = value.as_object().unwrap().clone();
+
+        let start = Instant::now();
+        let mut inserted = rhai::Map::new();
+        for (k, v) in entries {
+            inserted.insert(k.into(), from_value_to_dynamic(v).unwrap());
+        }
+        println!("to Rhai by insert: {:?}", start.elapsed());
+        let start = Instant::now();
+        let collected = from_value_to_dynamic(value).unwrap();
+        println!("to Rhai by collect: {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let mut back = AgentValueMap::new();
+        for (k, v) in inserted.iter() {
+            back.insert(k.to_string(), from_dynamic_to_value(v).unwrap());
+        }
+        println!("to AgentValue by insert: {:?}", start.elapsed());
+        let start = Instant::now();
+        let collected = from_dynamic_to_value(&collected).unwrap();
+        println!("to AgentValue by collect: {:?}", start.elapsed());
+        assert_eq!(collected, AgentValue::object(back));
+    }
+}
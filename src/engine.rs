@@ -0,0 +1,532 @@
+//! The Rhai engine shared by all agents.
+//!
+//! The engine is held behind a lock so a host can swap in a new one with
+//! updated functions at runtime:
+//!
+//! ```no_run
+//! let mut engine = askit_rhai_agents::engine::new_engine();
+//! engine.register_fn("double", |x: i64| x * 2);
+//! askit_rhai_agents::engine::set_engine(engine);
+//! ```
+//!
+//! Each evaluation takes its own reference to the current engine, so an
+//! evaluation that is already running when the engine is swapped finishes on
+//! the old engine, and only later evaluations see the new one. Scripts are
+//! compiled once per configuration, so agents keep their compiled AST across a
+//! swap; the compiled script cache is cleared so that scripts configured
+//! afterwards are compiled by the new engine.
+//!
+//! Integer arithmetic in scripts is checked: an overflow, such as adding one
+//! to the largest integer, fails the evaluation rather than wrapping around.
+//! The check is built into the engine, so it can't be switched per agent, and
+//! Rhai Script refuses a `checked_arithmetic` config rather than ignoring it.
+//! The `unchecked` feature of this crate turns the check off for every script,
+//! trading that safety for slightly faster arithmetic.
+//!
+//! ```
+//! use agent_stream_kit::AgentValue;
+//! use askit_rhai_agents::test_utils::run_script;
+//!
+//! let script = format!("{} + 1", rhai::INT::MAX);
+//! # #[cfg(not(feature = "unchecked"))]
+//! assert!(run_script(&script, AgentValue::unit()).is_err());
+//! ```
+//!
+//! A registered function can fail by returning
+//! `Result<_, Box<EvalAltResult>>`. Its error is raised in the script, which
+//! can catch it with `try`/`catch`; if the script doesn't, the agent fails the
+//! message with an `AgentError::IoError` carrying the function's message:
+//!
+//! ```
+//! use agent_stream_kit::{AgentError, AgentValue};
+//! use askit_rhai_agents::test_utils::run_script;
+//! use rhai::{EvalAltResult, INT};
+//!
+//! let mut engine = askit_rhai_agents::engine::new_engine();
+//! engine.register_fn("checked_div", |a: INT, b: INT| -> Result<INT, Box<EvalAltResult>> {
+//!     if b == 0 {
+//!         return Err("division by zero".into());
+//!     }
+//!     Ok(a / b)
+//! });
+//! askit_rhai_agents::engine::set_engine(engine);
+//!
+//! let out = run_script(
+//!     "try { checked_div(value, 0) } catch (e) { return `failed: ${e}`; }",
+//!     AgentValue::integer(1),
+//! );
+//! assert_eq!(out.unwrap(), AgentValue::string("failed: division by zero"));
+//!
+//! let err = run_script("checked_div(value, 0)", AgentValue::integer(1)).unwrap_err();
+//! assert!(matches!(&err, AgentError::IoError(m) if m.contains("division by zero")));
+//! ```
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use rhai::{Dynamic, Engine, EvalAltResult, FLOAT, INT, ImmutableString, Map, Position, Variant};
+use tokio::runtime::{Builder, Handle, RuntimeFlavor};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::cache::clear_ast_cache;
+use crate::functions::{register_functions, register_sandboxed_functions};
+
+static RHAI_ENGINE: OnceLock<RwLock<Arc<Engine>>> = OnceLock::new();
+
+fn engine_holder() -> &'static RwLock<Arc<Engine>> {
+    RHAI_ENGINE.get_or_init(|| RwLock::new(Arc::new(new_engine())))
+}
+
+/// Create an engine with the functions provided by this crate registered,
+/// and the extensions given to [`set_engine_setup`].
+pub fn new_engine() -> Engine {
+    let mut engine = Engine::new();
+    register_functions(&mut engine);
+    if let Some(setup) = ENGINE_SETUP.read().unwrap().as_ref() {
+        setup(&mut engine);
+    }
+    engine
+}
+
+/// Create an engine for untrusted scripts, with only the functions of this
+/// crate that don't reach outside the agent, and without the extensions given
+/// to [`set_engine_setup`].
+pub(crate) fn new_sandboxed_engine() -> Engine {
+    let mut engine = Engine::new();
+    register_sandboxed_functions(&mut engine);
+    engine
+}
+
+/// Extends the engines created by [`new_engine`].
+pub type EngineSetup = Arc<dyn Fn(&mut Engine) + Send + Sync>;
+
+static ENGINE_SETUP: RwLock<Option<EngineSetup>> = RwLock::new(None);
+
+/// Extend every engine this crate creates with `setup`, or stop extending
+/// them with `None`, and make a new engine with it the current one.
+///
+/// Unlike functions, custom syntax is needed to compile a script, and some
+/// configs, such as `disabled_symbols`, compile on an engine of their own, so
+/// custom syntax only works everywhere when it is registered here rather than
+/// on an engine given to [`set_engine`]:
+///
+/// ```
+/// use std::sync::Arc;
+/// use agent_stream_kit::AgentValue;
+/// use askit_rhai_agents::engine::set_engine_setup;
+/// use askit_rhai_agents::test_utils::run_script;
+///
+/// set_engine_setup(Some(Arc::new(|engine: &mut rhai::Engine| {
+///     engine
+///         .register_custom_syntax(["twice", "$expr$"], false, |context, inputs| {
+///             let value = context.eval_expression_tree(&inputs[0])?;
+///             Ok(vec![value.clone(), value].into())
+///         })
+///         .unwrap();
+/// })));
+/// let out = run_script("twice value + 1", AgentValue::integer(1));
+/// assert_eq!(
+///     out.unwrap(),
+///     AgentValue::array(vec![AgentValue::integer(2), AgentValue::integer(2)])
+/// );
+/// ```
+///
+/// Agents keep the scripts they have compiled, and a compiled script using
+/// custom syntax fails to run on an engine without it. So remove custom
+/// syntax only once no agent is configured with a script using it, and
+/// reconfigure agents whose scripts failed to compile before it was added.
+pub fn set_engine_setup(setup: Option<EngineSetup>) {
+    *ENGINE_SETUP.write().unwrap() = setup;
+    set_engine(new_engine());
+}
+
+/// Bumped on each [`set_engine`] while the engine is locked.
+static ENGINE_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Get the current engine.
+pub fn get_engine() -> Arc<Engine> {
+    engine_holder().read().unwrap().clone()
+}
+
+/// Get the current engine and its generation, which changes with each
+/// [`set_engine`], so what it compiled can be told apart from what a later
+/// engine compiles.
+pub(crate) fn get_engine_generation() -> (Arc<Engine>, u64) {
+    let engine = engine_holder().read().unwrap();
+    (engine.clone(), ENGINE_GENERATION.load(Ordering::Relaxed))
+}
+
+/// Replace the engine used by subsequent evaluations.
+pub fn set_engine(engine: Engine) {
+    let mut current = engine_holder().write().unwrap();
+    *current = Arc::new(engine);
+    ENGINE_GENERATION.fetch_add(1, Ordering::Relaxed);
+    drop(current);
+    clear_ast_cache();
+}
+
+static EVAL_LIMIT: RwLock<Option<Arc<Semaphore>>> = RwLock::new(None);
+
+/// Limit the number of scripts evaluated at the same time across all agents,
+/// or remove the limit with `0`.
+///
+/// Each agent evaluates its messages one at a time, but many agents receiving
+/// a burst at once can occupy every runtime worker. Agents beyond the limit
+/// wait for a running evaluation to finish. Evaluations already waiting keep
+/// the previous limit.
+pub fn set_max_concurrent_evals(max: usize) {
+    *EVAL_LIMIT.write().unwrap() = (max > 0).then(|| Arc::new(Semaphore::new(max)));
+}
+
+/// Wait until a script may be evaluated under the concurrency limit.
+/// The returned permit must be held for the evaluation.
+pub(crate) async fn eval_permit() -> Option<OwnedSemaphorePermit> {
+    let limit = EVAL_LIMIT.read().unwrap().clone()?;
+    // The semaphore is never closed
+    limit.acquire_owned().await.ok()
+}
+
+/// Wait until a script may be evaluated under `limit`, an agent's own
+/// concurrency limit, and then under the global one. The returned permits
+/// must be held for the evaluation.
+pub(crate) async fn eval_permits(
+    limit: Option<&Arc<Semaphore>>,
+) -> (Option<OwnedSemaphorePermit>, Option<OwnedSemaphorePermit>) {
+    let own = match limit {
+        Some(limit) => limit.clone().acquire_owned().await.ok(),
+        None => None,
+    };
+    (own, eval_permit().await)
+}
+
+static GLOBAL_CONSTANTS: RwLock<Option<Arc<Map>>> = RwLock::new(None);
+
+/// Make deployment-wide values, such as a region or cluster id, constants
+/// of every script, or remove them with an empty map.
+///
+/// A script's own variables of the same name, such as `value` or configured
+/// constants, take precedence.
+///
+/// ```
+/// use agent_stream_kit::AgentValue;
+/// use askit_rhai_agents::engine::set_global_constants;
+/// use askit_rhai_agents::test_utils::run_script;
+///
+/// let mut constants = rhai::Map::new();
+/// constants.insert("region".into(), "eu-west".into());
+/// set_global_constants(constants);
+/// let out = run_script("`${region}/${value}`", AgentValue::integer(1));
+/// assert_eq!(out.unwrap(), AgentValue::string("eu-west/1"));
+/// ```
+pub fn set_global_constants(constants: Map) {
+    *GLOBAL_CONSTANTS.write().unwrap() = (!constants.is_empty()).then(|| Arc::new(constants));
+}
+
+pub(crate) fn global_constants() -> Option<Arc<Map>> {
+    GLOBAL_CONSTANTS.read().unwrap().clone()
+}
+
+/// Where scripts may read files from with `read_file_blob`.
+#[derive(Clone, Debug)]
+pub struct FileAccess {
+    /// Directory that readable files must be in. Relative paths given to
+    /// `read_file_blob` are resolved against it.
+    pub root: PathBuf,
+
+    /// Largest file that can be read, in bytes.
+    pub max_bytes: u64,
+}
+
+static FILE_ACCESS: RwLock<Option<FileAccess>> = RwLock::new(None);
+
+/// Allow scripts to read files under a directory, or disallow reading files
+/// altogether with `None`, which is the default.
+pub fn set_file_access(access: Option<FileAccess>) {
+    *FILE_ACCESS.write().unwrap() = access;
+}
+
+pub(crate) fn file_access() -> Option<FileAccess> {
+    FILE_ACCESS.read().unwrap().clone()
+}
+
+/// Looks up a secret by name for the `secret` function.
+pub type SecretProvider = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+static SECRET_PROVIDER: RwLock<Option<SecretProvider>> = RwLock::new(None);
+
+/// Let scripts read secrets such as API keys with `secret(name)`, or disallow
+/// it with `None`, which is the default.
+///
+/// A script can't see the secret itself: `secret` returns a [`Secret`], which
+/// prints as `***`, also when concatenated into a string, and can't be
+/// converted to an output. Registered functions that need the value take a
+/// `Secret` argument:
+///
+/// ```
+/// use std::sync::Arc;
+/// use agent_stream_kit::AgentValue;
+/// use askit_rhai_agents::engine::{Secret, set_secret_provider};
+/// use askit_rhai_agents::test_utils::run_script;
+///
+/// set_secret_provider(Some(Arc::new(|name: &str| {
+///     (name == "token").then(|| "s3cr3t".to_string())
+/// })));
+/// let mut engine = askit_rhai_agents::engine::new_engine();
+/// engine.register_fn("auth_len", |s: &mut Secret| s.expose().len() as rhai::INT);
+/// askit_rhai_agents::engine::set_engine(engine);
+///
+/// let out = run_script(r#"`token=${secret("token")}`"#, AgentValue::unit());
+/// assert_eq!(out.unwrap(), AgentValue::string("token=***"));
+/// let out = run_script(r#"auth_len(secret("token"))"#, AgentValue::unit());
+/// assert_eq!(out.unwrap(), AgentValue::integer(6));
+/// ```
+pub fn set_secret_provider(provider: Option<SecretProvider>) {
+    *SECRET_PROVIDER.write().unwrap() = provider;
+}
+
+pub(crate) fn secret_provider() -> Option<SecretProvider> {
+    SECRET_PROVIDER.read().unwrap().clone()
+}
+
+/// A secret read by a script, which is never shown.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub(crate) fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    /// The secret value, for a registered function to use.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+/// Run an async host call to completion from inside a registered function.
+///
+/// Rhai functions are synchronous, so a function backed by an async API has
+/// to block until the future completes:
+///
+/// ```
+/// use agent_stream_kit::AgentValue;
+/// use askit_rhai_agents::test_utils::run_script;
+///
+/// async fn fetch(url: String) -> String {
+///     format!("fetched {}", url)
+/// }
+///
+/// let mut engine = askit_rhai_agents::engine::new_engine();
+/// engine.register_fn("http_get", |url: &str| {
+///     askit_rhai_agents::engine::block_on(fetch(url.to_string()))
+/// });
+/// askit_rhai_agents::engine::set_engine(engine);
+///
+/// let out = run_script("http_get(value)", AgentValue::string("a.com"));
+/// assert_eq!(out.unwrap(), AgentValue::string("fetched a.com"));
+/// ```
+///
+/// On a multi-thread Tokio runtime, the calling worker is handed over with
+/// `block_in_place` so other tasks keep running while the script waits, and
+/// the future runs on that runtime. A current-thread runtime has no other
+/// worker to hand over to, and its only worker may be the one evaluating the
+/// script, so the future runs on a runtime of its own on another thread
+/// instead, as it does outside of any runtime. There it can't use resources
+/// bound to the caller's runtime, such as its sockets or the tasks spawned on
+/// it, which don't make progress while the script waits.
+///
+/// The future must not wait on the agent that is evaluating the script, since
+/// that agent is busy until the script returns.
+pub fn block_on<F>(future: F) -> Result<F::Output, Box<EvalAltResult>>
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    if let Ok(handle) = Handle::try_current()
+        && handle.runtime_flavor() != RuntimeFlavor::CurrentThread
+    {
+        return Ok(tokio::task::block_in_place(|| handle.block_on(future)));
+    }
+    std::thread::scope(|s| {
+        let waiter = s.spawn(|| {
+            let runtime = Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| format!("Failed to create runtime: {}", e))?;
+            Ok(runtime.block_on(future))
+        });
+        waiter
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// Make `engine` reject operations mixing types that Rhai otherwise lets
+/// through, so that they fail the script instead of hiding a bug:
+///
+/// - Comparing (`==`, `!=`, `<`, `<=`, `>`, `>=`) a string with a number, or
+///   a boolean with a string or number, throws instead of being `false` (or
+///   `true` for `!=`). Integers and floats still compare numerically, and
+///   anything can still be compared with `()`. This includes comparisons made
+///   for the script, e.g. by `in` and `contains` on an array.
+/// - `+` and `+=` of a string and a number or boolean throw instead of
+///   concatenating. Interpolation such as `` `n=${n}` `` still works.
+///
+/// Rhai only consults registered operators for built-in types when its fast
+/// operators are off, so this turns them off, making arithmetic slightly
+/// slower. Apply it to every engine with [`set_engine_setup`], composing
+/// it with other extensions as needed:
+///
+/// ```
+/// use std::sync::Arc;
+/// use agent_stream_kit::AgentValue;
+/// use askit_rhai_agents::engine::{set_engine_setup, strict_operators};
+/// use askit_rhai_agents::test_utils::run_script;
+///
+/// set_engine_setup(Some(Arc::new(strict_operators)));
+/// assert!(run_script("value == \"1\"", AgentValue::integer(1)).is_err());
+/// assert!(run_script("\"n=\" + value", AgentValue::integer(1)).is_err());
+/// assert!(run_script("true < 1", AgentValue::unit()).is_err());
+///
+/// let out = run_script("[`n=${value}`, value == 1.0, value != ()]", AgentValue::integer(1));
+/// assert_eq!(
+///     out.unwrap(),
+///     AgentValue::array(vec![
+///         AgentValue::string("n=1"),
+///         AgentValue::boolean(true),
+///         AgentValue::boolean(true),
+///     ])
+/// );
+/// ```
+pub fn strict_operators(engine: &mut Engine) {
+    engine.set_fast_operators(false);
+    const COMPARISONS: &[&str] = &["==", "!=", "<", "<=", ">", ">="];
+    reject_mixed::<INT, ImmutableString>(engine, COMPARISONS);
+    reject_mixed::<FLOAT, ImmutableString>(engine, COMPARISONS);
+    reject_mixed::<bool, ImmutableString>(engine, COMPARISONS);
+    reject_mixed::<bool, INT>(engine, COMPARISONS);
+    reject_mixed::<bool, FLOAT>(engine, COMPARISONS);
+    reject_mixed::<ImmutableString, INT>(engine, &["+", "+="]);
+    reject_mixed::<ImmutableString, FLOAT>(engine, &["+", "+="]);
+    reject_mixed::<ImmutableString, bool>(engine, &["+", "+="]);
+    reject_mixed::<INT, ImmutableString>(engine, &["+"]);
+    reject_mixed::<FLOAT, ImmutableString>(engine, &["+"]);
+    reject_mixed::<bool, ImmutableString>(engine, &["+"]);
+}
+
+/// Register `ops` on `A` and `B`, in both orders for comparisons, to throw.
+fn reject_mixed<A: Variant + Clone, B: Variant + Clone>(engine: &mut Engine, ops: &[&'static str]) {
+    for &op in ops {
+        engine.register_fn(op, move |a: A, b: B| mixed_types(op, a, b));
+        if !op.starts_with('+') {
+            engine.register_fn(op, move |b: B, a: A| mixed_types(op, b, a));
+        }
+    }
+}
+
+fn mixed_types(
+    op: &str,
+    a: impl Variant + Clone,
+    b: impl Variant + Clone,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    Err(format!(
+        "Strict mode: {} on {} and {}",
+        op,
+        Dynamic::from(a).type_name(),
+        Dynamic::from(b).type_name()
+    )
+    .into())
+}
+
+static RETRYABLE_KEY: &str = "retryable";
+
+/// An error from a registered function that may succeed if the script is
+/// run again, such as a network timeout.
+///
+/// The Rhai Script agent retries evaluations that fail with such an error,
+/// up to its `max_retries` config:
+///
+/// ```no_run
+/// # fn fetch(url: &str) -> Result<String, String> { Ok(url.to_string()) }
+/// let mut engine = askit_rhai_agents::engine::new_engine();
+/// engine.register_fn("http_get", |url: &str| {
+///     fetch(url).map_err(askit_rhai_agents::engine::retryable_error)
+/// });
+/// askit_rhai_agents::engine::set_engine(engine);
+/// ```
+///
+/// Scripts can raise one with `throw #{ retryable: true, message: "..." }`.
+pub fn retryable_error(message: impl Into<String>) -> Box<EvalAltResult> {
+    let mut map = Map::new();
+    map.insert(RETRYABLE_KEY.into(), Dynamic::TRUE);
+    map.insert("message".into(), message.into().into());
+    EvalAltResult::ErrorRuntime(map.into(), Position::NONE).into()
+}
+
+/// Whether an evaluation failed with a [`retryable_error`].
+pub fn is_retryable(err: &EvalAltResult) -> bool {
+    match err {
+        EvalAltResult::ErrorInFunctionCall(_, _, inner, _)
+        | EvalAltResult::ErrorInModule(_, inner, _) => is_retryable(inner),
+        EvalAltResult::ErrorRuntime(value, _) => value.as_map_ref().is_ok_and(|map| {
+            map.get(RETRYABLE_KEY)
+                .is_some_and(|r| r.as_bool() == Ok(true))
+        }),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{block_on, lock_globals};
+
+    #[test]
+    fn eval_permits_bound_concurrency() {
+        let _globals = lock_globals();
+        set_max_concurrent_evals(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        block_on(async {
+            let tasks: Vec<_> = (0..10)
+                .map(|_| {
+                    let running = running.clone();
+                    let peak = peak.clone();
+                    tokio::spawn(async move {
+                        let _permit = eval_permit().await;
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+        });
+        set_max_concurrent_evals(0);
+        // Agents of the tests that don't lock the globals take permits too,
+        // which can only leave fewer for these tasks
+        let peak = peak.load(Ordering::SeqCst);
+        assert!((1..=2).contains(&peak), "{} evaluations at once", peak);
+    }
+
+    #[test]
+    fn no_permit_is_needed_without_a_limit() {
+        let _globals = lock_globals();
+        set_max_concurrent_evals(0);
+        assert!(block_on(eval_permit()).is_none());
+    }
+}
@@ -0,0 +1,127 @@
+//! Telling apart the errors of scripts.
+//!
+//! `AgentError` is defined by agent-stream-kit, so script errors are reported
+//! as `AgentError::IoError` with a message starting with a fixed prefix, such
+//! as `Rhai Compile Error: ...`. [`script_error_kind`] recovers the kind:
+//!
+//! ```
+//! use agent_stream_kit::AgentValue;
+//! use askit_rhai_agents::error::{ScriptErrorKind, script_error_kind};
+//! use askit_rhai_agents::test_utils::run_script;
+//!
+//! let err = run_script("1 +", AgentValue::unit()).unwrap_err();
+//! assert_eq!(script_error_kind(&err), Some(ScriptErrorKind::Compile));
+//!
+//! let err = run_script("throw \"oops\"", AgentValue::unit()).unwrap_err();
+//! assert_eq!(script_error_kind(&err), Some(ScriptErrorKind::Runtime));
+//!
+//! # #[cfg(not(feature = "unchecked"))] {
+//! let mut engine = askit_rhai_agents::engine::new_engine();
+//! engine.set_max_operations(1000);
+//! askit_rhai_agents::engine::set_engine(engine);
+//! let err = run_script("loop {}", AgentValue::unit()).unwrap_err();
+//! assert_eq!(script_error_kind(&err), Some(ScriptErrorKind::Limit));
+//! # }
+//! ```
+
+use std::fmt::Display;
+
+use agent_stream_kit::{AgentError, AgentValue, AgentValueMap};
+use rhai::EvalAltResult;
+
+use crate::engine::get_engine;
+
+/// Message prefix of errors from compiling a script.
+pub const COMPILE_ERROR_PREFIX: &str = "Rhai Compile Error: ";
+
+/// Message prefix of errors raised while a script runs.
+pub const RUNTIME_ERROR_PREFIX: &str = "Rhai Runtime Error: ";
+
+/// Message prefix of errors from a script stopped by a resource limit, such
+/// as the engine's operation limit, or terminated by the host's progress
+/// callback, e.g. on a timeout.
+pub const LIMIT_ERROR_PREFIX: &str = "Rhai Limit Error: ";
+
+/// The kind of a script error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScriptErrorKind {
+    /// The script failed to compile, so it never ran. Fixing the
+    /// configuration is the only remedy.
+    Compile,
+    /// The script failed for a particular input.
+    Runtime,
+    /// The script ran into a resource limit or was terminated, rather than
+    /// failing by itself.
+    Limit,
+}
+
+/// The kind of script error `err` is, or `None` if it didn't come from a script.
+pub fn script_error_kind(err: &AgentError) -> Option<ScriptErrorKind> {
+    let AgentError::IoError(message) = err else {
+        return None;
+    };
+    if message.starts_with(COMPILE_ERROR_PREFIX) {
+        Some(ScriptErrorKind::Compile)
+    } else if message.starts_with(RUNTIME_ERROR_PREFIX) {
+        Some(ScriptErrorKind::Runtime)
+    } else if message.starts_with(LIMIT_ERROR_PREFIX) {
+        Some(ScriptErrorKind::Limit)
+    } else {
+        None
+    }
+}
+
+/// Compile `script` and describe why it fails as `{ line, col, message }`
+/// for an editor to mark, or return `None` if it compiles.
+///
+/// `line` and `col` start at 1 and are unit when Rhai doesn't know where the
+/// error is, such as for a script that ends too early.
+///
+/// ```
+/// use agent_stream_kit::AgentValue;
+/// use askit_rhai_agents::error::compile_diagnostic;
+///
+/// let err = compile_diagnostic("let x = 1;\nlet = 2;").unwrap();
+/// assert_eq!(err.get("line"), Some(&AgentValue::integer(2)));
+/// assert_eq!(err.get("col"), Some(&AgentValue::integer(5)));
+/// assert!(err.get_str("message").is_some());
+///
+/// assert!(compile_diagnostic("value + 1").is_none());
+/// ```
+pub fn compile_diagnostic(script: &str) -> Option<AgentValue> {
+    let err = get_engine().compile(script).err()?;
+    let position =
+        |p: Option<usize>| p.map_or(AgentValue::unit(), |p| AgentValue::integer(p as i64));
+    let mut map = AgentValueMap::new();
+    map.insert("line".to_string(), position(err.1.line()));
+    map.insert("col".to_string(), position(err.1.position()));
+    map.insert("message".to_string(), AgentValue::string(err.0.to_string()));
+    Some(AgentValue::object(map))
+}
+
+pub(crate) fn compile_error(e: impl Display) -> AgentError {
+    AgentError::IoError(format!("{}{}", COMPILE_ERROR_PREFIX, e))
+}
+
+pub(crate) fn runtime_error(e: Box<EvalAltResult>) -> AgentError {
+    let prefix = if is_limit(&e) {
+        LIMIT_ERROR_PREFIX
+    } else {
+        RUNTIME_ERROR_PREFIX
+    };
+    AgentError::IoError(format!("{}{}", prefix, e))
+}
+
+fn is_limit(err: &EvalAltResult) -> bool {
+    match err {
+        EvalAltResult::ErrorInFunctionCall(_, _, inner, _)
+        | EvalAltResult::ErrorInModule(_, inner, _) => is_limit(inner),
+        EvalAltResult::ErrorTerminated(..)
+        | EvalAltResult::ErrorTooManyOperations(_)
+        | EvalAltResult::ErrorTooManyVariables(_)
+        | EvalAltResult::ErrorTooManyModules(_)
+        | EvalAltResult::ErrorStackOverflow(_)
+        | EvalAltResult::ErrorDataTooLarge(..) => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,414 @@
+use agent_stream_kit::{AgentError, AgentValue, AgentValueMap};
+
+/// Text formats understood by the parse agent.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Format {
+    #[default]
+    Json,
+    /// A single line of comma separated values.
+    CsvLine,
+    /// Whitespace separated `key=value` pairs.
+    Kv,
+}
+
+/// How numbers are written as csv-line and kv fields. JSON keeps the
+/// formatting of serde_json.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum FloatFormat {
+    /// Rust's formatting, which writes `1.0` as `1`.
+    #[default]
+    Plain,
+    /// The shortest text that parses back to the same number, such as `1.0`
+    /// or `0.1`. Whole numbers keep a `.0`, except for very large or small
+    /// ones written with an exponent, such as `1e21`.
+    Shortest,
+    /// A fixed number of decimal places.
+    Fixed(usize),
+}
+
+impl FloatFormat {
+    /// `""`, `shortest` or `fixed:N`.
+    pub fn from_name(name: &str) -> Result<Self, AgentError> {
+        match name {
+            "" => Ok(FloatFormat::Plain),
+            "shortest" => Ok(FloatFormat::Shortest),
+            _ => name
+                .strip_prefix("fixed:")
+                .and_then(|n| n.parse().ok())
+                .map(FloatFormat::Fixed)
+                .ok_or_else(|| {
+                    AgentError::InvalidConfig(format!("Unknown float format: {}", name))
+                }),
+        }
+    }
+
+    fn format(&self, n: f64) -> String {
+        match self {
+            FloatFormat::Plain => n.to_string(),
+            // Debug is the shortest round-trip form and keeps the ".0"
+            FloatFormat::Shortest => format!("{:?}", n),
+            FloatFormat::Fixed(decimals) => format!("{:.*}", decimals, n),
+        }
+    }
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Result<Self, AgentError> {
+        match name {
+            "" | "json" => Ok(Format::Json),
+            "csv-line" => Ok(Format::CsvLine),
+            "kv" => Ok(Format::Kv),
+            _ => Err(AgentError::InvalidConfig(format!(
+                "Unknown format: {}",
+                name
+            ))),
+        }
+    }
+
+    pub fn parse(&self, s: &str) -> Result<AgentValue, AgentError> {
+        match self {
+            Format::Json => {
+                let json = serde_json::from_str(s)
+                    .map_err(|e| AgentError::JsonParseError(e.to_string()))?;
+                AgentValue::from_json(json)
+            }
+            Format::CsvLine => Ok(AgentValue::array(
+                parse_csv_line(s)?
+                    .into_iter()
+                    .map(AgentValue::string)
+                    .collect(),
+            )),
+            Format::Kv => {
+                let mut map = AgentValueMap::new();
+                for (k, v) in parse_kv(s)? {
+                    map.insert(k, AgentValue::string(v));
+                }
+                Ok(AgentValue::object(map))
+            }
+        }
+    }
+
+    pub fn serialize(
+        &self,
+        value: &AgentValue,
+        float_format: FloatFormat,
+    ) -> Result<String, AgentError> {
+        match self {
+            Format::Json => serde_json::to_string(value)
+                .map_err(|e| AgentError::SerializationError(e.to_string())),
+            Format::CsvLine => {
+                let Some(arr) = value.as_array() else {
+                    return Err(AgentError::InvalidValue(
+                        "csv-line expects an array".to_string(),
+                    ));
+                };
+                let fields = arr
+                    .iter()
+                    .map(|v| scalar_to_string(v, float_format).map(|s| quote_csv_field(&s)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(fields.join(","))
+            }
+            Format::Kv => {
+                let Some(map) = value.as_object() else {
+                    return Err(AgentError::InvalidValue("kv expects an object".to_string()));
+                };
+                let pairs = map
+                    .iter()
+                    .map(|(k, v)| {
+                        check_kv_key(k)?;
+                        scalar_to_string(v, float_format)
+                            .map(|s| format!("{}={}", k, quote_kv_value(&s)))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(pairs.join(" "))
+            }
+        }
+    }
+}
+
+fn scalar_to_string(value: &AgentValue, float_format: FloatFormat) -> Result<String, AgentError> {
+    match value {
+        AgentValue::Unit => Ok(String::new()),
+        AgentValue::Boolean(b) => Ok(b.to_string()),
+        AgentValue::Integer(i) => Ok(i.to_string()),
+        AgentValue::Number(n) => Ok(float_format.format(*n)),
+        AgentValue::String(s) => Ok(s.to_string()),
+        _ => Err(AgentError::InvalidValue(
+            "Only scalar values can be serialized as fields".to_string(),
+        )),
+    }
+}
+
+/// Parse one CSV line. Fields may be quoted with `"`, in which case they can
+/// contain commas, and `""` stands for a literal quote.
+pub(crate) fn parse_csv_line(line: &str) -> Result<Vec<String>, AgentError> {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    let line = line.strip_suffix('\r').unwrap_or(line);
+
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    let mut was_quoted = false;
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    quoted = false;
+                }
+            } else {
+                field.push(c);
+            }
+            continue;
+        }
+        match c {
+            ',' => {
+                fields.push(std::mem::take(&mut field));
+                was_quoted = false;
+            }
+            '"' if field.is_empty() && !was_quoted => {
+                quoted = true;
+                was_quoted = true;
+            }
+            '"' => {
+                return Err(AgentError::InvalidValue(format!(
+                    "Unexpected quote in CSV field {}",
+                    fields.len()
+                )));
+            }
+            _ if was_quoted => {
+                return Err(AgentError::InvalidValue(format!(
+                    "Unexpected character after quoted CSV field {}",
+                    fields.len()
+                )));
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(AgentError::InvalidValue(
+            "Unterminated quoted CSV field".to_string(),
+        ));
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+fn quote_csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Parse whitespace separated `key=value` pairs. Values may be quoted with `"`,
+/// using `\` to escape a quote or backslash.
+pub(crate) fn parse_kv(s: &str) -> Result<Vec<(String, String)>, AgentError> {
+    let mut pairs = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && !c.is_whitespace()) {
+            key.push(c);
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            return Err(AgentError::InvalidValue(format!(
+                "Missing '=' after key \"{}\"",
+                key
+            )));
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => {
+                        if let Some(c) = chars.next() {
+                            value.push(c);
+                        }
+                    }
+                    Some(c) => value.push(c),
+                    None => {
+                        return Err(AgentError::InvalidValue(format!(
+                            "Unterminated quoted value for key \"{}\"",
+                            key
+                        )));
+                    }
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                value.push(c);
+            }
+        }
+        pairs.push((key, value));
+    }
+    Ok(pairs)
+}
+
+/// Keys are written unquoted, so they can't be parsed back if they are empty
+/// or contain whitespace or `=`.
+fn check_kv_key(key: &str) -> Result<(), AgentError> {
+    if key.is_empty() || key.contains(|c: char| c.is_whitespace() || c == '=') {
+        return Err(AgentError::InvalidValue(format!(
+            "kv key \"{}\" can't be empty or contain whitespace or '='",
+            key
+        )));
+    }
+    Ok(())
+}
+
+fn quote_kv_value(s: &str) -> String {
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn strings(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_json() {
+        let value = Format::Json.parse(r#"{"a": [1, "x"]}"#).unwrap();
+        assert_eq!(value.to_json(), json!({"a": [1, "x"]}));
+        assert!(Format::Json.parse("{").is_err());
+    }
+
+    #[test]
+    fn parses_quoted_csv_fields() {
+        let fields = parse_csv_line("a,\"b, c\",\"say \"\"hi\"\"\",,d\r\n").unwrap();
+        assert_eq!(fields, strings(&["a", "b, c", "say \"hi\"", "", "d"]));
+    }
+
+    #[test]
+    fn rejects_malformed_csv() {
+        for line in ["\"open", "a\"b", "\"a\"b"] {
+            assert!(parse_csv_line(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn csv_lines_round_trip() {
+        let value =
+            AgentValue::from_json(json!(["plain", "with,comma", "with \"quote\""])).unwrap();
+        let line = Format::CsvLine
+            .serialize(&value, FloatFormat::Plain)
+            .unwrap();
+        assert_eq!(line, r#"plain,"with,comma","with ""quote""""#);
+        assert_eq!(Format::CsvLine.parse(&line).unwrap(), value);
+    }
+
+    #[test]
+    fn parses_kv_pairs() {
+        let pairs = parse_kv(r#" level=warn  msg="disk \"sda\" full" empty="" "#).unwrap();
+        assert_eq!(
+            pairs,
+            [
+                ("level".to_string(), "warn".to_string()),
+                ("msg".to_string(), "disk \"sda\" full".to_string()),
+                ("empty".to_string(), String::new()),
+            ]
+        );
+        assert!(parse_kv("novalue").is_err());
+        assert!(parse_kv("k=\"open").is_err());
+    }
+
+    #[test]
+    fn kv_round_trips() {
+        let value = AgentValue::from_json(json!({"a": "x y", "b": "z"})).unwrap();
+        let s = Format::Kv.serialize(&value, FloatFormat::Plain).unwrap();
+        assert_eq!(s, r#"a="x y" b=z"#);
+        assert_eq!(Format::Kv.parse(&s).unwrap(), value);
+
+        let bad_key = AgentValue::from_json(json!({"a b": 1})).unwrap();
+        assert!(Format::Kv.serialize(&bad_key, FloatFormat::Plain).is_err());
+    }
+
+    #[test]
+    fn kv_rejects_keys_that_cant_be_parsed_back() {
+        for key in ["", "a=b", "a\tb", "a\nb"] {
+            let mut map = AgentValueMap::new();
+            map.insert(key.to_string(), AgentValue::integer(1));
+            let err = Format::Kv
+                .serialize(&AgentValue::object(map), FloatFormat::Plain)
+                .unwrap_err();
+            assert!(err.to_string().contains("kv key"), "{:?}: {}", key, err);
+        }
+
+        // Numeric keys are fine and come back as strings
+        let value = AgentValue::from_json(json!({"1": "a"})).unwrap();
+        let s = Format::Kv.serialize(&value, FloatFormat::Plain).unwrap();
+        assert_eq!(s, "1=a");
+        assert_eq!(Format::Kv.parse(&s).unwrap(), value);
+    }
+
+    #[test]
+    fn only_scalars_serialize_as_fields() {
+        let nested = AgentValue::from_json(json!([[1]])).unwrap();
+        assert!(
+            Format::CsvLine
+                .serialize(&nested, FloatFormat::Plain)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn float_formats() {
+        let cases: [(f64, &str, &str, &str); 5] = [
+            (1.0, "1", "1.0", "1.00"),
+            (0.1, "0.1", "0.1", "0.10"),
+            (-2.5, "-2.5", "-2.5", "-2.50"),
+            (
+                1e21,
+                "1000000000000000000000",
+                "1e21",
+                "1000000000000000000000.00",
+            ),
+            (0.125, "0.125", "0.125", "0.12"),
+        ];
+        let plain = FloatFormat::from_name("").unwrap();
+        let shortest = FloatFormat::from_name("shortest").unwrap();
+        let fixed = FloatFormat::from_name("fixed:2").unwrap();
+        for (n, p, s, f) in cases {
+            assert_eq!(plain.format(n), p);
+            assert_eq!(shortest.format(n), s);
+            assert_eq!(fixed.format(n), f);
+        }
+        for name in ["fixed", "fixed:x", "round"] {
+            assert!(FloatFormat::from_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn float_format_applies_to_fields() {
+        let value = AgentValue::from_json(json!([1.0, 2, "3.0"])).unwrap();
+        let line = Format::CsvLine
+            .serialize(&value, FloatFormat::Shortest)
+            .unwrap();
+        assert_eq!(line, "1.0,2,3.0");
+        let value = AgentValue::from_json(json!({"x": 1.5})).unwrap();
+        let kv = Format::Kv.serialize(&value, FloatFormat::Fixed(3)).unwrap();
+        assert_eq!(kv, "x=1.500");
+    }
+}
@@ -0,0 +1,1506 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use agent_stream_kit::{ASKit, AgentContext, AgentValue, AgentValueMap};
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, FLOAT, INT, Map, Position};
+
+use crate::convert::{from_dynamic_to_value, from_value_to_dynamic};
+use crate::engine::{Secret, file_access, secret_provider};
+
+const TRACE_TARGET: &str = "askit_rhai_agents::trace";
+
+/// The agent and message a script is currently being evaluated for.
+#[derive(Default)]
+pub(crate) struct Caller {
+    pub agent_id: String,
+    pub ctx: AgentContext,
+
+    /// State of the random generator when the evaluation is seeded.
+    pub rng: Option<u64>,
+
+    /// Set when the agent supports `send_to`, which queues values in `sends`
+    /// for the agent to deliver once the evaluation succeeds.
+    pub askit: Option<ASKit>,
+    pub sends: Vec<(String, AgentValue)>,
+
+    /// Values queued by `publish` with their channel, also published only once
+    /// the evaluation succeeds.
+    pub publishes: Vec<(String, AgentValue)>,
+
+    /// Values queued by `emit_now` and `emit_after` with their delay, also
+    /// emitted only once the evaluation succeeds.
+    pub emits: Vec<(Duration, AgentValue)>,
+
+    /// How long the agent should hold off its next input, set by `pause`.
+    pub pause: Duration,
+
+    /// What to emit instead of the script's result, set by `no_output` and
+    /// `output`.
+    pub output: Option<ScriptOutput>,
+
+    /// Reference tables for `table_lookup`, by name.
+    pub tables: Option<Arc<BTreeMap<String, Map>>>,
+
+    /// Printed before the output of `print` and `debug`; the agent id when empty.
+    pub log_prefix: String,
+
+    /// Estimated JSON size of the input, for `value_size()`, set when the
+    /// agent's script calls it.
+    pub input_size: Option<usize>,
+}
+
+/// An output chosen by a script regardless of the value it evaluates to.
+pub(crate) enum ScriptOutput {
+    None,
+    Value(Dynamic),
+}
+
+thread_local! {
+    static CALLER: RefCell<Option<Caller>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with `caller` available to the registered functions.
+///
+/// Evaluation is synchronous, so the caller is tracked per thread and restored
+/// afterwards to support nested evaluations. The caller is returned as the
+/// functions left it.
+pub(crate) fn with_caller<R>(caller: Caller, f: impl FnOnce() -> R) -> (R, Caller) {
+    let prev = CALLER.with(|c| c.replace(Some(caller)));
+    let result = f();
+    let caller = CALLER.with(|c| c.replace(prev)).unwrap_or_default();
+    (result, caller)
+}
+
+fn caller_info() -> (String, usize) {
+    CALLER.with(|c| {
+        c.borrow()
+            .as_ref()
+            .map(|c| (c.agent_id.clone(), c.ctx.id()))
+            .unwrap_or_default()
+    })
+}
+
+// The prefix of print and debug output, or None outside of an agent.
+fn log_prefix() -> Option<String> {
+    CALLER.with(|c| {
+        c.borrow().as_ref().map(|c| {
+            if c.log_prefix.is_empty() {
+                c.agent_id.clone()
+            } else {
+                c.log_prefix.clone()
+            }
+        })
+    })
+}
+
+// The line printed for `print(text)`, as Rhai prints by default.
+fn print_line(text: &str) -> String {
+    match log_prefix() {
+        Some(prefix) => format!("{} | {}", prefix, text),
+        None => text.to_string(),
+    }
+}
+
+// The line printed for `debug(text)`, as Rhai prints by default with the agent
+// in place of the source.
+fn debug_line(text: &str, source: Option<&str>, pos: Position) -> String {
+    match (log_prefix().or(source.map(str::to_string)), pos) {
+        (Some(prefix), Position::NONE) => format!("{} | {}", prefix, text),
+        (Some(prefix), pos) => format!("{} @ {:?} | {}", prefix, pos, text),
+        (None, Position::NONE) => text.to_string(),
+        (None, pos) => format!("{:?} | {}", pos, text),
+    }
+}
+
+/// Functions reaching outside the agent, left out of sandboxed engines.
+pub(crate) const HOST_FUNCTIONS: &[&str] = &[
+    "secret",
+    "send_to",
+    "emit_now",
+    "emit_after",
+    "publish",
+    "read_file_blob",
+];
+
+/// Register the functions of this crate, including those reaching outside the
+/// agent: files, secrets and messages to other agents.
+pub(crate) fn register_functions(engine: &mut Engine) {
+    register_sandboxed_functions(engine);
+    engine.register_type_with_name::<Secret>("Secret");
+    engine.register_fn("secret", secret);
+    engine.register_fn("to_string", |_: &mut Secret| REDACTED);
+    engine.register_fn("to_debug", |_: &mut Secret| REDACTED);
+    engine.register_fn("send_to", send_to);
+    engine.register_fn("emit_now", |value: Dynamic| {
+        queue_emit("emit_now", Duration::ZERO, value)
+    });
+    engine.register_fn("emit_after", emit_after);
+    engine.register_fn("publish", publish);
+    engine.register_fn("read_file_blob", read_file_blob);
+}
+
+/// Register the functions of this crate that only see the agent running the
+/// script, for scripts that aren't trusted with the others.
+pub(crate) fn register_sandboxed_functions(engine: &mut Engine) {
+    engine.on_print(|text| println!("{}", print_line(text)));
+    engine.on_debug(|text, source, pos| println!("{}", debug_line(text, source, pos)));
+    engine.register_fn("trace_event", trace_event);
+    engine.register_fn("trace_event", |name: &str| trace_event(name, Map::new()));
+    engine.register_fn("strict_eq", strict_eq);
+    engine.register_fn("random", random);
+    engine.register_fn("uuid", uuid);
+    engine.register_fn("get_path", get_path);
+    engine.register_fn("set_path", set_path);
+    engine.register_fn("to_json", to_json);
+    engine.register_fn("to_json", to_json_with);
+    engine.register_fn("to_pairs", to_pairs);
+    engine.register_fn("obj", Map::new);
+    engine.register_fn("arr", Array::new);
+    engine.register_fn("with_key", with_key);
+    engine.register_fn("with_item", with_item);
+    engine.register_fn("dump", dump);
+    engine.register_fn("value_size", value_size);
+    engine.register_fn("value_size", input_size);
+    engine.register_fn("freeze", freeze);
+    #[cfg(feature = "url")]
+    crate::url::register_url_functions(engine);
+    #[cfg(feature = "unicode")]
+    crate::unicode::register_unicode_functions(engine);
+    engine.register_fn("to_blob", to_blob);
+    engine.register_fn("as_integer", as_integer);
+    engine.register_fn("as_number", as_number);
+    engine.register_fn("as_string", as_string);
+    engine.register_fn("as_boolean", as_boolean);
+    engine.register_fn("to_bool", to_bool);
+    engine.register_fn("duration_ms", duration_ms);
+    engine.register_fn("duration_ms", |seconds: INT| duration_ms(seconds as FLOAT));
+    engine.register_fn("pause", pause);
+    engine.register_fn("no_output", || set_output("no_output", ScriptOutput::None));
+    engine.register_fn("output", |value: Dynamic| {
+        set_output("output", ScriptOutput::Value(value))
+    });
+    engine.register_fn("ctx_var", ctx_var);
+    engine.register_fn("table_lookup", table_lookup);
+    engine.register_fn("approx_eq", approx_eq);
+    engine.register_fn("arr_sum", arr_sum);
+    engine.register_fn("arr_min", arr_min);
+    engine.register_fn("arr_max", arr_max);
+    engine.register_fn("arr_mean", arr_mean);
+}
+
+// trace_event(name, attrs)
+//
+// Emits a tracing event named by its message, with the agent id, the context
+// id and the attributes, converted like any other value and serialized as
+// JSON, as fields. Hosts that only use `log` get it as a log record.
+fn trace_event(name: &str, attrs: Map) -> Result<(), Box<EvalAltResult>> {
+    let mut value_map = AgentValueMap::new();
+    for (k, v) in attrs.iter() {
+        let av = from_dynamic_to_value(v).map_err(|e| e.to_string())?;
+        value_map.insert(k.to_string(), av);
+    }
+    let attrs = AgentValue::object(value_map).to_json();
+    let (agent_id, ctx_id) = caller_info();
+    tracing::info!(
+        target: TRACE_TARGET,
+        agent = agent_id.as_str(),
+        ctx = ctx_id,
+        attrs = %attrs,
+        "{}",
+        name
+    );
+    Ok(())
+}
+
+// send_to(agent_id, value)
+//
+// The value is delivered to the `value` input of the agent after the script
+// has finished, and only if it succeeds. The sending agent doesn't wait for
+// the delivery, so agents can send to each other.
+fn send_to(agent_id: &str, value: Dynamic) -> Result<(), Box<EvalAltResult>> {
+    let value = from_dynamic_to_value(&value).map_err(|e| e.to_string())?;
+    CALLER.with(|c| {
+        let mut c = c.borrow_mut();
+        let Some(caller) = c.as_mut() else {
+            return Err("send_to is not available here".into());
+        };
+        let Some(askit) = &caller.askit else {
+            return Err("send_to is not supported by this agent".into());
+        };
+        if agent_id == caller.agent_id {
+            return Err("send_to can't send to the agent running the script".into());
+        }
+        if askit.get_agent(agent_id).is_none() {
+            return Err(format!("send_to: agent {} not found", agent_id).into());
+        }
+        caller.sends.push((agent_id.to_string(), value));
+        Ok(())
+    })
+}
+
+// emit_after(ms, value)
+//
+// The value is emitted on the `value` output `ms` milliseconds after the
+// script has finished, and only if it succeeds. `emit_now(value)` emits it
+// right away, before the script's own result.
+fn emit_after(ms: INT, value: Dynamic) -> Result<(), Box<EvalAltResult>> {
+    let delay = u64::try_from(ms).map_err(|_| format!("emit_after: negative delay {}", ms))?;
+    queue_emit("emit_after", Duration::from_millis(delay), value)
+}
+
+// publish(channel, value)
+//
+// The value is written to the board named `channel` after the script has
+// finished, and only if it succeeds. Rhai Script agents subscribed to the
+// channel read it as `channels[channel]`.
+fn publish(channel: &str, value: Dynamic) -> Result<(), Box<EvalAltResult>> {
+    let value = from_dynamic_to_value(&value).map_err(|e| e.to_string())?;
+    CALLER.with(|c| {
+        let mut c = c.borrow_mut();
+        match c.as_mut() {
+            Some(caller) if caller.askit.is_some() => {
+                caller.publishes.push((channel.to_string(), value));
+                Ok(())
+            }
+            _ => Err("publish is not supported by this agent".into()),
+        }
+    })
+}
+
+// pause(ms)
+//
+// Once the script has succeeded and its outputs are sent, the agent waits `ms`
+// milliseconds before it takes its next input. Inputs arriving meanwhile queue
+// up in the agent's bounded input channel, and once it is full upstream agents
+// are held back too, so a script can slow its producers down, e.g. while a
+// downstream service is overloaded. The pause ends by itself; the longest one
+// requested in an evaluation is applied.
+fn pause(ms: INT) -> Result<(), Box<EvalAltResult>> {
+    let ms = u64::try_from(ms).map_err(|_| format!("pause: negative duration {}", ms))?;
+    CALLER.with(|c| {
+        let mut c = c.borrow_mut();
+        match c.as_mut() {
+            Some(caller) if caller.askit.is_some() => {
+                caller.pause = caller.pause.max(Duration::from_millis(ms));
+                Ok(())
+            }
+            _ => Err("pause is not supported by this agent".into()),
+        }
+    })
+}
+
+// no_output() and output(value)
+//
+// The agent emits nothing for the input, or `value` even when it is unit,
+// rather than the value the script evaluates to. The script goes on running,
+// and the last call wins. Values given to send_to and emit_* are delivered
+// either way.
+fn set_output(name: &str, output: ScriptOutput) -> Result<(), Box<EvalAltResult>> {
+    CALLER.with(|c| {
+        let mut c = c.borrow_mut();
+        match c.as_mut() {
+            Some(caller) if caller.askit.is_some() => {
+                caller.output = Some(output);
+                Ok(())
+            }
+            _ => Err(format!("{} is not supported by this agent", name).into()),
+        }
+    })
+}
+
+fn queue_emit(name: &str, delay: Duration, value: Dynamic) -> Result<(), Box<EvalAltResult>> {
+    let value = from_dynamic_to_value(&value).map_err(|e| e.to_string())?;
+    CALLER.with(|c| {
+        let mut c = c.borrow_mut();
+        match c.as_mut() {
+            Some(caller) if caller.askit.is_some() => {
+                caller.emits.push((delay, value));
+                Ok(())
+            }
+            _ => Err(format!("{} is not supported by this agent", name).into()),
+        }
+    })
+}
+
+// ctx_var(name) -> the variable of the message's context, or () if it isn't set
+//
+// Such as a result attached by an upstream Rhai Script with `result_var`.
+fn ctx_var(name: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+    let value = CALLER.with(|c| {
+        c.borrow()
+            .as_ref()
+            .and_then(|caller| caller.ctx.get_var(name).cloned())
+    });
+    match value {
+        Some(value) => from_value_to_dynamic(value).map_err(|e| e.to_string().into()),
+        None => Ok(Dynamic::UNIT),
+    }
+}
+
+// table_lookup(table, key) -> the value of key in a configured table, or ()
+//
+// Throws when the agent has no table of that name.
+fn table_lookup(table: &str, key: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+    CALLER.with(|c| {
+        let c = c.borrow();
+        let Some(t) = c
+            .as_ref()
+            .and_then(|c| c.tables.as_ref())
+            .and_then(|tables| tables.get(table))
+        else {
+            return Err(format!("table_lookup: unknown table {}", table).into());
+        };
+        Ok(t.get(key).cloned().unwrap_or(Dynamic::UNIT))
+    })
+}
+
+// strict_eq(a, b)
+//
+// Unlike `==`, values of different AgentValue types are never equal,
+// so `strict_eq(5, 5.0)` is false. Arrays and objects are compared element-wise.
+fn strict_eq(a: Dynamic, b: Dynamic) -> Result<bool, Box<EvalAltResult>> {
+    let a = from_dynamic_to_value(&a).map_err(|e| e.to_string())?;
+    let b = from_dynamic_to_value(&b).map_err(|e| e.to_string())?;
+    Ok(a == b)
+}
+
+// to_json(value) -> JSON string
+// to_json(value, #{ sort_keys: true, pretty: false }) -> JSON string
+//
+// Object keys are always written in sorted order, so the same value gives
+// the same JSON on every run. Both Rhai maps and AgentValue objects are
+// sorted maps, so the key order of a parsed input is not preserved, and
+// `sort_keys: false` throws rather than pretend otherwise; `to_pairs` keeps
+// an order in an array instead.
+fn to_json(value: Dynamic) -> Result<String, Box<EvalAltResult>> {
+    to_json_with(value, Map::new())
+}
+
+fn to_json_with(value: Dynamic, options: Map) -> Result<String, Box<EvalAltResult>> {
+    let mut pretty = false;
+    for (name, option) in options {
+        let flag = option
+            .as_bool()
+            .map_err(|_| format!("to_json option {} must be a boolean", name))?;
+        match name.as_str() {
+            "sort_keys" if !flag => {
+                return Err(
+                    "to_json can't keep the key order: object keys are always sorted; use to_pairs"
+                        .into(),
+                );
+            }
+            "sort_keys" => {}
+            "pretty" => pretty = flag,
+            _ => return Err(format!("Unknown to_json option: {}", name).into()),
+        }
+    }
+    let value = from_dynamic_to_value(&value).map_err(|e| e.to_string())?;
+    let json = if pretty {
+        serde_json::to_string_pretty(&value)
+    } else {
+        serde_json::to_string(&value)
+    };
+    json.map_err(|e| e.to_string().into())
+}
+
+static REDACTED: &str = "***";
+
+// secret(name) -> Secret from the host's secret provider
+//
+// It prints as *** wherever it is turned into a string, such as in print,
+// dump or string interpolation. Throws when the secret doesn't exist.
+fn secret(name: &str) -> Result<Secret, Box<EvalAltResult>> {
+    let Some(provider) = secret_provider() else {
+        return Err("Secrets are not available".into());
+    };
+    match provider(name) {
+        Some(value) => Ok(Secret::new(value)),
+        None => Err(format!("secret {} not found", name).into()),
+    }
+}
+
+// freeze(value) -> a deep copy of value that shares nothing with it
+//
+// Rhai copies values on assignment, so `state.last = value` already stores a
+// copy that later changes to `value` don't affect, even when `value` is shared
+// with a closure. freeze makes the copy explicit, and also unshares values at
+// any depth that a host function may have returned still shared.
+fn freeze(value: Dynamic) -> Dynamic {
+    let value = value.flatten();
+    if value.is_map() {
+        let map = value.cast::<Map>();
+        return map
+            .into_iter()
+            .map(|(k, v)| (k, freeze(v)))
+            .collect::<Map>()
+            .into();
+    }
+    if value.is_array() {
+        let arr = value.cast::<Array>();
+        return arr.into_iter().map(freeze).collect::<Array>().into();
+    }
+    value
+}
+
+// value_size(value) -> approximate size of value as JSON, in bytes
+// value_size() -> the same for the input of a Rhai Script agent
+//
+// Computed by walking the value, without serializing it. String escapes
+// aren't counted, and floats are measured as Rust formats them, so the result
+// can differ a little from the length of to_json(value). The input is
+// measured before it's converted for the script, so value_size() stays the
+// same when the script changes value.
+fn value_size(value: Dynamic) -> INT {
+    json_size(&value).try_into().unwrap_or(INT::MAX)
+}
+
+fn input_size() -> Result<INT, Box<EvalAltResult>> {
+    CALLER
+        .with(|c| c.borrow().as_ref().and_then(|c| c.input_size))
+        .map(|size| size.try_into().unwrap_or(INT::MAX))
+        .ok_or_else(|| "value_size() is not available here, pass the value to measure".into())
+}
+
+/// Approximate size of `value` as JSON, in bytes, measured as `value_size`
+/// measures the converted value.
+pub(crate) fn agent_value_size(value: &AgentValue) -> usize {
+    match value {
+        AgentValue::Unit => 4,
+        AgentValue::Boolean(b) => b.to_string().len(),
+        AgentValue::Integer(i) => i.to_string().len(),
+        AgentValue::Number(n) => format!("{:?}", n).len(),
+        AgentValue::String(s) => s.len() + 2,
+        AgentValue::Array(arr) => {
+            let items: usize = arr.iter().map(agent_value_size).sum();
+            2 + items + arr.len().saturating_sub(1)
+        }
+        AgentValue::Object(map) => {
+            let items: usize = map
+                .iter()
+                .map(|(k, v)| k.len() + 3 + agent_value_size(v))
+                .sum();
+            2 + items + map.len().saturating_sub(1)
+        }
+        // Only with agent-stream-kit's image feature
+        #[allow(unreachable_patterns)]
+        _ => value.to_json().to_string().len(),
+    }
+}
+
+fn json_size(value: &Dynamic) -> usize {
+    // Separators: `,` between items, plus `"":` around each key
+    if let Ok(map) = value.as_map_ref() {
+        let items: usize = map.iter().map(|(k, v)| k.len() + 3 + json_size(v)).sum();
+        return 2 + items + map.len().saturating_sub(1);
+    }
+    if let Ok(arr) = value.as_array_ref() {
+        let items: usize = arr.iter().map(json_size).sum();
+        return 2 + items + arr.len().saturating_sub(1);
+    }
+    if let Ok(s) = value.as_immutable_string_ref() {
+        return s.len() + 2;
+    }
+    if value.is_unit() {
+        return 4;
+    }
+    // Converted to `{"__rhai_blob__":"<base64>"}`
+    if let Some(blob) = value.read_lock::<Blob>() {
+        return 20 + blob.len().div_ceil(3) * 4;
+    }
+    value.to_string().len()
+}
+
+// to_pairs(obj) -> array of [key, value] pairs
+//
+// An array keeps its order through conversion and JSON, unlike an object,
+// whose keys downstream consumers may reorder. The pairs are in the order the
+// map iterates, which for Rhai maps is sorted by key.
+fn to_pairs(obj: Map) -> Array {
+    obj.into_iter()
+        .map(|(k, v)| Dynamic::from_array(vec![k.into(), v]))
+        .collect()
+}
+
+// with_key(obj, key, value) -> obj with key set to value
+// with_item(arr, value) -> arr with value appended
+//
+// For building outputs fluently, such as `obj().with_key(name, 1)` with a
+// computed key. `with` itself is a reserved word in Rhai.
+fn with_key(mut obj: Map, key: &str, value: Dynamic) -> Map {
+    obj.insert(key.into(), value);
+    obj
+}
+
+fn with_item(mut arr: Array, value: Dynamic) -> Array {
+    arr.push(value);
+    arr
+}
+
+// dump(value) -> indented description of a value with its types, for debugging
+//
+//   map {
+//     items: array [
+//       i64 1,
+//     ],
+//   }
+fn dump(value: Dynamic) -> String {
+    let mut out = String::new();
+    dump_into(&mut out, &value, 0);
+    out
+}
+
+fn dump_into(out: &mut String, value: &Dynamic, indent: usize) {
+    let pad = "  ".repeat(indent + 1);
+    if let Ok(map) = value.as_map_ref() {
+        if map.is_empty() {
+            out.push_str("map {}");
+            return;
+        }
+        out.push_str("map {\n");
+        for (k, v) in map.iter() {
+            out.push_str(&format!("{}{}: ", pad, k));
+            dump_into(out, v, indent + 1);
+            out.push_str(",\n");
+        }
+        out.push_str(&format!("{}}}", "  ".repeat(indent)));
+    } else if let Ok(arr) = value.as_array_ref() {
+        if arr.is_empty() {
+            out.push_str("array []");
+            return;
+        }
+        out.push_str("array [\n");
+        for v in arr.iter() {
+            out.push_str(&pad);
+            dump_into(out, v, indent + 1);
+            out.push_str(",\n");
+        }
+        out.push_str(&format!("{}]", "  ".repeat(indent)));
+    } else if value.is_blob() {
+        let blob = value.clone().cast::<Blob>();
+        let hex: Vec<String> = blob.iter().map(|b| format!("{:02x}", b)).collect();
+        out.push_str(&format!("blob[{}] {}", blob.len(), hex.join(" ")));
+    } else if value.is_unit() {
+        out.push_str("()");
+    } else if value.is::<Secret>() {
+        out.push_str(&format!("Secret {}", REDACTED));
+    } else {
+        out.push_str(&format!("{} {:?}", value.type_name(), value));
+    }
+}
+
+// to_blob(array) -> blob of the array's integers, each of which must be 0 to 255
+//
+// Blobs leave the script as `#{ __rhai_blob__: base64 }` and come back as
+// blobs in the next one, while arrays stay arrays.
+fn to_blob(arr: Array) -> Result<Blob, Box<EvalAltResult>> {
+    arr.iter()
+        .enumerate()
+        .map(|(i, v)| {
+            v.as_int()
+                .ok()
+                .and_then(|n| u8::try_from(n).ok())
+                .ok_or_else(|| format!("to_blob: element {} is not a byte: {}", i, v).into())
+        })
+        .collect()
+}
+
+// read_file_blob(path) -> blob of the file's contents
+//
+// Only files under the directory allowed by the host with
+// `engine::set_file_access` can be read, up to its size limit.
+fn read_file_blob(path: &str) -> Result<Blob, Box<EvalAltResult>> {
+    let Some(access) = file_access() else {
+        return Err("read_file_blob: reading files is not allowed".into());
+    };
+    let err = |e: std::io::Error| format!("read_file_blob {}: {}", path, e);
+    let root = access.root.canonicalize().map_err(err)?;
+    // Resolving symlinks and `..` before the check keeps the path inside root
+    let file = root.join(path).canonicalize().map_err(err)?;
+    if !file.starts_with(&root) {
+        return Err(format!("read_file_blob {}: outside of the allowed directory", path).into());
+    }
+
+    let len = std::fs::metadata(&file).map_err(err)?.len();
+    if len > access.max_bytes {
+        return Err(format!(
+            "read_file_blob {}: {} bytes exceeds the limit of {}",
+            path, len, access.max_bytes
+        )
+        .into());
+    }
+    let mut blob = Blob::with_capacity(len as usize);
+    std::fs::File::open(&file)
+        .and_then(|f| f.take(access.max_bytes).read_to_end(&mut blob))
+        .map_err(err)?;
+    Ok(blob)
+}
+
+fn number_of(value: &Dynamic) -> Option<FLOAT> {
+    value
+        .as_float()
+        .ok()
+        .or_else(|| value.as_int().ok().map(|i| i as FLOAT))
+}
+
+// approx_eq(a, b, epsilon)
+//
+// True when a and b differ by at most epsilon, either absolutely or relative
+// to the larger magnitude, so that it works for both small and large numbers.
+fn approx_eq(a: Dynamic, b: Dynamic, epsilon: Dynamic) -> Result<bool, Box<EvalAltResult>> {
+    let (Some(a), Some(b), Some(epsilon)) = (number_of(&a), number_of(&b), number_of(&epsilon))
+    else {
+        return Err("approx_eq expects numbers".into());
+    };
+    if a == b {
+        // Including infinities of the same sign
+        return Ok(true);
+    }
+    let diff = (a - b).abs();
+    Ok(diff <= epsilon || diff <= epsilon * a.abs().max(b.abs()))
+}
+
+// Array statistics
+//
+// Elements must all be numbers. The sum of integers is an integer, erroring
+// on overflow; otherwise it's a float. arr_min and arr_max return the element
+// itself. For an empty array, arr_sum is 0 and the others are ().
+
+fn numbers_of<'a>(
+    name: &str,
+    arr: &'a Array,
+) -> Result<Vec<(FLOAT, &'a Dynamic)>, Box<EvalAltResult>> {
+    arr.iter()
+        .enumerate()
+        .map(|(i, v)| {
+            number_of(v).map(|n| (n, v)).ok_or_else(|| {
+                format!("{}: element {} is not a number: {}", name, i, v.type_name()).into()
+            })
+        })
+        .collect()
+}
+
+fn arr_sum(arr: Array) -> Result<Dynamic, Box<EvalAltResult>> {
+    let numbers = numbers_of("arr_sum", &arr)?;
+    if arr.iter().all(|v| v.is_int()) {
+        let mut sum: INT = 0;
+        for v in &arr {
+            sum = sum
+                .checked_add(v.as_int().unwrap_or_default())
+                .ok_or("arr_sum: integer overflow")?;
+        }
+        return Ok(sum.into());
+    }
+    Ok(numbers.iter().map(|(n, _)| n).sum::<FLOAT>().into())
+}
+
+fn arr_extreme(
+    name: &str,
+    arr: &Array,
+    pick: fn(FLOAT, FLOAT) -> bool,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let numbers = numbers_of(name, arr)?;
+    let best = numbers
+        .into_iter()
+        .reduce(|best, cur| if pick(cur.0, best.0) { cur } else { best });
+    Ok(best.map_or(Dynamic::UNIT, |(_, v)| v.clone()))
+}
+
+fn arr_min(arr: Array) -> Result<Dynamic, Box<EvalAltResult>> {
+    arr_extreme("arr_min", &arr, |a, b| a < b)
+}
+
+fn arr_max(arr: Array) -> Result<Dynamic, Box<EvalAltResult>> {
+    arr_extreme("arr_max", &arr, |a, b| a > b)
+}
+
+fn arr_mean(arr: Array) -> Result<Dynamic, Box<EvalAltResult>> {
+    let numbers = numbers_of("arr_mean", &arr)?;
+    if numbers.is_empty() {
+        return Ok(Dynamic::UNIT);
+    }
+    let sum: FLOAT = numbers.iter().map(|(n, _)| n).sum();
+    Ok((sum / numbers.len() as FLOAT).into())
+}
+
+// Coercion to AgentValue types
+//
+//   as_integer: integers, whole floats and integer strings
+//   as_number:  integers, floats and numeric strings
+//   as_string:  strings, characters, numbers and booleans
+//   as_boolean: booleans, 0 and 1, and "true" and "false"
+//   to_bool:    as_boolean, but also any number (true unless zero), numeric
+//               strings by the same rule, and "true" and "false" in any case
+//
+// Anything else throws, so booleans are not numbers and unit is not a string.
+// to_bool throws on NaN and on strings such as "yes" or "".
+
+fn coercion_error(target: &str, value: &Dynamic) -> Box<EvalAltResult> {
+    format!(
+        "Cannot convert {} {} to {}",
+        value.type_name(),
+        value,
+        target
+    )
+    .into()
+}
+
+// duration_ms(seconds) -> the duration in whole milliseconds, rounded
+//
+// For the seconds Rhai gives for `timestamp() - start` or `start.elapsed`.
+fn duration_ms(seconds: FLOAT) -> Result<INT, Box<EvalAltResult>> {
+    let ms = (seconds * 1000.0).round();
+    if !(ms >= INT::MIN as FLOAT && ms < INT::MAX as FLOAT) {
+        return Err(format!("duration_ms: {} seconds is out of range", seconds).into());
+    }
+    Ok(ms as INT)
+}
+
+fn as_integer(value: Dynamic) -> Result<INT, Box<EvalAltResult>> {
+    if let Ok(i) = value.as_int() {
+        return Ok(i);
+    }
+    if let Ok(f) = value.as_float()
+        && f.fract() == 0.0
+        && f >= INT::MIN as FLOAT
+        && f < INT::MAX as FLOAT
+    {
+        return Ok(f as INT);
+    }
+    if value.is_string()
+        && let Ok(i) = value
+            .clone()
+            .into_string()
+            .unwrap_or_default()
+            .trim()
+            .parse()
+    {
+        return Ok(i);
+    }
+    Err(coercion_error("integer", &value))
+}
+
+fn as_number(value: Dynamic) -> Result<FLOAT, Box<EvalAltResult>> {
+    if let Ok(f) = value.as_float() {
+        return Ok(f);
+    }
+    if let Ok(i) = value.as_int() {
+        return Ok(i as FLOAT);
+    }
+    if value.is_string()
+        && let Ok(f) = value
+            .clone()
+            .into_string()
+            .unwrap_or_default()
+            .trim()
+            .parse()
+    {
+        return Ok(f);
+    }
+    Err(coercion_error("number", &value))
+}
+
+fn as_string(value: Dynamic) -> Result<String, Box<EvalAltResult>> {
+    if value.is_string() || value.is_char() || value.is_int() || value.is_float() || value.is_bool()
+    {
+        return Ok(value.to_string());
+    }
+    Err(coercion_error("string", &value))
+}
+
+fn as_boolean(value: Dynamic) -> Result<bool, Box<EvalAltResult>> {
+    if let Ok(b) = value.as_bool() {
+        return Ok(b);
+    }
+    match value.as_int() {
+        Ok(0) => return Ok(false),
+        Ok(1) => return Ok(true),
+        _ => {}
+    }
+    if value.is_string() {
+        match value.clone().into_string().unwrap_or_default().trim() {
+            "true" => return Ok(true),
+            "false" => return Ok(false),
+            _ => {}
+        }
+    }
+    Err(coercion_error("boolean", &value))
+}
+
+fn to_bool(value: Dynamic) -> Result<bool, Box<EvalAltResult>> {
+    if let Ok(b) = value.as_bool() {
+        return Ok(b);
+    }
+    if let Ok(i) = value.as_int() {
+        return Ok(i != 0);
+    }
+    if let Ok(f) = value.as_float()
+        && !f.is_nan()
+    {
+        return Ok(f != 0.0);
+    }
+    if value.is_string() {
+        let s = value.clone().into_string().unwrap_or_default();
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("true") {
+            return Ok(true);
+        }
+        if s.eq_ignore_ascii_case("false") {
+            return Ok(false);
+        }
+        if let Ok(f) = s.parse::<f64>()
+            && !f.is_nan()
+        {
+            return Ok(f != 0.0);
+        }
+    }
+    Err(coercion_error("boolean", &value))
+}
+
+// Nested paths
+//
+// A path is a `.` separated list of object keys and array indices,
+// e.g. `"items.0.name"`.
+
+// get_path(obj, path) -> the value at path, or () when it doesn't exist
+fn get_path(obj: &mut Dynamic, path: &str) -> Dynamic {
+    let keys: Vec<&str> = path.split('.').collect();
+    get_in(obj, &keys)
+}
+
+fn get_in(value: &Dynamic, keys: &[&str]) -> Dynamic {
+    let Some((key, rest)) = keys.split_first() else {
+        return value.clone();
+    };
+    if let Ok(map) = value.as_map_ref() {
+        return map
+            .get(*key)
+            .map_or(Dynamic::UNIT, |child| get_in(child, rest));
+    }
+    if let Ok(arr) = value.as_array_ref() {
+        return key
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| arr.get(i))
+            .map_or(Dynamic::UNIT, |child| get_in(child, rest));
+    }
+    Dynamic::UNIT
+}
+
+// set_path(obj, path, value)
+//
+// Missing or unit object entries along the path are created as empty objects.
+// Array indices must already exist.
+fn set_path(obj: &mut Dynamic, path: &str, value: Dynamic) -> Result<(), Box<EvalAltResult>> {
+    let keys: Vec<&str> = path.split('.').collect();
+    set_in(obj, &keys, value).map_err(|e| format!("set_path {}: {}", path, e).into())
+}
+
+fn set_in(target: &mut Dynamic, keys: &[&str], value: Dynamic) -> Result<(), String> {
+    let Some((key, rest)) = keys.split_first() else {
+        *target = value;
+        return Ok(());
+    };
+    if target.is_map() {
+        let mut map = target.as_map_mut()?;
+        let child = map.entry((*key).into()).or_insert(Dynamic::UNIT);
+        if child.is_unit() && !rest.is_empty() {
+            *child = Map::new().into();
+        }
+        return set_in(child, rest, value);
+    }
+    if target.is_array() {
+        let mut arr = target.as_array_mut()?;
+        let len = arr.len();
+        let child = key
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| arr.get_mut(i))
+            .ok_or_else(|| format!("index {} out of bounds for array of {}", key, len))?;
+        return set_in(child, rest, value);
+    }
+    Err(format!("cannot set {} of {}", key, target.type_name()))
+}
+
+// Random numbers
+//
+// SplitMix64, which is fast and good enough for sampling and ids, but not
+// suitable for anything security related. A seeded evaluation (see the
+// `seed_from` config) produces the same sequence for the same seed; otherwise
+// a process-wide generator seeded from the clock is used.
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+static GLOBAL_RNG: OnceLock<AtomicU64> = OnceLock::new();
+
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn next_u64() -> u64 {
+    let seeded = CALLER.with(|c| {
+        let mut c = c.borrow_mut();
+        let state = c.as_mut()?.rng.as_mut()?;
+        *state = state.wrapping_add(GOLDEN_GAMMA);
+        Some(mix64(*state))
+    });
+    if let Some(n) = seeded {
+        return n;
+    }
+
+    let rng = GLOBAL_RNG.get_or_init(|| {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        AtomicU64::new(nanos)
+    });
+    mix64(
+        rng.fetch_add(GOLDEN_GAMMA, Ordering::Relaxed)
+            .wrapping_add(GOLDEN_GAMMA),
+    )
+}
+
+/// Derive a random seed from a value, stable across runs.
+pub(crate) fn seed_from_value(value: &AgentValue) -> u64 {
+    // FNV-1a over the JSON representation
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in value.to_json().to_string().bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+// random() -> float in [0, 1)
+fn random() -> FLOAT {
+    ((next_u64() >> 11) as f64 / (1u64 << 53) as f64) as FLOAT
+}
+
+// uuid() -> random (version 4) UUID string
+fn uuid() -> String {
+    let hi = (next_u64() & !0xf000) | 0x4000;
+    let lo = (next_u64() & !(0b11 << 62)) | (0b10 << 62);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        hi >> 32,
+        (hi >> 16) & 0xffff,
+        hi & 0xffff,
+        lo >> 48,
+        lo & 0xffff_ffff_ffff
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::new_engine;
+    use crate::engine::{FileAccess, set_file_access};
+    use crate::testing::{capture_traces, events, lock_globals};
+
+    fn caller(agent_id: &str) -> Caller {
+        Caller {
+            agent_id: agent_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn eval_as(caller: Caller, script: &str) -> (Result<Dynamic, Box<EvalAltResult>>, Caller) {
+        let engine = new_engine();
+        with_caller(caller, || engine.eval::<Dynamic>(script))
+    }
+
+    /// Evaluate `script` outside of an agent and convert its result.
+    fn eval(script: &str) -> Result<AgentValue, String> {
+        let result = new_engine()
+            .eval::<Dynamic>(script)
+            .map_err(|e| e.to_string())?;
+        from_dynamic_to_value(&result).map_err(|e| e.to_string())
+    }
+
+    fn eval_ok(script: &str) -> AgentValue {
+        eval(script).unwrap_or_else(|e| panic!("{}: {}", script, e))
+    }
+
+    #[test]
+    fn trace_event_records_agent_and_attrs() {
+        capture_traces();
+        let script = r#"trace_event("fetched", #{ rows: 3, source: "db" })"#;
+        let (result, _) = eval_as(caller("trace-attrs"), script);
+        assert!(result.unwrap().is_unit());
+
+        let recorded = events(TRACE_TARGET, "agent", "trace-attrs");
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0]["message"], "fetched");
+        assert_eq!(recorded[0]["ctx"], "0");
+        assert_eq!(recorded[0]["attrs"], r#"{"rows":3,"source":"db"}"#);
+    }
+
+    #[test]
+    fn trace_event_without_attrs() {
+        capture_traces();
+        let (result, _) = eval_as(caller("trace-bare"), r#"trace_event("tick")"#);
+        assert!(result.unwrap().is_unit());
+
+        let recorded = events(TRACE_TARGET, "agent", "trace-bare");
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0]["message"], "tick");
+        assert_eq!(recorded[0]["attrs"], "{}");
+    }
+
+    #[test]
+    fn strict_eq_compares_types() {
+        assert_eq!(eval_ok("strict_eq(5, 5)"), AgentValue::boolean(true));
+        assert_eq!(eval_ok("strict_eq(5, 5.0)"), AgentValue::boolean(false));
+        assert_eq!(eval_ok("5 == 5.0"), AgentValue::boolean(true));
+        assert_eq!(
+            eval_ok(r#"strict_eq(#{ a: [1, "x"] }, #{ a: [1, "x"] })"#),
+            AgentValue::boolean(true)
+        );
+        assert_eq!(
+            eval_ok(r#"strict_eq(#{ a: [1] }, #{ a: [1.0] })"#),
+            AgentValue::boolean(false)
+        );
+        assert_eq!(eval_ok(r#"strict_eq("1", 1)"#), AgentValue::boolean(false));
+    }
+
+    #[test]
+    fn uuid_is_a_version_4_uuid() {
+        let AgentValue::String(id) = eval_ok("uuid()") else {
+            panic!("uuid() must return a string");
+        };
+        let parts: Vec<_> = id.split('-').map(str::len).collect();
+        assert_eq!(parts, [8, 4, 4, 4, 12]);
+        assert_eq!(id.as_bytes()[14], b'4');
+        assert!(matches!(id.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+    }
+
+    #[test]
+    fn seeded_random_is_reproducible() {
+        let seeded = |seed| Caller {
+            rng: Some(seed),
+            ..Default::default()
+        };
+        let script = "[random(), random(), uuid()]";
+        let (a, _) = eval_as(seeded(7), script);
+        let (b, _) = eval_as(seeded(7), script);
+        let (c, _) = eval_as(seeded(8), script);
+        let (a, b, c) = (
+            a.unwrap().to_string(),
+            b.unwrap().to_string(),
+            c.unwrap().to_string(),
+        );
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+
+        let (values, _) = eval_as(seeded(7), "random()");
+        let n = values.unwrap().as_float().unwrap();
+        assert!((0.0..1.0).contains(&n));
+    }
+
+    fn json(value: serde_json::Value) -> AgentValue {
+        AgentValue::from_json(value).unwrap()
+    }
+
+    #[test]
+    fn get_path_reads_nested_values() {
+        let obj = r#"let obj = #{ a: #{ b: 1, list: [#{ c: "x" }] } };"#;
+        assert_eq!(
+            eval_ok(&format!(r#"{obj} get_path(obj, "a.b")"#)),
+            json(serde_json::json!(1))
+        );
+        assert_eq!(
+            eval_ok(&format!(r#"{obj} get_path(obj, "a.list.0.c")"#)),
+            AgentValue::string("x")
+        );
+        for missing in ["a.missing.b", "a.list.5", "a.b.c", "a.list.x"] {
+            let script = format!(r#"{obj} get_path(obj, "{missing}")"#);
+            assert!(eval_ok(&script).is_unit(), "{}", missing);
+        }
+    }
+
+    #[test]
+    fn set_path_creates_missing_objects() {
+        let script = r#"
+            let obj = #{ a: #{ b: 1 }, list: [1] };
+            obj.set_path("a.b", 2);
+            obj.set_path("x.y.z", 3);
+            obj.set_path("list.0", 4);
+            obj
+        "#;
+        assert_eq!(
+            eval_ok(script),
+            json(serde_json::json!({"a": {"b": 2}, "x": {"y": {"z": 3}}, "list": [4]}))
+        );
+    }
+
+    #[test]
+    fn set_path_rejects_what_it_cannot_create() {
+        let err = eval(r#"let obj = #{ list: [] }; obj.set_path("list.0", 1); obj"#).unwrap_err();
+        assert!(
+            err.contains("set_path list.0: index 0 out of bounds"),
+            "{}",
+            err
+        );
+        let err = eval(r#"let obj = #{ a: 1 }; obj.set_path("a.b", 1); obj"#).unwrap_err();
+        assert!(
+            err.contains("cannot set b of i64") || err.contains("cannot set b of i32"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn to_json_sorts_keys() {
+        let script = r#"let obj = #{}; obj.zeta = 1; obj.alpha = #{ b: 2, a: 1 }; to_json(obj)"#;
+        let expected = AgentValue::string(r#"{"alpha":{"a":1,"b":2},"zeta":1}"#);
+        for _ in 0..3 {
+            assert_eq!(eval_ok(script), expected);
+        }
+        let script = r#"to_json(#{ b: 1, a: [1] }, #{ sort_keys: true })"#;
+        assert_eq!(eval_ok(script), AgentValue::string(r#"{"a":[1],"b":1}"#));
+    }
+
+    #[test]
+    fn to_json_options() {
+        assert_eq!(
+            eval_ok(r#"to_json(#{ a: 1 }, #{ pretty: true })"#),
+            AgentValue::string("{\n  \"a\": 1\n}")
+        );
+        let err = eval(r#"to_json(#{ a: 1 }, #{ sort_keys: false })"#).unwrap_err();
+        assert!(err.contains("use to_pairs"), "{}", err);
+        assert!(eval(r#"to_json(1, #{ indent: 2 })"#).is_err());
+        assert!(eval(r#"to_json(1, #{ pretty: "yes" })"#).is_err());
+    }
+
+    #[test]
+    fn dump_marks_types_of_nested_values() {
+        let out = eval_ok(r#"dump(#{ items: [1, "a", ()], b: blob(2, 255), e: #{} })"#);
+        let AgentValue::String(out) = out else {
+            panic!("dump returned {:?}", out);
+        };
+        let int = std::any::type_name::<INT>();
+        let expected = format!(
+            "map {{\n  b: blob[2] ff ff,\n  e: map {{}},\n  items: array [\n    {} 1,\n    string \"a\",\n    (),\n  ],\n}}",
+            int
+        );
+        assert_eq!(*out, expected);
+    }
+
+    #[test]
+    fn dump_redacts_secrets() {
+        let out = dump(Dynamic::from(Secret::new("hunter2".to_string())));
+        assert_eq!(out, format!("Secret {}", REDACTED));
+    }
+
+    #[test]
+    fn to_blob_checks_each_byte() {
+        assert_eq!(
+            eval_ok("to_blob([0, 127, 255])").to_json(),
+            serde_json::json!({"__rhai_blob__": "AH//"})
+        );
+        assert_eq!(
+            eval_ok("to_blob([])").to_json(),
+            serde_json::json!({"__rhai_blob__": ""})
+        );
+
+        let err = eval("to_blob([1, 256])").unwrap_err();
+        assert!(err.contains("element 1 is not a byte: 256"), "{}", err);
+        let err = eval("to_blob([-1])").unwrap_err();
+        assert!(err.contains("element 0 is not a byte: -1"), "{}", err);
+        let err = eval(r#"to_blob([1, "2"])"#).unwrap_err();
+        assert!(err.contains("element 1 is not a byte"), "{}", err);
+    }
+
+    #[test]
+    fn value_size_of_a_blob_matches_its_json() {
+        for n in 0..5 {
+            let script = format!("let b = blob({}, 7); [value_size(b), to_json(b).len()]", n);
+            let AgentValue::Array(sizes) = eval_ok(&script) else {
+                panic!("{}", script);
+            };
+            assert_eq!(sizes[0], sizes[1], "{}", script);
+        }
+    }
+
+    #[test]
+    fn as_integer_coerces_whole_values() {
+        assert_eq!(eval_ok("as_integer(7)"), AgentValue::integer(7));
+        assert_eq!(eval_ok("as_integer(7.0)"), AgentValue::integer(7));
+        assert_eq!(eval_ok(r#"as_integer(" -3 ")"#), AgentValue::integer(-3));
+        for script in [
+            "as_integer(7.5)",
+            r#"as_integer("7.0")"#,
+            "as_integer(true)",
+            "as_integer(())",
+            "as_integer(1e30)",
+        ] {
+            let err = eval(script).unwrap_err();
+            assert!(err.contains("to integer"), "{}: {}", script, err);
+        }
+    }
+
+    #[test]
+    fn as_number_coerces_numeric_values() {
+        assert_eq!(eval_ok("as_number(2)"), AgentValue::number(2.0));
+        assert_eq!(eval_ok("as_number(2.5)"), AgentValue::number(2.5));
+        assert_eq!(eval_ok(r#"as_number("0.5")"#), AgentValue::number(0.5));
+        for script in [r#"as_number("abc")"#, "as_number(false)", "as_number([1])"] {
+            let err = eval(script).unwrap_err();
+            assert!(err.contains("to number"), "{}: {}", script, err);
+        }
+    }
+
+    #[test]
+    fn as_string_coerces_scalars() {
+        assert_eq!(eval_ok(r#"as_string("a")"#), AgentValue::string("a"));
+        assert_eq!(eval_ok("as_string('c')"), AgentValue::string("c"));
+        assert_eq!(eval_ok("as_string(12)"), AgentValue::string("12"));
+        assert_eq!(eval_ok("as_string(1.5)"), AgentValue::string("1.5"));
+        assert_eq!(eval_ok("as_string(true)"), AgentValue::string("true"));
+        for script in ["as_string(())", "as_string(#{})", "as_string([])"] {
+            let err = eval(script).unwrap_err();
+            assert!(err.contains("to string"), "{}: {}", script, err);
+        }
+    }
+
+    #[test]
+    fn as_boolean_coerces_strictly() {
+        assert_eq!(eval_ok("as_boolean(true)"), AgentValue::boolean(true));
+        assert_eq!(eval_ok("as_boolean(0)"), AgentValue::boolean(false));
+        assert_eq!(eval_ok("as_boolean(1)"), AgentValue::boolean(true));
+        assert_eq!(
+            eval_ok(r#"as_boolean("false")"#),
+            AgentValue::boolean(false)
+        );
+        for script in [
+            "as_boolean(2)",
+            "as_boolean(1.0)",
+            r#"as_boolean("TRUE")"#,
+            r#"as_boolean("yes")"#,
+            "as_boolean(())",
+        ] {
+            let err = eval(script).unwrap_err();
+            assert!(err.contains("to boolean"), "{}: {}", script, err);
+        }
+    }
+
+    #[test]
+    fn read_file_blob_reads_only_allowed_files() {
+        let _globals = lock_globals();
+        let dir = std::env::temp_dir().join(format!("askit-rhai-blob-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("data.bin"), [1, 2, 3]).unwrap();
+        std::fs::write(root.join("large.bin"), [0; 11]).unwrap();
+        std::fs::write(dir.join("outside.bin"), [9]).unwrap();
+
+        let err = eval(r#"read_file_blob("data.bin")"#).unwrap_err();
+        assert!(err.contains("not allowed"), "{}", err);
+
+        set_file_access(Some(FileAccess {
+            root: root.clone(),
+            max_bytes: 10,
+        }));
+        let read = eval(r#"let b = read_file_blob("data.bin"); [b.len(), b[2]]"#);
+        let outside = eval(r#"read_file_blob("../outside.bin")"#);
+        let absolute = eval(&format!(
+            "read_file_blob({:?})",
+            dir.join("outside.bin").display().to_string()
+        ));
+        let large = eval(r#"read_file_blob("large.bin")"#);
+        let missing = eval(r#"read_file_blob("missing.bin")"#);
+        set_file_access(None);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(read.unwrap().to_json(), serde_json::json!([3, 3]));
+        for err in [outside.unwrap_err(), absolute.unwrap_err()] {
+            assert!(err.contains("outside of the allowed directory"), "{}", err);
+        }
+        let err = large.unwrap_err();
+        assert!(err.contains("11 bytes exceeds the limit of 10"), "{}", err);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn approx_eq_uses_absolute_and_relative_tolerance() {
+        let t = AgentValue::boolean(true);
+        let f = AgentValue::boolean(false);
+        assert_eq!(eval_ok("approx_eq(0.1 + 0.2, 0.3, 1e-6)"), t);
+        assert_eq!(eval_ok("approx_eq(1, 1.0005, 0.001)"), t);
+        assert_eq!(eval_ok("approx_eq(1, 1.01, 0.001)"), f);
+        // Large magnitudes compare relative to their size
+        assert_eq!(eval_ok("approx_eq(1e15, 1.000001e15, 1e-5)"), t);
+        assert_eq!(eval_ok("approx_eq(1e15, 1.1e15, 1e-5)"), f);
+        // but near zero the absolute tolerance applies
+        assert_eq!(eval_ok("approx_eq(0.0, 1e-9, 1e-6)"), t);
+        assert_eq!(eval_ok("approx_eq(0.0, 1e-3, 1e-6)"), f);
+        assert_eq!(eval_ok("approx_eq(1.0 / 0.0, 1.0 / 0.0, 0.1)"), t);
+
+        let err = eval(r#"approx_eq(1, "1", 0.1)"#).unwrap_err();
+        assert!(err.contains("expects numbers"), "{}", err);
+    }
+
+    #[test]
+    fn array_statistics() {
+        assert_eq!(eval_ok("arr_sum([1, 2, 3])"), AgentValue::integer(6));
+        assert_eq!(eval_ok("arr_sum([1, 2.5])"), AgentValue::number(3.5));
+        assert_eq!(eval_ok("arr_min([3, 1.5, 2])"), AgentValue::number(1.5));
+        assert_eq!(eval_ok("arr_max([3, 1.5, 2])"), AgentValue::integer(3));
+        assert_eq!(eval_ok("arr_mean([1, 2, 3, 4])"), AgentValue::number(2.5));
+        assert!(eval(&format!("arr_sum([{}, 1])", INT::MAX)).is_err());
+    }
+
+    #[test]
+    fn array_statistics_of_an_empty_array() {
+        assert_eq!(eval_ok("arr_sum([])"), AgentValue::integer(0));
+        for name in ["arr_min", "arr_max", "arr_mean"] {
+            assert!(eval_ok(&format!("{}([])", name)).is_unit(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn array_statistics_reject_non_numbers() {
+        for name in ["arr_sum", "arr_min", "arr_max", "arr_mean"] {
+            let err = eval(&format!(r#"{}([1, 2, "3"])"#, name)).unwrap_err();
+            assert!(
+                err.contains(&format!("{}: element 2 is not a number: string", name)),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn agent_value_size_matches_value_size() {
+        for json in [
+            serde_json::json!(null),
+            serde_json::json!([true, false, 12, -3, 1.5, "abc"]),
+            serde_json::json!({"a": {"b": [1, 2, {}]}, "c": [], "d": "x y"}),
+        ] {
+            let value = AgentValue::from_json(json.clone()).unwrap();
+            let size = agent_value_size(&value);
+            assert_eq!(size, json.to_string().len(), "{}", json);
+            let d = from_value_to_dynamic(value).unwrap();
+            assert_eq!(value_size(d), size as INT, "{}", json);
+        }
+    }
+
+    #[test]
+    fn value_size_of_the_input_needs_an_agent() {
+        let err = eval("value_size()").unwrap_err();
+        assert!(err.contains("not available here"), "{}", err);
+    }
+
+    #[test]
+    fn to_bool_accepts_boolean_like_values() {
+        for (script, expected) in [
+            ("to_bool(true)", true),
+            ("to_bool(0)", false),
+            ("to_bool(-2)", true),
+            ("to_bool(0.0)", false),
+            ("to_bool(0.5)", true),
+            (r#"to_bool("TRUE")"#, true),
+            (r#"to_bool(" False ")"#, false),
+            (r#"to_bool("1")"#, true),
+            (r#"to_bool("0")"#, false),
+            (r#"to_bool("0.0")"#, false),
+        ] {
+            assert_eq!(eval_ok(script), AgentValue::boolean(expected), "{}", script);
+        }
+    }
+
+    #[test]
+    fn to_bool_rejects_ambiguous_values() {
+        for script in [
+            r#"to_bool("yes")"#,
+            r#"to_bool("")"#,
+            r#"to_bool("NaN")"#,
+            "to_bool(0.0 / 0.0)",
+            "to_bool(())",
+            "to_bool([])",
+        ] {
+            let err = eval(script).unwrap_err();
+            assert!(err.contains("to boolean"), "{}: {}", script, err);
+        }
+    }
+
+    #[test]
+    fn freeze_unshares_nested_values() {
+        let mut inner: Dynamic = Dynamic::from_array(vec![Dynamic::from_int(1)]).into_shared();
+        let outer: Dynamic = vec![inner.clone()].into();
+        let frozen = freeze(outer);
+        inner
+            .write_lock::<Array>()
+            .unwrap()
+            .push(Dynamic::from_int(2));
+
+        let frozen = frozen.cast::<Array>();
+        assert!(!frozen[0].is_shared());
+        assert_eq!(frozen[0].clone().cast::<Array>().len(), 1);
+        assert_eq!(inner.read_lock::<Array>().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn to_pairs_lists_keys_in_order() {
+        let pairs = eval_ok(r#"to_pairs(#{ b: 2, a: [1], c: #{ d: () } })"#);
+        assert_eq!(
+            pairs.to_json(),
+            serde_json::json!([["a", [1]], ["b", 2], ["c", {"d": null}]])
+        );
+        assert_eq!(eval_ok("to_pairs(#{})").to_json(), serde_json::json!([]));
+    }
+
+    #[test]
+    fn elapsed_durations_convert() {
+        let elapsed = eval_ok("let start = timestamp(); timestamp() - start");
+        let seconds = elapsed.as_f64().unwrap();
+        assert!((0.0..1.0).contains(&seconds), "{}", seconds);
+        let ms = eval_ok("let start = timestamp(); duration_ms(start.elapsed)");
+        assert!((0..1000).contains(&ms.as_i64().unwrap()), "{:?}", ms);
+
+        assert_eq!(eval_ok("duration_ms(1.2346)"), AgentValue::integer(1235));
+        assert_eq!(eval_ok("duration_ms(2)"), AgentValue::integer(2000));
+        let err = eval("duration_ms(1e30)").unwrap_err();
+        assert!(err.contains("out of range"), "{}", err);
+    }
+
+    #[test]
+    fn print_and_debug_lines_are_prefixed() {
+        assert_eq!(print_line("hi"), "hi");
+        assert_eq!(debug_line("hi", Some("main"), Position::NONE), "main | hi");
+
+        let (lines, _) = with_caller(caller("printer"), || {
+            (
+                print_line("hi"),
+                debug_line("hi", Some("main"), Position::new(2, 5)),
+            )
+        });
+        assert_eq!(lines.0, "printer | hi");
+        assert_eq!(lines.1, "printer @ 2:5 | hi");
+
+        let prefixed = Caller {
+            log_prefix: "[orders]".to_string(),
+            ..caller("printer")
+        };
+        let (line, _) = with_caller(prefixed, || print_line("hi"));
+        assert_eq!(line, "[orders] | hi");
+    }
+
+    #[test]
+    fn builders_take_computed_keys() {
+        let script = r#"
+            let name = "item_" + 2;
+            obj().with_key(name, arr().with_item(1).with_item(#{})).with_key("n", 3)
+        "#;
+        assert_eq!(
+            eval_ok(script).to_json(),
+            serde_json::json!({"item_2": [1, {}], "n": 3})
+        );
+    }
+}
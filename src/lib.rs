@@ -1 +1,18 @@
 pub mod agents;
+mod cache;
+mod convert;
+pub mod engine;
+pub mod error;
+mod formats;
+mod functions;
+pub mod metadata;
+mod patch;
+mod state;
+mod template;
+pub mod test_utils;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "unicode")]
+mod unicode;
+#[cfg(feature = "url")]
+mod url;
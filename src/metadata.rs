@@ -0,0 +1,137 @@
+//! Static analysis of compiled scripts.
+//!
+//! The analysis walks Rhai's AST, which is only exposed by its `internals`
+//! feature and may change in any release.
+
+use std::collections::BTreeSet;
+
+use agent_stream_kit::AgentError;
+use rhai::{AST, ASTNode, Expr, Stmt};
+
+use crate::engine::get_engine;
+use crate::error::compile_error;
+
+/// What a script consumes and calls, found without running it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScriptMetadata {
+    /// Variables the script reads but doesn't define itself, i.e. the scope
+    /// inputs it expects, such as `value`.
+    pub referenced_vars: BTreeSet<String>,
+
+    /// Names of the functions the script calls, excluding operators.
+    pub called_fns: BTreeSet<String>,
+
+    /// Scope variables the script assigns to, including through a property
+    /// or index such as `value.x = 1`.
+    pub assigned_vars: BTreeSet<String>,
+}
+
+/// Analyze a compiled script.
+pub fn script_metadata(ast: &AST) -> ScriptMetadata {
+    let mut metadata = ScriptMetadata::default();
+    collect(ast.statements(), &[], &mut metadata);
+    for f in ast.iter_fn_def() {
+        collect(f.body.statements(), &f.params, &mut metadata);
+    }
+    metadata
+}
+
+fn collect(stmts: &[Stmt], params: &[rhai::ImmutableString], metadata: &mut ScriptMetadata) {
+    let mut on_node = |path: &[ASTNode]| {
+        match path.last() {
+            Some(ASTNode::Expr(expr @ Expr::Variable(..))) => {
+                if let Some(name) = scope_var(expr, params) {
+                    metadata.referenced_vars.insert(name.to_string());
+                }
+            }
+            Some(ASTNode::Stmt(Stmt::Assignment(x))) => {
+                let mut target = &x.1.lhs;
+                while let Expr::Dot(x, ..) | Expr::Index(x, ..) = target {
+                    target = &x.lhs;
+                }
+                if let Some(name) = scope_var(target, params) {
+                    metadata.assigned_vars.insert(name.to_string());
+                }
+            }
+            Some(ASTNode::Expr(Expr::FnCall(f, _) | Expr::MethodCall(f, _)))
+            | Some(ASTNode::Stmt(Stmt::FnCall(f, _)))
+                if f.op_token.is_none() =>
+            {
+                metadata.called_fns.insert(f.name.to_string());
+            }
+            _ => {}
+        }
+        true
+    };
+    for stmt in stmts {
+        stmt.walk(&mut Vec::new(), &mut on_node);
+    }
+}
+
+/// The name of a variable that comes from the scope rather than the script.
+fn scope_var<'a>(expr: &'a Expr, params: &[rhai::ImmutableString]) -> Option<&'a str> {
+    let Expr::Variable(x, short_index, _) = expr else {
+        return None;
+    };
+    let (index, name, namespace, _) = &**x;
+    // Variables defined by the script are resolved to a stack index at
+    // compile time; the rest come from the scope. This is how Rhai's
+    // internals happen to work rather than an API, hence the exact version
+    // of rhai in Cargo.toml.
+    (index.is_none() && short_index.is_none() && namespace.is_empty() && !params.contains(name))
+        .then_some(name.as_str())
+}
+
+/// Compile a script and analyze it.
+pub fn analyze_script(script: &str) -> Result<ScriptMetadata, AgentError> {
+    let ast = get_engine().compile(script).map_err(compile_error)?;
+    Ok(script_metadata(&ast))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn finds_scope_variables_and_calls() {
+        let metadata = analyze_script(
+            r#"
+            let limit = config.max;
+            let total = value.items.len() + offset(limit);
+            fn offset(n) { n * scale }
+            total.to_string()
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            metadata.referenced_vars,
+            names(&["config", "value", "scale"])
+        );
+        assert_eq!(metadata.called_fns, names(&["len", "offset", "to_string"]));
+        assert!(metadata.assigned_vars.is_empty());
+    }
+
+    #[test]
+    fn finds_assignments_to_scope_variables() {
+        let metadata = analyze_script(
+            r#"
+            let local = 1;
+            local += 1;
+            value = local;
+            state.count += 1;
+            config.items[0] = 2;
+            "#,
+        )
+        .unwrap();
+        assert_eq!(metadata.assigned_vars, names(&["value", "state", "config"]));
+    }
+
+    #[test]
+    fn compile_errors_are_reported() {
+        assert!(analyze_script("let = 1").is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use agent_stream_kit::{AgentError, AgentValue};
+
+/// Apply a JSON Patch (RFC 6902) style list of operations to `doc`.
+///
+/// Each operation is an object `{op, path, value}` where `op` is `add`,
+/// `replace` or `remove`, and `path` is a JSON Pointer such as `/items/0`.
+/// `add` with a last path segment of `-` appends to an array. The operations
+/// are applied all or nothing: on error, `doc` is left unchanged.
+pub(crate) fn apply_patch(doc: &mut AgentValue, ops: &AgentValue) -> Result<(), AgentError> {
+    let Some(ops) = ops.as_array() else {
+        return Err(AgentError::InvalidValue(
+            "patch must be an array of operations".to_string(),
+        ));
+    };
+
+    let mut patched = doc.clone();
+    for (i, op) in ops.iter().enumerate() {
+        apply_op(&mut patched, op)
+            .map_err(|e| AgentError::InvalidValue(format!("patch operation {}: {}", i, e)))?;
+    }
+    *doc = patched;
+    Ok(())
+}
+
+fn apply_op(doc: &mut AgentValue, op: &AgentValue) -> Result<(), String> {
+    let name = op.get("op").and_then(|v| v.as_str()).ok_or("missing op")?;
+    let path = op
+        .get("path")
+        .and_then(|v| v.as_str())
+        .ok_or("missing path")?;
+    let tokens = parse_pointer(path)?;
+    let value = || {
+        op.get("value")
+            .cloned()
+            .ok_or_else(|| format!("{} {}: missing value", name, path))
+    };
+
+    let Some((last, parents)) = tokens.split_last() else {
+        // The whole document
+        return match name {
+            "add" | "replace" => {
+                *doc = value()?;
+                Ok(())
+            }
+            "remove" => Err("can't remove the whole document".to_string()),
+            _ => Err(format!("unknown op {}", name)),
+        };
+    };
+    let parent = resolve(doc, parents).ok_or_else(|| format!("{} {}: no such path", name, path))?;
+
+    if let Some(obj) = parent.as_object_mut() {
+        match name {
+            "add" => {
+                obj.insert(last.clone(), value()?);
+            }
+            "replace" if obj.contains_key(last) => {
+                obj.insert(last.clone(), value()?);
+            }
+            "remove" if obj.remove(last).is_some() => {}
+            "replace" | "remove" => return Err(format!("{} {}: no such key", name, path)),
+            _ => return Err(format!("unknown op {}", name)),
+        }
+        return Ok(());
+    }
+
+    let Some(arr) = parent.as_array_mut() else {
+        return Err(format!(
+            "{} {}: parent is not an object or array",
+            name, path
+        ));
+    };
+    let len = arr.len();
+    let index = if name == "add" && last == "-" {
+        len
+    } else {
+        parse_index(last).ok_or_else(|| format!("{} {}: not an array index", name, path))?
+    };
+    match name {
+        "add" if index <= len => arr.insert(index, value()?),
+        "replace" if index < len => arr[index] = value()?,
+        "remove" if index < len => {
+            arr.remove(index);
+        }
+        "add" | "replace" | "remove" => {
+            return Err(format!(
+                "{} {}: index out of bounds for array of {}",
+                name, path, len
+            ));
+        }
+        _ => return Err(format!("unknown op {}", name)),
+    }
+    Ok(())
+}
+
+// "/a/b~1c" -> ["a", "b/c"]
+fn parse_pointer(path: &str) -> Result<Vec<String>, String> {
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = path.strip_prefix('/') else {
+        return Err(format!("path {} must start with /", path));
+    };
+    Ok(rest
+        .split('/')
+        .map(|t| t.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+// Array indices have no leading zeros or signs
+fn parse_index(token: &str) -> Option<usize> {
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) {
+        return None;
+    }
+    if !token.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    token.parse().ok()
+}
+
+fn resolve<'a>(doc: &'a mut AgentValue, tokens: &[String]) -> Option<&'a mut AgentValue> {
+    let mut current = doc;
+    for token in tokens {
+        current = if current.is_object() {
+            current.get_mut(token)?
+        } else {
+            current.as_array_mut()?.get_mut(parse_index(token)?)?
+        };
+    }
+    Some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn patch(doc: Value, ops: Value) -> Result<Value, String> {
+        let mut doc = AgentValue::from_json(doc).unwrap();
+        apply_patch(&mut doc, &AgentValue::from_json(ops).unwrap()).map_err(|e| e.to_string())?;
+        Ok(doc.to_json())
+    }
+
+    #[test]
+    fn adds_to_objects_and_arrays() {
+        let doc = patch(
+            json!({"items": [1, 3]}),
+            json!([
+                {"op": "add", "path": "/name", "value": "x"},
+                {"op": "add", "path": "/items/1", "value": 2},
+                {"op": "add", "path": "/items/-", "value": 4},
+                {"op": "add", "path": "/a~1b", "value": {}},
+            ]),
+        )
+        .unwrap();
+        assert_eq!(doc, json!({"items": [1, 2, 3, 4], "name": "x", "a/b": {}}));
+    }
+
+    #[test]
+    fn replaces_existing_values_only() {
+        let doc = patch(
+            json!({"n": 1, "items": [1, 2]}),
+            json!([
+                {"op": "replace", "path": "/n", "value": 2},
+                {"op": "replace", "path": "/items/0", "value": "a"},
+            ]),
+        )
+        .unwrap();
+        assert_eq!(doc, json!({"n": 2, "items": ["a", 2]}));
+
+        let err = patch(
+            json!({}),
+            json!([{"op": "replace", "path": "/n", "value": 1}]),
+        );
+        assert!(err.unwrap_err().contains("no such key"));
+        let err = patch(
+            json!([1]),
+            json!([{"op": "replace", "path": "/1", "value": 1}]),
+        );
+        assert!(err.unwrap_err().contains("out of bounds"));
+    }
+
+    #[test]
+    fn removes_values() {
+        let doc = patch(
+            json!({"n": 1, "items": [1, 2, 3]}),
+            json!([
+                {"op": "remove", "path": "/n"},
+                {"op": "remove", "path": "/items/1"},
+            ]),
+        )
+        .unwrap();
+        assert_eq!(doc, json!({"items": [1, 3]}));
+
+        let err = patch(json!({"n": 1}), json!([{"op": "remove", "path": ""}]));
+        assert!(err.unwrap_err().contains("whole document"));
+    }
+
+    #[test]
+    fn invalid_ops_leave_the_document_unchanged() {
+        let mut doc = AgentValue::from_json(json!({"n": 1})).unwrap();
+        let ops = AgentValue::from_json(json!([
+            {"op": "add", "path": "/m", "value": 2},
+            {"op": "move", "path": "/n"},
+        ]))
+        .unwrap();
+        let err = apply_patch(&mut doc, &ops).unwrap_err().to_string();
+        assert!(
+            err.contains("patch operation 1: unknown op move"),
+            "{}",
+            err
+        );
+        assert_eq!(doc.to_json(), json!({"n": 1}));
+
+        for (ops, message) in [
+            (json!({"op": "add"}), "must be an array"),
+            (json!([{"path": "/n"}]), "missing op"),
+            (
+                json!([{"op": "add", "path": "n", "value": 1}]),
+                "must start with /",
+            ),
+            (json!([{"op": "add", "path": "/m"}]), "missing value"),
+            (
+                json!([{"op": "add", "path": "/n/x", "value": 1}]),
+                "not an object or array",
+            ),
+            (json!([{"op": "remove", "path": "/x/y"}]), "no such"),
+        ] {
+            let err = patch(json!({"n": 1}), ops.clone()).unwrap_err();
+            assert!(err.contains(message), "{}: {}", ops, err);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use agent_stream_kit::AgentError;
+use rhai::Map;
+
+use crate::convert::{ConvertOptions, from_dynamic_to_value_with};
+
+/// What to do when the state grows beyond its limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Eviction {
+    /// Fail the evaluation and keep the previous state.
+    #[default]
+    Reject,
+    /// Drop the least recently updated entries.
+    Lru,
+}
+
+impl Eviction {
+    pub fn from_name(name: &str) -> Result<Self, AgentError> {
+        match name {
+            "" | "reject" => Ok(Eviction::Reject),
+            "lru" => Ok(Eviction::Lru),
+            _ => Err(AgentError::InvalidConfig(format!(
+                "Unknown state eviction policy: {}",
+                name
+            ))),
+        }
+    }
+}
+
+/// Limits of the state map. Zero means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct StateLimits {
+    pub max_entries: usize,
+    /// Measured as the key lengths plus the JSON sizes of the values.
+    pub max_bytes: usize,
+    pub eviction: Eviction,
+}
+
+impl StateLimits {
+    fn is_limited(&self) -> bool {
+        self.max_entries > 0 || self.max_bytes > 0
+    }
+}
+
+/// The `state` map a script keeps across messages.
+///
+/// Each evaluation gets its own copy of the map and the map it leaves behind
+/// replaces the stored one. Rhai copies values as they are stored, so nothing
+/// in the state aliases the input or a later message.
+#[derive(Default)]
+pub(crate) struct StateStore {
+    map: Map,
+    pub limits: StateLimits,
+
+    // Keys from least to most recently updated, with a fingerprint and the
+    // size of their values. Only tracked when limits are set.
+    order: Vec<String>,
+    entries: HashMap<String, (u64, usize)>,
+}
+
+impl StateStore {
+    pub fn map(&self) -> Map {
+        self.map.clone()
+    }
+
+    /// Replace the state with the one left by a script, applying the limits.
+    pub fn update(&mut self, agent_id: &str, mut map: Map) -> Result<(), AgentError> {
+        if !self.limits.is_limited() {
+            self.map = map;
+            return Ok(());
+        }
+
+        let mut order: Vec<String> = self
+            .order
+            .iter()
+            .filter(|k| map.contains_key(k.as_str()))
+            .cloned()
+            .collect();
+        // Only measured, so closures in the state aren't stored as opaque
+        // values on every update
+        let measure = ConvertOptions {
+            describe_opaque: true,
+            ..Default::default()
+        };
+        let mut entries = HashMap::with_capacity(map.len());
+        for (k, v) in map.iter() {
+            let json = from_dynamic_to_value_with(v, &measure)?
+                .to_json()
+                .to_string();
+            let mut hasher = DefaultHasher::new();
+            json.hash(&mut hasher);
+            let entry = (hasher.finish(), k.len() + json.len());
+            let key = k.to_string();
+            if self.entries.get(&key) != Some(&entry) {
+                order.retain(|o| *o != key);
+                order.push(key.clone());
+            }
+            entries.insert(key, entry);
+        }
+
+        let mut bytes: usize = entries.values().map(|(_, size)| size).sum();
+        let over = |len: usize, bytes: usize| {
+            (self.limits.max_entries > 0 && len > self.limits.max_entries)
+                || (self.limits.max_bytes > 0 && bytes > self.limits.max_bytes)
+        };
+        if over(order.len(), bytes) {
+            if self.limits.eviction == Eviction::Reject {
+                return Err(AgentError::InvalidValue(format!(
+                    "state of {} entries and {} bytes exceeds the limits",
+                    order.len(),
+                    bytes
+                )));
+            }
+            let mut evicted = 0;
+            while over(order.len(), bytes) {
+                let key = order.remove(0);
+                map.remove(key.as_str());
+                if let Some((_, size)) = entries.remove(&key) {
+                    bytes -= size;
+                }
+                evicted += 1;
+            }
+            log::warn!("{}: evicted {} state entries", agent_id, evicted);
+        }
+
+        self.map = map;
+        self.order = order;
+        self.entries = entries;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rhai::Dynamic;
+
+    use super::*;
+
+    fn map(entries: &[(&str, i64)]) -> Map {
+        entries
+            .iter()
+            .map(|(k, v)| ((*k).into(), Dynamic::from_int(*v as rhai::INT)))
+            .collect()
+    }
+
+    fn keys(store: &StateStore) -> Vec<String> {
+        store.map().keys().map(|k| k.to_string()).collect()
+    }
+
+    fn limited(max_entries: usize, max_bytes: usize, eviction: Eviction) -> StateStore {
+        StateStore {
+            limits: StateLimits {
+                max_entries,
+                max_bytes,
+                eviction,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reject_keeps_the_previous_state() {
+        let mut store = limited(2, 0, Eviction::Reject);
+        store.update("state", map(&[("a", 1), ("b", 2)])).unwrap();
+        let err = store
+            .update("state", map(&[("a", 1), ("b", 2), ("c", 3)]))
+            .unwrap_err();
+        assert!(err.to_string().contains("state of 3 entries"), "{}", err);
+        assert_eq!(keys(&store), ["a", "b"]);
+    }
+
+    #[test]
+    fn lru_drops_the_least_recently_updated() {
+        let mut store = limited(2, 0, Eviction::Lru);
+        store.update("state", map(&[("a", 1), ("b", 2)])).unwrap();
+        // Updating a makes b the oldest
+        store.update("state", map(&[("a", 10), ("b", 2)])).unwrap();
+        store
+            .update("state", map(&[("a", 10), ("b", 2), ("c", 3)]))
+            .unwrap();
+        assert_eq!(keys(&store), ["a", "c"]);
+    }
+
+    #[test]
+    fn byte_limit_counts_keys_and_values() {
+        // "a" plus "1" is two bytes
+        let mut store = limited(0, 4, Eviction::Reject);
+        store.update("state", map(&[("a", 1), ("b", 2)])).unwrap();
+        assert!(store.update("state", map(&[("a", 1), ("b", 22)])).is_err());
+    }
+
+    #[test]
+    fn unlimited_state_is_kept_as_is() {
+        let mut store = StateStore::default();
+        let entries: Vec<_> = (0..100).map(|i| (i.to_string(), i)).collect();
+        let entries: Vec<_> = entries.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+        store.update("state", map(&entries)).unwrap();
+        assert_eq!(store.map().len(), 100);
+    }
+
+    #[test]
+    fn unknown_eviction_is_a_config_error() {
+        assert_eq!(Eviction::from_name("lru").unwrap(), Eviction::Lru);
+        assert!(Eviction::from_name("fifo").is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use agent_stream_kit::{AgentContext, AgentError};
+use rhai::{AST, Dynamic, Scope};
+
+use crate::agents::eval_ast;
+use crate::engine::get_engine;
+use crate::error::compile_error;
+
+enum Segment {
+    Literal(String),
+    Expr { source: String, ast: Arc<AST> },
+}
+
+/// A string with `${expr}` placeholders, each a Rhai expression evaluated
+/// with the input bound to `value`.
+///
+/// `\${` is a literal `${` and `\\` a literal backslash; other backslashes are
+/// kept as they are.
+pub(crate) struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, AgentError> {
+        let engine = get_engine();
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(i) = rest.find(['\\', '$']) {
+            literal.push_str(&rest[..i]);
+            rest = &rest[i..];
+            if let Some(r) = rest.strip_prefix("\\${") {
+                literal.push_str("${");
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("\\\\") {
+                literal.push('\\');
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("${") {
+                let end = placeholder_end(r).ok_or_else(|| {
+                    compile_error(format!("unclosed placeholder in template: ${{{}", r))
+                })?;
+                let source = r[..end].trim().to_string();
+                let ast = engine
+                    .compile_expression(&source)
+                    .map_err(|e| compile_error(format!("{} in placeholder ${{{}}}", e, source)))?;
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Expr {
+                    source,
+                    ast: Arc::new(ast),
+                });
+                rest = &r[end + 1..];
+            } else {
+                // A lone backslash or dollar sign
+                literal.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn render(
+        &self,
+        agent_id: &str,
+        ctx: &AgentContext,
+        value: Dynamic,
+    ) -> Result<String, AgentError> {
+        let mut scope = Scope::new();
+        scope.push_constant("value", value);
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => out.push_str(s),
+                Segment::Expr { source, ast } => {
+                    let result = eval_ast(agent_id, ctx, ast, &mut scope).map_err(|e| match e {
+                        AgentError::IoError(m) => {
+                            AgentError::IoError(format!("{} in placeholder ${{{}}}", m, source))
+                        }
+                        e => e,
+                    })?;
+                    out.push_str(&result.to_string());
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+// Index of the `}` closing a placeholder, skipping nested braces such as
+// those of an object map literal and braces inside string literals.
+fn placeholder_end(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '`' | '\'' => quote = Some(c),
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_stream_kit::AgentValue;
+    use serde_json::json;
+
+    use super::*;
+    use crate::convert::from_value_to_dynamic;
+
+    fn render(template: &str, value: serde_json::Value) -> Result<String, AgentError> {
+        let value = from_value_to_dynamic(AgentValue::from_json(value).unwrap()).unwrap();
+        Template::parse(template)?.render("template", &AgentContext::new(), value)
+    }
+
+    #[test]
+    fn renders_each_placeholder() {
+        let out = render(
+            "${value.user} has ${value.items.len()} items, the first ${ value.items[0] }",
+            json!({"user": "ann", "items": ["pen", "cup"]}),
+        )
+        .unwrap();
+        assert_eq!(out, "ann has 2 items, the first pen");
+    }
+
+    #[test]
+    fn placeholders_may_contain_braces_and_strings() {
+        let out = render(r#"${#{ a: value }.a} ${"}"} ${`x${value}`}"#, json!(1)).unwrap();
+        assert_eq!(out, "1 } x1");
+    }
+
+    #[test]
+    fn escapes_are_literal() {
+        let out = render(r"cost: \${value} \\${value} $5 \n", json!(3)).unwrap();
+        assert_eq!(out, r"cost: ${value} \3 $5 \n");
+    }
+
+    #[test]
+    fn reports_bad_placeholders() {
+        let err = render("${value", json!(1)).unwrap_err().to_string();
+        assert!(err.contains("unclosed placeholder"), "{}", err);
+        let err = render("ok ${value +}", json!(1)).unwrap_err().to_string();
+        assert!(err.contains("in placeholder ${value +}"), "{}", err);
+        let err = render("${value.missing()}", json!(1))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("in placeholder ${value.missing()}"), "{}", err);
+    }
+}
@@ -0,0 +1,141 @@
+//! Helpers for testing scripts without setting up an agent flow.
+//!
+//! ```
+//! use agent_stream_kit::AgentValue;
+//! use askit_rhai_agents::test_utils::{assert_script_output, run_script};
+//!
+//! let out = run_script("value * 2", AgentValue::integer(21)).unwrap();
+//! assert_eq!(out, AgentValue::integer(42));
+//!
+//! assert_script_output("value.len()", AgentValue::string("abc"), AgentValue::integer(3));
+//! ```
+
+use std::time::{Duration, Instant};
+
+use agent_stream_kit::{AgentContext, AgentError, AgentValue};
+use rhai::{AST, Scope};
+
+use crate::agents::{compile_script, eval_ast};
+use crate::convert::{from_dynamic_to_value, from_value_to_dynamic};
+
+static TEST_AGENT_ID: &str = "test";
+
+/// Evaluate `script` with `input` bound to `value`, as the Rhai Script agent does.
+///
+/// An empty script produces unit.
+pub fn run_script(script: &str, input: AgentValue) -> Result<AgentValue, AgentError> {
+    let Some(ast) = compile_script(script, false)? else {
+        return Ok(AgentValue::unit());
+    };
+    run_compiled(&ast, input)
+}
+
+fn run_compiled(ast: &AST, input: AgentValue) -> Result<AgentValue, AgentError> {
+    let mut scope = Scope::new();
+    scope.push("value", from_value_to_dynamic(input)?);
+    let result = eval_ast(TEST_AGENT_ID, &AgentContext::new(), ast, &mut scope)?;
+    from_dynamic_to_value(&result)
+}
+
+/// Timings of [`benchmark_script`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BenchmarkStats {
+    pub iterations: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub p99: Duration,
+}
+
+/// Run `script` on `input` `iterations` times, as [`run_script`] does, and
+/// report how long each run took, for sizing a deployment.
+///
+/// The script is compiled once beforehand, as an agent does when it is
+/// configured, so only converting the input, evaluating and converting the
+/// result are timed. Rhai is an interpreter, so there is no warmup to wait for.
+///
+/// ```
+/// use agent_stream_kit::AgentValue;
+/// use askit_rhai_agents::test_utils::benchmark_script;
+///
+/// let stats = benchmark_script("value + 1", AgentValue::integer(1), 100).unwrap();
+/// assert_eq!(stats.iterations, 100);
+/// assert!(stats.min <= stats.median && stats.median <= stats.p99);
+/// ```
+pub fn benchmark_script(
+    script: &str,
+    input: AgentValue,
+    iterations: usize,
+) -> Result<BenchmarkStats, AgentError> {
+    if iterations == 0 {
+        return Err(AgentError::InvalidValue(
+            "benchmark needs at least one iteration".to_string(),
+        ));
+    }
+    let ast = compile_script(script, false)?.unwrap_or_default();
+    let mut times = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let started = Instant::now();
+        run_compiled(&ast, input.clone())?;
+        times.push(started.elapsed());
+    }
+    times.sort();
+    // Nearest rank
+    let percentile = |p: usize| times[(iterations * p).div_ceil(100).max(1) - 1];
+    Ok(BenchmarkStats {
+        iterations,
+        min: times[0],
+        median: percentile(50),
+        p99: percentile(99),
+    })
+}
+
+/// Assert that `script` produces `expected` for `input`.
+#[track_caller]
+pub fn assert_script_output(script: &str, input: AgentValue, expected: AgentValue) {
+    match run_script(script, input) {
+        Ok(actual) => assert_eq!(actual, expected, "unexpected output of script: {}", script),
+        Err(e) => panic!("script failed: {}: {}", script, e),
+    }
+}
+
+/// Assert that `script` fails for `input`, returning the error message.
+#[track_caller]
+pub fn assert_script_error(script: &str, input: AgentValue) -> String {
+    match run_script(script, input) {
+        Ok(actual) => panic!("script succeeded with {:?}: {}", actual, script),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Assert that `value` comes back unchanged from being converted to a Rhai
+/// value and back, as every input a script passes through untouched does.
+///
+/// Integers and numbers only roundtrip within the range and precision of
+/// Rhai's, which the `only_i32` and `f32_float` features narrow, and `NaN`
+/// is never equal to itself.
+///
+/// ```
+/// use agent_stream_kit::AgentValue;
+/// use askit_rhai_agents::test_utils::assert_roundtrip;
+///
+/// let values = [
+///     AgentValue::unit(),
+///     AgentValue::boolean(true),
+///     AgentValue::integer(-7),
+///     AgentValue::number(0.5),
+///     AgentValue::string("héllo"),
+///     AgentValue::array(vec![AgentValue::integer(1), AgentValue::array(vec![])]),
+///     AgentValue::from_json(serde_json::json!({"a": [1, {"b": null}], "": "empty key"})).unwrap(),
+/// ];
+/// for value in values {
+///     assert_roundtrip(value);
+/// }
+/// ```
+#[track_caller]
+pub fn assert_roundtrip(value: AgentValue) {
+    let actual = from_value_to_dynamic(value.clone()).and_then(|d| from_dynamic_to_value(&d));
+    match actual {
+        Ok(actual) => assert_eq!(actual, value, "value changed in roundtrip"),
+        Err(e) => panic!("roundtrip of {:?} failed: {}", value, e),
+    }
+}
@@ -0,0 +1,334 @@
+//! Helpers shared by the unit tests.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Mutex, MutexGuard, Once};
+use std::time::Duration;
+
+use agent_stream_kit::test_utils::{ProbeReceiver, TestProbeAgent, probe_receiver};
+use agent_stream_kit::{
+    ASKit, Agent, AgentConfigs, AgentContext, AgentError, AgentFlowEdge, AgentStatus, AgentValue,
+};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber, subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Run a future to completion on a fresh multi-threaded runtime.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+static GLOBALS: Mutex<()> = Mutex::new(());
+
+/// Serialize the tests that change or depend on process-wide settings, like
+/// the engine, the global constants or the eval limit.
+pub(crate) fn lock_globals() -> MutexGuard<'static, ()> {
+    GLOBALS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+static LOGGER: Once = Once::new();
+static LOGS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+struct CaptureLogger;
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        LOGS.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((record.target().to_string(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Start capturing log records for [`logs`].
+pub(crate) fn capture_logs() {
+    LOGGER.call_once(|| {
+        log::set_logger(&CaptureLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+/// The messages logged so far under `target` that contain `needle`.
+///
+/// Tests run in parallel and share the logger, so pick a needle that's unique
+/// to the test, like an agent id.
+pub(crate) fn logs(target: &str, needle: &str) -> Vec<String> {
+    LOGS.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|(t, m)| t == target && m.contains(needle))
+        .map(|(_, m)| m.clone())
+        .collect()
+}
+
+static TRACER: Once = Once::new();
+static SPANS: Mutex<Vec<TracedSpan>> = Mutex::new(Vec::new());
+static EVENTS: Mutex<Vec<(String, BTreeMap<String, String>)>> = Mutex::new(Vec::new());
+
+/// A span recorded by [`capture_traces`], with its fields formatted.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TracedSpan {
+    pub(crate) name: &'static str,
+    pub(crate) entered: bool,
+    pub(crate) fields: BTreeMap<String, String>,
+}
+
+struct FieldVisitor<'a>(&'a mut BTreeMap<String, String>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+struct CaptureLayer;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for CaptureLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut span = TracedSpan {
+            name: attrs.metadata().name(),
+            ..Default::default()
+        };
+        attrs.record(&mut FieldVisitor(&mut span.fields));
+        if let Some(s) = ctx.span(id) {
+            s.extensions_mut().insert(span);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(s) = ctx.span(id)
+            && let Some(span) = s.extensions_mut().get_mut::<TracedSpan>()
+        {
+            values.record(&mut FieldVisitor(&mut span.fields));
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(s) = ctx.span(id)
+            && let Some(span) = s.extensions_mut().get_mut::<TracedSpan>()
+        {
+            span.entered = true;
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut fields = BTreeMap::new();
+        event.record(&mut FieldVisitor(&mut fields));
+        EVENTS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((event.metadata().target().to_string(), fields));
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(s) = ctx.span(&id)
+            && let Some(span) = s.extensions_mut().remove::<TracedSpan>()
+        {
+            SPANS.lock().unwrap_or_else(|e| e.into_inner()).push(span);
+        }
+    }
+}
+
+/// Start recording closed tracing spans for [`spans`] and events for
+/// [`events`].
+pub(crate) fn capture_traces() {
+    TRACER.call_once(|| {
+        let subscriber = tracing_subscriber::registry().with(CaptureLayer);
+        subscriber::set_global_default(subscriber).unwrap();
+    });
+}
+
+/// The spans named `name` closed so far whose `field` is `value`.
+///
+/// Like [`logs`], pick a field value that's unique to the test.
+pub(crate) fn spans(name: &str, field: &str, value: &str) -> Vec<TracedSpan> {
+    SPANS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|s| s.name == name && s.fields.get(field).is_some_and(|v| v == value))
+        .cloned()
+        .collect()
+}
+
+/// The fields of the events recorded so far under `target` whose `field` is
+/// `value`, with the message under `message`.
+pub(crate) fn events(target: &str, field: &str, value: &str) -> Vec<BTreeMap<String, String>> {
+    EVENTS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|(t, fields)| t == target && fields.get(field).is_some_and(|v| v == value))
+        .map(|(_, fields)| fields.clone())
+        .collect()
+}
+
+/// A running flow to put agents in.
+pub(crate) struct TestFlow {
+    pub(crate) askit: ASKit,
+    flow_id: String,
+}
+
+impl TestFlow {
+    pub(crate) async fn new() -> Self {
+        let askit = ASKit::init().unwrap();
+        let flow = askit.new_agent_flow("test").unwrap();
+        askit.ready().await.unwrap();
+        Self {
+            askit,
+            flow_id: flow.id().to_string(),
+        }
+    }
+
+    /// Add an agent of definition `def_name` with `configs` on top of its
+    /// defaults, and wait for it to start.
+    pub(crate) async fn add(&self, id: &str, def_name: &str, configs: serde_json::Value) {
+        let mut node = self.askit.new_agent_flow_node(def_name).unwrap();
+        node.id = id.to_string();
+        node.enabled = true;
+        let mut merged = node.spec.configs.take().unwrap_or_default();
+        if let serde_json::Value::Object(map) = configs {
+            for (key, value) in map {
+                merged.set(key, AgentValue::from_json(value).unwrap());
+            }
+        }
+        node.spec.configs = Some(merged);
+        self.askit
+            .add_agent_flow_node(&self.flow_id, &node)
+            .unwrap();
+        self.askit.start_agent(id).await.unwrap();
+        self.wait_started(id).await;
+    }
+
+    async fn wait_started(&self, id: &str) {
+        let agent = self.askit.get_agent(id).unwrap();
+        for _ in 0..1000 {
+            if *agent.lock().await.status() == AgentStatus::Start {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        panic!("agent {} didn't start", id);
+    }
+
+    /// Connect `port` of `source` to a new probe and return its receiver.
+    pub(crate) async fn probe(&self, source: &str, port: &str) -> Probe {
+        let id = format!("{}:{}", source, port);
+        self.add(&id, TestProbeAgent::DEF_NAME, serde_json::json!({}))
+            .await;
+        self.connect(source, port, &id, "*");
+        Probe(probe_receiver(&self.askit, &id).await.unwrap())
+    }
+
+    /// Connect `port` of `source` to the `pin` input of `target`.
+    pub(crate) fn connect(&self, source: &str, port: &str, target: &str, pin: &str) {
+        let edge = AgentFlowEdge {
+            id: format!("{}:{}->{}:{}", source, port, target, pin),
+            source: source.to_string(),
+            source_handle: port.to_string(),
+            target: target.to_string(),
+            target_handle: pin.to_string(),
+        };
+        self.askit
+            .add_agent_flow_edge(&self.flow_id, &edge)
+            .unwrap();
+    }
+
+    /// Process `value` on the `pin` input of `id` right away and return the
+    /// agent's result.
+    pub(crate) async fn process(
+        &self,
+        id: &str,
+        pin: &str,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let agent = self.askit.get_agent(id).unwrap();
+        let mut agent = agent.lock().await;
+        agent
+            .process(AgentContext::new(), pin.to_string(), value)
+            .await
+    }
+
+    /// Change some configs of a running agent, keeping the rest.
+    pub(crate) async fn configure(
+        &self,
+        id: &str,
+        configs: serde_json::Value,
+    ) -> Result<(), AgentError> {
+        let agent = self.askit.get_agent(id).unwrap();
+        let mut agent = agent.lock().await;
+        let mut merged: AgentConfigs = agent.spec().configs.clone().unwrap_or_default();
+        if let serde_json::Value::Object(map) = configs {
+            for (key, value) in map {
+                merged.set(key, AgentValue::from_json(value).unwrap());
+            }
+        }
+        agent.set_configs(merged)
+    }
+
+    pub(crate) async fn stop(&self, id: &str) {
+        self.askit.stop_agent(id).await.unwrap();
+    }
+
+    /// Start a stopped agent again and wait for it to start.
+    pub(crate) async fn restart(&self, id: &str) {
+        self.askit.start_agent(id).await.unwrap();
+        self.wait_started(id).await;
+    }
+
+    /// Run `f` on the agent `id`, downcast to `T`.
+    pub(crate) async fn with_agent<T: Agent, R>(&self, id: &str, f: impl FnOnce(&mut T) -> R) -> R {
+        let agent = self.askit.get_agent(id).unwrap();
+        let mut agent = agent.lock().await;
+        f(agent.as_agent_mut::<T>().unwrap())
+    }
+}
+
+/// The receiving end of a probe.
+pub(crate) struct Probe(ProbeReceiver);
+
+impl Probe {
+    /// The next value, failing the test if none arrives within a second.
+    pub(crate) async fn recv(&self) -> AgentValue {
+        self.0.recv().await.expect("no value reached the probe").1
+    }
+
+    /// The next value with its context.
+    pub(crate) async fn recv_ctx(&self) -> (AgentContext, AgentValue) {
+        self.0.recv().await.expect("no value reached the probe")
+    }
+
+    /// The next value, or `None` if nothing arrives within `millis`.
+    pub(crate) async fn try_recv(&self, millis: u64) -> Option<AgentValue> {
+        self.0
+            .recv_with_timeout(Duration::from_millis(millis))
+            .await
+            .ok()
+            .map(|(_, value)| value)
+    }
+
+    /// Fail the test if a value arrives within 100ms.
+    pub(crate) async fn assert_empty(&self) {
+        if let Some(value) = self.try_recv(100).await {
+            panic!("unexpected value at the probe: {:?}", value);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use unicode_normalization::UnicodeNormalization;
+
+use rhai::Engine;
+
+pub(crate) fn register_unicode_functions(engine: &mut Engine) {
+    engine.register_fn("nfc", nfc);
+    engine.register_fn("nfd", nfd);
+    engine.register_fn("casefold", casefold);
+}
+
+// nfc(s) -> s in Unicode Normalization Form C
+//
+// Characters are composed where possible, so "e" followed by a combining
+// acute accent becomes the single character "é".
+fn nfc(s: &str) -> String {
+    s.nfc().collect()
+}
+
+// nfd(s) -> s in Unicode Normalization Form D
+//
+// Characters are decomposed into a base character and combining marks.
+fn nfd(s: &str) -> String {
+    s.nfd().collect()
+}
+
+// casefold(s) -> s with the Unicode default case folding applied
+//
+// Meant for caseless matching rather than display: "Straße" and "STRASSE"
+// both fold to "strasse", which to_lower doesn't do. Fold normalized strings,
+// like nfc(casefold(nfc(s))), to also match composed and decomposed forms.
+fn casefold(s: &str) -> String {
+    caseless::default_case_fold_str(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPOSED: &str = "Caf\u{e9} \u{c5}ngstr\u{f6}m";
+    const DECOMPOSED: &str = "Cafe\u{301} A\u{30a}ngstro\u{308}m";
+
+    fn eval<T: Clone + Send + Sync + 'static>(script: &str) -> T {
+        let mut engine = Engine::new();
+        register_unicode_functions(&mut engine);
+        engine.eval::<T>(script).unwrap()
+    }
+
+    #[test]
+    fn composed_and_decomposed_forms_normalize_alike() {
+        assert_ne!(COMPOSED, DECOMPOSED);
+        assert_eq!(nfc(COMPOSED), nfc(DECOMPOSED));
+        assert_eq!(nfd(COMPOSED), nfd(DECOMPOSED));
+        assert_eq!(nfc(DECOMPOSED), COMPOSED);
+        assert_eq!(nfd(COMPOSED), DECOMPOSED);
+
+        let script = format!(r#"nfc("{}") == nfc("{}")"#, COMPOSED, DECOMPOSED);
+        assert!(eval::<bool>(&script));
+    }
+
+    #[test]
+    fn casefold_matches_strings_differing_in_case() {
+        assert_eq!(eval::<String>(r#"casefold("Straße")"#), "strasse");
+        assert_eq!(eval::<String>(r#"casefold("STRASSE")"#), "strasse");
+        assert_eq!(
+            nfc(&casefold(&nfc(COMPOSED))),
+            nfc(&casefold(&nfc(&DECOMPOSED.to_uppercase())))
+        );
+    }
+}
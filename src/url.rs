@@ -0,0 +1,119 @@
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map};
+
+pub(crate) fn register_url_functions(engine: &mut Engine) {
+    engine.register_fn("url_encode", url_encode);
+    engine.register_fn("url_decode", url_decode);
+    engine.register_fn("parse_query", parse_query);
+}
+
+// url_encode(s) -> s with everything but unreserved characters percent-encoded
+//
+// Unreserved characters are ASCII letters, digits and `-_.~`. Others are
+// encoded as their UTF-8 bytes, so the result can be used in any URL component.
+fn url_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+// url_decode(s) -> s with percent-encoded bytes decoded
+//
+// Throws on a `%` not followed by two hex digits, or when the decoded bytes
+// are not UTF-8. A `+` is kept as it is; parse_query reads it as a space.
+fn url_decode(s: &str) -> Result<String, Box<EvalAltResult>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let byte = s
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| format!("Malformed percent-encoding at {} in {}", i, s))?;
+        out.push(byte);
+        i += 3;
+    }
+    String::from_utf8(out).map_err(|_| format!("Percent-encoded bytes are not UTF-8: {}", s).into())
+}
+
+// parse_query(s) -> map of the `key=value` pairs of a query string
+//
+// A leading `?` is skipped and `+` stands for a space. A key given more than
+// once maps to an array of its values in order; a key without `=` has the
+// value "".
+fn parse_query(s: &str) -> Result<Map, Box<EvalAltResult>> {
+    let mut map = Map::new();
+    let s = s.strip_prefix('?').unwrap_or(s);
+    for pair in s.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = url_decode(&key.replace('+', " "))?;
+        let value: Dynamic = url_decode(&value.replace('+', " "))?.into();
+        match map.get_mut(key.as_str()) {
+            None => {
+                map.insert(key.into(), value);
+            }
+            Some(existing) if existing.is_array() => {
+                existing.write_lock::<Array>().unwrap().push(value);
+            }
+            Some(existing) => {
+                let first = std::mem::take(existing);
+                *existing = vec![first, value].into();
+            }
+        }
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_round_trips_special_characters() {
+        let s = "a b&c=d/é?~_.-+%";
+        let encoded = url_encode(s);
+        assert_eq!(encoded, "a%20b%26c%3Dd%2F%C3%A9%3F~_.-%2B%25");
+        assert_eq!(url_decode(&encoded).unwrap(), s);
+        assert_eq!(url_decode("a+b").unwrap(), "a+b");
+    }
+
+    #[test]
+    fn malformed_encoding_throws() {
+        for s in ["%", "%2", "%zz", "%C3"] {
+            assert!(url_decode(s).is_err(), "{}", s);
+        }
+        let mut engine = Engine::new();
+        register_url_functions(&mut engine);
+        let caught: String = engine
+            .eval(r#"let r = ""; try { r = url_decode("100%") } catch { r = "caught" } r"#)
+            .unwrap();
+        assert_eq!(caught, "caught");
+    }
+
+    #[test]
+    fn parse_query_collects_repeated_keys() {
+        let map = parse_query("?tag=a&q=hello+world&tag=b&flag&tag=c%26d").unwrap();
+        assert_eq!(map["q"].clone().into_string().unwrap(), "hello world");
+        assert_eq!(map["flag"].clone().into_string().unwrap(), "");
+        let tags: Vec<String> = map["tag"]
+            .clone()
+            .into_typed_array::<rhai::ImmutableString>()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(tags, ["a", "b", "c&d"]);
+        assert!(parse_query("a=%G0").is_err());
+    }
+}